@@ -0,0 +1,32 @@
+//! Digs the dmm-tools version out of the workspace lockfile, so the bots can say which renderer produced their images
+
+use std::path::PathBuf;
+
+fn main() {
+    let lock = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap()).join("../../Cargo.lock");
+    println!("cargo:rerun-if-changed={}", lock.display());
+
+    let version = std::fs::read_to_string(&lock)
+        .ok()
+        .and_then(|lock| dmm_tools_version(&lock))
+        .unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=DMM_TOOLS_VERSION={version}");
+}
+
+fn dmm_tools_version(lock: &str) -> Option<String> {
+    let package = lock
+        .split("[[package]]")
+        .find(|package| package.contains("name = \"dmm-tools\""))?;
+    let field = |name: &str| {
+        package
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .map(|value| value.trim_start_matches(" = ").trim_matches('"'))
+    };
+    let version = field("version")?;
+    // Git dependencies keep their commit after the #, that's what actually identifies the build
+    match field("source").and_then(|source| source.rsplit_once('#')) {
+        Some((_, commit)) => Some(format!("{version} ({})", &commit[..commit.len().min(7)])),
+        None => Some(version.to_owned()),
+    }
+}
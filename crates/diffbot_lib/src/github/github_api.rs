@@ -1,5 +1,5 @@
 use crate::github::github_types::{
-    CreateCheckRun, Output, RawCheckRun, Repository, UpdateCheckRunBuilder,
+    CreateCheckRun, Output, PullRequest, RawCheckRun, Repository, UpdateCheckRunBuilder,
 };
 use async_fs::File;
 use eyre::{format_err, Context, Result};
@@ -7,13 +7,38 @@ use futures_lite::io::AsyncWriteExt;
 use octocrab::models::repos::Content;
 use octocrab::models::InstallationId;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use std::{future::Future, pin::Pin};
 
+/// `name` with `suffix` tacked on, `{repo}` in the suffix becomes the repo's full name and `{repo_name}` just its name
+pub fn check_name(name: &str, suffix: Option<&str>, repo: &Repository) -> String {
+    match suffix {
+        Some(suffix) => {
+            let suffix = suffix
+                .replace("{repo}", &repo.full_name())
+                .replace("{repo_name}", &repo.name_tuple().1);
+            format!("{name}{suffix}")
+        }
+        None => name.to_owned(),
+    }
+}
+
+/// What a failed check tells the PR, the correlation id is always the check run's id
+pub struct FailureDetails<'a> {
+    pub stack_trace: &'a str,
+    pub category: &'a str,
+    pub retry_status: &'a str,
+    pub support_link: Option<&'a str>,
+}
+
+/// The event's name and its raw `X-Hub-Signature-256` header, left for [`crate::verify::verify_signature`] to make sense of
 pub struct GithubEvent(pub String, pub Option<Vec<u8>>);
 
 impl actix_web::FromRequest for GithubEvent {
-    type Error = std::io::Error;
+    type Error = actix_web::Error;
 
     type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
 
@@ -24,42 +49,22 @@ impl actix_web::FromRequest for GithubEvent {
                 Some(event) => event
                     .to_str()
                     .map_err(|_| {
-                        std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
+                        actix_web::error::ErrorBadRequest(
                             "Corrupt X-Github-Event header, failed to convert to string",
                         )
                     })?
                     .to_owned(),
                 None => {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
+                    return Err(actix_web::error::ErrorBadRequest(
                         "Missing X-Github-Event header",
                     ))
                 }
             };
-            let hmac_header = match req.headers().get("X-Hub-Signature-256") {
-                Some(event) => {
-                    let sig = event.to_str().map_err(|_| {
-                        std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Corrupt X-Hub-Signature-256 header, failed to convert to string",
-                        )
-                    })?;
-
-                    //remove the `sha256=` part
-                    let (_, sig) = sig.split_at(7);
-
-                    let sig_bytes = hex::decode(sig).map_err(|_| {
-                        std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Corrupt X-Hub-Signature-256 header, failed to decode hex string",
-                        )
-                    })?;
-
-                    Some(sig_bytes)
-                }
-                _ => None,
-            };
+            // Parsed along with the check, so every bad signature gets the same 401
+            let hmac_header = req
+                .headers()
+                .get("X-Hub-Signature-256")
+                .map(|sig| sig.as_bytes().to_vec());
             Ok(GithubEvent(event_header, hmac_header))
         })
     }
@@ -122,20 +127,49 @@ impl CheckRun {
         .context("Marking check run as queued")
     }
 
-    pub async fn mark_started(&self) -> Result<()> {
-        self.update(
-            UpdateCheckRunBuilder::default()
-                .status("in_progress")
-                .started_at(chrono::Utc::now().to_rfc3339()),
-        )
-        .await
-        .context("Marking check run as in progress")
+    /// With `queued`, the output says how long the job waited, so a backlog isn't mistaken for slow rendering
+    pub async fn mark_started(&self, queued: Option<Duration>) -> Result<()> {
+        let mut builder = UpdateCheckRunBuilder::default()
+            .status("in_progress")
+            .started_at(chrono::Utc::now().to_rfc3339());
+        if let Some(queued) = queued {
+            builder = builder.output(Output {
+                title: "Rendering",
+                summary: format!(
+                    "Queued for {} seconds before rendering started.",
+                    queued.as_secs()
+                ),
+                text: "".to_owned(),
+            });
+        }
+        self.update(builder)
+            .await
+            .context("Marking check run as in progress")
     }
 
     pub async fn mark_failed(&self, stack_trace: &str) -> Result<()> {
+        self.mark_failed_with(&FailureDetails {
+            stack_trace,
+            category: "The job ran into an error.",
+            retry_status: "No, pushing a new commit will render the PR again.",
+            support_link: None,
+        })
+        .await
+    }
+
+    /// Like `mark_failed`, but with everything `error_template.txt` can show filled in
+    pub async fn mark_failed_with(&self, details: &FailureDetails<'_>) -> Result<()> {
+        let support = details
+            .support_link
+            .map(|link| format!("**Need a hand?** {link}\n"))
+            .unwrap_or_default();
         let summary = format!(
             include_str!("error_template.txt"),
-            stack_trace = stack_trace
+            category = details.category,
+            correlation_id = self.id(),
+            retry_status = details.retry_status,
+            support = support,
+            stack_trace = details.stack_trace,
         );
 
         self.update(
@@ -175,6 +209,13 @@ impl CheckRun {
         .context("Marking check as skipped")
     }
 
+    /// Where the check's "Details" link goes
+    pub async fn set_details_url(&self, url: &str) -> Result<()> {
+        self.update(UpdateCheckRunBuilder::default().details_url(url.to_owned()))
+            .await
+            .context("Setting check run details url")
+    }
+
     pub async fn set_output(&self, output: Output) -> Result<()> {
         self.update(UpdateCheckRunBuilder::default().output(output))
             .await
@@ -207,6 +248,242 @@ impl CheckRun {
     }
 }
 
+/// Mints a fresh installation access token, for authenticating things octocrab doesn't handle for us, like git fetches
+pub async fn installation_token(installation: &InstallationId) -> Result<String> {
+    let token: octocrab::models::InstallationToken = octocrab::instance()
+        .post(
+            format!("/app/installations/{installation}/access_tokens"),
+            None::<&()>,
+        )
+        .await
+        .context("Creating installation access token")?;
+    Ok(token.token)
+}
+
+#[derive(Deserialize)]
+struct AuthenticatedApp {
+    id: u64,
+}
+
+/// Makes sure the private key actually belongs to `app_id`, a mismatch otherwise only shows up as auth errors on the first job
+pub async fn verify_app_credentials(app_id: u64) -> Result<()> {
+    let app: AuthenticatedApp = octocrab::instance()
+        .get("/app", None::<&()>)
+        .await
+        .with_context(|| {
+            format!(
+                "Couldn't authenticate as app {app_id}, check that the private key belongs to it"
+            )
+        })?;
+    if app.id != app_id {
+        return Err(eyre::anyhow!(
+            "The private key belongs to app {}, but app_id is set to {app_id}",
+            app.id
+        ));
+    }
+    Ok(())
+}
+
+pub enum RepoAvailability {
+    Available,
+    Archived,
+    Inaccessible(String),
+}
+
+/// Checks whether we can still work on the repository, it may have been archived or had the app removed since the job was queued
+pub async fn repo_availability(
+    installation: &InstallationId,
+    repo: &Repository,
+) -> Result<RepoAvailability> {
+    let (owner, name) = repo.name_tuple();
+    match octocrab::instance()
+        .installation(*installation)
+        .repos(owner, name)
+        .get()
+        .await
+    {
+        Ok(repository) if repository.archived.unwrap_or(false) => Ok(RepoAvailability::Archived),
+        Ok(_) => Ok(RepoAvailability::Available),
+        // Github answered, but not with the repo, so it's gone or we're not allowed to see it anymore
+        Err(octocrab::Error::GitHub { source, .. }) => {
+            Ok(RepoAvailability::Inaccessible(source.message))
+        }
+        Err(e) => Err(e).context("Getting repository"),
+    }
+}
+
+#[derive(Deserialize)]
+struct CommitRef {
+    sha: String,
+}
+
+/// How long a repo's default branch is remembered, it's renamed rarely enough that this only has to catch up eventually
+const DEFAULT_BRANCH_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Repo id to its default branch, and when that was looked up
+static DEFAULT_BRANCHES: Mutex<Option<HashMap<u64, (String, Instant)>>> = Mutex::new(None);
+
+/// The repo's default branch, don't assume it's `master` or `main`. Cached for a while
+pub async fn default_branch(installation: &InstallationId, repo: &Repository) -> Result<String> {
+    if let Some((branch, fetched)) = DEFAULT_BRANCHES
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .get(&repo.id)
+    {
+        if fetched.elapsed() < DEFAULT_BRANCH_TTL {
+            return Ok(branch.clone());
+        }
+    }
+
+    let (owner, name) = repo.name_tuple();
+    let branch = octocrab::instance()
+        .installation(*installation)
+        .repos(&owner, &name)
+        .get()
+        .await
+        .context("Getting repository")?
+        .default_branch
+        .ok_or_else(|| format_err!("Repository has no default branch"))?;
+
+    DEFAULT_BRANCHES
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(repo.id, (branch.clone(), Instant::now()));
+    Ok(branch)
+}
+
+/// The default branch's name and the commit it currently points at
+pub async fn default_branch_head(
+    installation: &InstallationId,
+    repo: &Repository,
+) -> Result<(String, String)> {
+    let (owner, name) = repo.name_tuple();
+    let branch = default_branch(installation, repo).await?;
+    let commit: CommitRef = octocrab::instance()
+        .installation(*installation)
+        .get(
+            format!("/repos/{owner}/{name}/commits/{branch}"),
+            None::<&()>,
+        )
+        .await
+        .context("Getting default branch head")?;
+    Ok((branch, commit.sha))
+}
+
+#[derive(Deserialize)]
+struct CollaboratorPermission {
+    permission: String,
+}
+
+/// Whether `user` can push to the repository
+pub async fn has_write_access(
+    installation: &InstallationId,
+    repo: &Repository,
+    user: &str,
+) -> Result<bool> {
+    let permission: CollaboratorPermission = octocrab::instance()
+        .installation(*installation)
+        .get(
+            format!(
+                "/repos/{}/collaborators/{user}/permission",
+                repo.full_name()
+            ),
+            None::<&()>,
+        )
+        .await
+        .context("Getting collaborator permission")?;
+    Ok(matches!(permission.permission.as_str(), "admin" | "write"))
+}
+
+pub async fn get_pull(
+    installation: &InstallationId,
+    repo: &Repository,
+    number: u64,
+) -> Result<PullRequest> {
+    octocrab::instance()
+        .installation(*installation)
+        .get(
+            format!("/repos/{}/pulls/{number}", repo.full_name()),
+            None::<&()>,
+        )
+        .await
+        .context("Getting pull request")
+}
+
+/// Open PRs into `branch`, only the first 100
+pub async fn open_pulls_into(
+    installation: &InstallationId,
+    repo: &Repository,
+    branch: &str,
+) -> Result<Vec<PullRequest>> {
+    octocrab::instance()
+        .installation(*installation)
+        .get(
+            format!("/repos/{}/pulls", repo.full_name()),
+            Some(&[("state", "open"), ("base", branch), ("per_page", "100")]),
+        )
+        .await
+        .context("Listing open pull requests")
+}
+
+#[derive(Deserialize)]
+struct Comparison {
+    #[serde(default)]
+    files: Vec<ComparedFile>,
+}
+
+#[derive(Deserialize)]
+struct ComparedFile {
+    filename: String,
+    previous_filename: Option<String>,
+}
+
+/// Every file changed between `base` and `head`, renamed files under both names.
+/// None if Github cut the list short, it stops at 300 files.
+pub async fn changed_between(
+    installation: &InstallationId,
+    repo: &Repository,
+    base: &str,
+    head: &str,
+) -> Result<Option<Vec<String>>> {
+    let comparison: Comparison = octocrab::instance()
+        .installation(*installation)
+        .get(
+            format!("/repos/{}/compare/{base}...{head}", repo.full_name()),
+            None::<&()>,
+        )
+        .await
+        .context("Comparing commits")?;
+    if comparison.files.len() >= 300 {
+        return Ok(None);
+    }
+    Ok(Some(
+        comparison
+            .files
+            .into_iter()
+            .flat_map(|file| std::iter::once(file.filename).chain(file.previous_filename))
+            .collect(),
+    ))
+}
+
+pub async fn add_labels(
+    installation: &InstallationId,
+    repo: &Repository,
+    issue: u64,
+    labels: &[String],
+) -> Result<()> {
+    let (owner, name) = repo.name_tuple();
+    octocrab::instance()
+        .installation(*installation)
+        .issues(owner, name)
+        .add_labels(issue, labels)
+        .await
+        .context("Adding labels")?;
+    Ok(())
+}
+
 static DOWNLOAD_DIR: &str = "download";
 
 async fn find_content<S: AsRef<str>>(
@@ -236,11 +513,33 @@ async fn find_content<S: AsRef<str>>(
         .ok_or_else(|| format_err!("No content was found"))
 }
 
-pub async fn download_url<S: AsRef<str>>(
+/// How long a download gets, and how many more tries after a timeout or a hiccup on Github's end
+#[derive(Debug, Clone, Copy)]
+pub struct DownloadPolicy {
+    pub timeout: Duration,
+    pub retries: u32,
+}
+
+/// Worth another try, as opposed to Github telling us no
+fn is_transient(error: &eyre::Report) -> bool {
+    if let Some(e) = error.downcast_ref::<reqwest::Error>() {
+        return e.is_timeout()
+            || e.is_connect()
+            || e.status().map_or(true, |status| {
+                status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            });
+    }
+    matches!(
+        error.downcast_ref::<octocrab::Error>(),
+        Some(e) if !matches!(e, octocrab::Error::GitHub { .. })
+    )
+}
+
+async fn download_once(
     installation: &InstallationId,
     repo: &Repository,
-    filename: S,
-    commit: S,
+    filename: &str,
+    commit: &str,
 ) -> Result<Vec<u8>> {
     let target = find_content(installation, repo, filename, commit).await?;
 
@@ -249,16 +548,51 @@ pub async fn download_url<S: AsRef<str>>(
         .as_ref()
         .ok_or_else(|| format_err!("No download URL given by GitHub"))?;
 
-    let response = reqwest::get(download_url).await?;
+    let response = reqwest::get(download_url).await?.error_for_status()?;
 
     Ok(response.bytes().await?.to_vec())
 }
 
+pub async fn download_url<S: AsRef<str>>(
+    installation: &InstallationId,
+    repo: &Repository,
+    filename: S,
+    commit: S,
+    policy: DownloadPolicy,
+) -> Result<Vec<u8>> {
+    let (filename, commit) = (filename.as_ref(), commit.as_ref());
+    let mut backoff = Duration::from_secs(1);
+    let mut attempt = 0;
+    loop {
+        let (result, transient) = match actix_web::rt::time::timeout(
+            policy.timeout,
+            download_once(installation, repo, filename, commit),
+        )
+        .await
+        {
+            Ok(Ok(data)) => return Ok(data),
+            Ok(Err(e)) => {
+                let transient = is_transient(&e);
+                (e, transient)
+            }
+            Err(_) => (format_err!("Timed out after {:?}", policy.timeout), true),
+        };
+        if !transient || attempt >= policy.retries {
+            return Err(result);
+        }
+        log::warn!("Downloading {filename} failed, retrying in {backoff:?}: {result:?}");
+        actix_web::rt::time::sleep(backoff).await;
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
 pub async fn download_file<S: AsRef<str>>(
     installation: &InstallationId,
     repo: &Repository,
     filename: S,
     commit: S,
+    policy: DownloadPolicy,
 ) -> Result<PathBuf> {
     let target = find_content(installation, repo, &filename, &commit).await?;
 
@@ -271,7 +605,7 @@ pub async fn download_file<S: AsRef<str>>(
     async_fs::create_dir_all(path.parent().unwrap()).await?;
     let mut file = File::create(&path).await?;
 
-    let data = download_url(installation, repo, &filename, &commit).await?;
+    let data = download_url(installation, repo, &filename, &commit, policy).await?;
     file.write_all(&data).await?;
     Ok(path)
 }
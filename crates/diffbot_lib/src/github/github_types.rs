@@ -29,12 +29,19 @@ pub struct Branch {
     pub r#ref: String,
 }
 
+#[derive(Deserialize, Debug, Clone)]
+pub struct Label {
+    pub name: String,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct PullRequest {
     pub number: u64,
     pub head: Branch,
     pub base: Branch,
     pub title: Option<String>,
+    #[serde(default)]
+    pub labels: Vec<Label>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -72,6 +79,17 @@ pub struct CheckRunPayload {
     pub check_run: RawCheckRun,
 }
 
+#[derive(Deserialize, Debug, Clone)]
+pub struct ChangedFrom {
+    pub from: String,
+}
+
+/// Only sent with `edited` events
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct PullRequestChanges {
+    pub title: Option<ChangedFrom>,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct PullRequestEventPayload {
     pub action: String,
@@ -79,6 +97,115 @@ pub struct PullRequestEventPayload {
     pub repository: Repository,
     pub pull_request: PullRequest,
     pub installation: Installation,
+    #[serde(default)]
+    pub changes: PullRequestChanges,
+}
+
+impl PullRequestEventPayload {
+    /// True for an `edited` event that took the last skip marker out of the title, so the PR is ready to render
+    pub fn skip_marker_removed(&self, patterns: &[String]) -> bool {
+        self.action == "edited"
+            && self.changes.title.as_ref().map_or(false, |old| {
+                title_skip_marker(Some(&old.from), patterns).is_some()
+            })
+            && title_skip_marker(self.pull_request.title.as_deref(), patterns).is_none()
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct User {
+    pub login: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Comment {
+    pub body: String,
+    pub user: User,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Issue {
+    pub number: u64,
+    /// Only there if the issue is a PR
+    pub pull_request: Option<serde::de::IgnoredAny>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct IssueCommentEventPayload {
+    pub action: String,
+    pub issue: Issue,
+    pub comment: Comment,
+    pub repository: Repository,
+    pub installation: Installation,
+}
+
+/// Push events link the repo's page instead of its API url like the other events do
+#[derive(Deserialize, Debug)]
+pub struct PushRepository {
+    pub id: u64,
+    pub full_name: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PushCommit {
+    #[serde(default)]
+    pub added: Vec<String>,
+    #[serde(default)]
+    pub removed: Vec<String>,
+    #[serde(default)]
+    pub modified: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PushEventPayload {
+    pub r#ref: String,
+    #[serde(default)]
+    pub before: String,
+    pub after: String,
+    #[serde(default)]
+    pub deleted: bool,
+    pub repository: PushRepository,
+    pub installation: Installation,
+    #[serde(default)]
+    pub commits: Vec<PushCommit>,
+}
+
+impl PushEventPayload {
+    /// The pushed branch's name, None for tags
+    pub fn branch(&self) -> Option<&str> {
+        self.r#ref.strip_prefix("refs/heads/")
+    }
+
+    pub fn repository(&self) -> Repository {
+        Repository {
+            url: format!("https://api.github.com/repos/{}", self.repository.full_name),
+            id: self.repository.id,
+        }
+    }
+
+    /// Github only lists the first 20 commits of a push, past that `changed_files` can miss some
+    pub fn commits_truncated(&self) -> bool {
+        self.commits.len() >= 20
+    }
+
+    /// Every file the listed commits touched, see `commits_truncated`
+    pub fn changed_files(&self) -> std::collections::HashSet<&str> {
+        self.commits
+            .iter()
+            .flat_map(|commit| [&commit.added, &commit.removed, &commit.modified])
+            .flatten()
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+/// The first of `patterns` the title contains, ignoring case
+pub fn title_skip_marker<'a>(title: Option<&str>, patterns: &'a [String]) -> Option<&'a str> {
+    let title = title?.to_ascii_lowercase();
+    patterns
+        .iter()
+        .find(|pattern| title.contains(&pattern.to_ascii_lowercase()))
+        .map(String::as_str)
 }
 
 #[derive(Serialize, Debug)]
@@ -88,6 +215,17 @@ pub struct Output {
     pub text: String,
 }
 
+impl Output {
+    /// For PRs without a single changed file, like ones made of empty commits
+    pub fn empty_pull() -> Self {
+        Self {
+            title: "No files",
+            summary: "This PR doesn't change any files, there's nothing to render.".to_owned(),
+            text: "".to_owned(),
+        }
+    }
+}
+
 #[derive(Serialize)]
 pub struct CreateCheckRun {
     pub name: String,
@@ -100,6 +238,15 @@ pub struct FileDiff {
     pub status: ChangeType,
 }
 
+impl FileDiff {
+    /// Case insensitive, `ext` is given without the leading dot
+    pub fn has_extension(&self, ext: &str) -> bool {
+        std::path::Path::new(&self.filename)
+            .extension()
+            .map_or(false, |e| e.eq_ignore_ascii_case(ext))
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ChangeType {
     Added,
@@ -127,6 +274,8 @@ pub struct UpdateCheckRun {
     pub completed_at: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub output: Option<Output>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details_url: Option<String>,
 }
 
 pub type CheckOutputs = Vec<Output>;
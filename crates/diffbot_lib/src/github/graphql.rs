@@ -1,4 +1,4 @@
-use super::github_types::{ChangeType, FileDiff};
+use super::github_types::{ChangeType, CheckOutputs, FileDiff, Output};
 use eyre::Result;
 use octocrab::models::InstallationId;
 use serde::Deserialize;
@@ -97,6 +97,30 @@ struct Node {
     change_type: String,
 }
 
+/// Github stops listing a PR's files past this many, without saying it did
+pub const PULL_FILES_LIMIT: usize = 3000;
+
+/// Whether a list from [`get_pull_files`] hit [`PULL_FILES_LIMIT`], and so may be missing files
+pub fn pull_files_truncated(files: &[FileDiff]) -> bool {
+    files.len() >= PULL_FILES_LIMIT
+}
+
+/// Puts a warning about the files list being cut short at the very top of the outputs, adding one if there are none.
+/// Otherwise a reviewer would assume the bot looked at everything.
+pub fn warn_truncated_files(outputs: &mut CheckOutputs, title: &'static str) {
+    let warning = format!(
+        "**Warning:** this PR changes more files than Github will list ({PULL_FILES_LIMIT}), so some files may not have been diffed.\n\n"
+    );
+    match outputs.first_mut() {
+        Some(output) => output.text.insert_str(0, &warning),
+        None => outputs.push(Output {
+            title,
+            summary: warning,
+            text: "".to_owned(),
+        }),
+    }
+}
+
 pub async fn get_pull_files<I: Into<InstallationId>>(
     (user, repo): (String, String),
     installation: I,
@@ -1,2 +1,8 @@
+pub mod budget;
+pub mod cooldown;
+pub mod in_flight;
+pub mod opt_out;
+pub mod render_limiter;
+pub mod resources;
 pub mod runner;
 pub mod types;
@@ -0,0 +1,64 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Keeps count of how many bytes of images a job has produced, so rendering can stop once it's over the limit
+#[derive(Debug, Default)]
+pub struct OutputBudget {
+    limit: Option<u64>,
+    used: AtomicU64,
+    images: AtomicUsize,
+}
+
+impl OutputBudget {
+    pub fn new(limit: Option<u64>) -> Self {
+        Self {
+            limit,
+            used: AtomicU64::new(0),
+            images: AtomicUsize::new(0),
+        }
+    }
+
+    /// One image of `bytes`
+    pub fn add(&self, bytes: u64) {
+        self.used.fetch_add(bytes, Ordering::Relaxed);
+        self.images.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Images that don't count against the limit, only towards the total
+    pub fn add_images(&self, count: usize) {
+        self.images.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn images(&self) -> usize {
+        self.images.load(Ordering::Relaxed)
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    pub fn exceeded(&self) -> bool {
+        self.limit.map_or(false, |limit| self.used() >= limit)
+    }
+
+    /// A note for the check output explaining the limit was hit, if it was
+    pub fn note(&self) -> Option<String> {
+        let limit = self.limit?;
+        self.exceeded().then(|| {
+            format!(
+                "\n\n**Output size limit of {} MiB reached after rendering {} MiB, some changes were not rendered.**\n",
+                limit / (1024 * 1024),
+                self.used() / (1024 * 1024)
+            )
+        })
+    }
+
+    /// Goes above the footer of a finished job, so a slow check can be told apart from a big one
+    pub fn effort_note(&self, elapsed: Duration) -> String {
+        format!(
+            "\n\n<sub>Rendered {} images in {:.1}s</sub>",
+            self.images(),
+            elapsed.as_secs_f64()
+        )
+    }
+}
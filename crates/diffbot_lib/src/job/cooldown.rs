@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// (repo id, pull request number)
+pub type PullKey = (u64, u64);
+
+struct Entry<T> {
+    last_start: Instant,
+    pending: Option<T>,
+}
+
+pub enum Submission<T> {
+    /// Go ahead and render it now
+    Ready(T),
+    /// Held until the cooldown is over, `take` it after waiting this long
+    Deferred(Duration),
+    /// Replaced an already deferred event, whoever is waiting on that one will pick this up instead
+    Coalesced,
+}
+
+/// Smooths rapid-fire pushes to a PR into a single render.
+/// Events arriving within the cooldown of the last render are held back, and only the latest one is kept.
+/// With a debounce, even the first event waits that long, so a burst of pushes never gets a check per push.
+pub struct Cooldown<T> {
+    duration: Duration,
+    debounce: Duration,
+    entries: Mutex<HashMap<PullKey, Entry<T>>>,
+}
+
+impl<T> Cooldown<T> {
+    pub fn new(duration: Duration) -> Self {
+        Self {
+            duration,
+            debounce: Duration::ZERO,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = debounce;
+        self
+    }
+
+    pub fn submit(&self, key: PullKey, item: T) -> Submission<T> {
+        if self.duration.is_zero() && self.debounce.is_zero() {
+            return Submission::Ready(item);
+        }
+
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+
+        // Don't let PRs that were pushed to once a week ago hang around forever
+        entries.retain(|_, entry| {
+            entry.pending.is_some() || now.duration_since(entry.last_start) < self.duration
+        });
+
+        let entry = match entries.get_mut(&key) {
+            Some(entry) => entry,
+            None if self.debounce.is_zero() => {
+                entries.insert(
+                    key,
+                    Entry {
+                        last_start: now,
+                        pending: None,
+                    },
+                );
+                return Submission::Ready(item);
+            }
+            None => {
+                entries.insert(
+                    key,
+                    Entry {
+                        last_start: now,
+                        pending: Some(item),
+                    },
+                );
+                return Submission::Deferred(self.debounce);
+            }
+        };
+
+        if entry.pending.replace(item).is_some() {
+            return Submission::Coalesced;
+        }
+
+        // retain() above already dropped entries that are past their cooldown with nothing pending
+        let remaining = self
+            .duration
+            .saturating_sub(now.duration_since(entry.last_start));
+        Submission::Deferred(remaining.max(self.debounce))
+    }
+
+    /// Picks up the latest deferred event for `key`, restarting its cooldown
+    pub fn take(&self, key: PullKey) -> Option<T> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(&key)?;
+        entry.last_start = Instant::now();
+        entry.pending.take()
+    }
+}
@@ -0,0 +1,90 @@
+//! Tracks pulls with a job rendering right now, so cleaning up after a closed pull waits for it instead of deleting images from under it
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use super::cooldown::PullKey;
+
+/// A pull can be in here more than once if it has several jobs going
+static IN_FLIGHT: Mutex<Vec<PullKey>> = Mutex::new(Vec::new());
+
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Dropped in each job's output directory, so the directories belonging to a pull can be found again
+const PULL_MARKER: &str = ".pull_request";
+
+/// Keeps the pull marked as in flight until dropped
+pub struct InFlight(PullKey);
+
+impl InFlight {
+    pub fn start(key: PullKey) -> Self {
+        IN_FLIGHT.lock().unwrap().push(key);
+        Self(key)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let mut in_flight = IN_FLIGHT.lock().unwrap();
+        if let Some(idx) = in_flight.iter().position(|key| *key == self.0) {
+            in_flight.swap_remove(idx);
+        }
+    }
+}
+
+pub fn is_in_flight(key: PullKey) -> bool {
+    IN_FLIGHT.lock().unwrap().contains(&key)
+}
+
+fn marker_contents((repo, pull_request): PullKey) -> String {
+    format!("{repo}/{pull_request}")
+}
+
+/// Records which pull `directory` belongs to, see [`pull_directories`]
+pub fn mark_pull(directory: &Path, key: PullKey) -> std::io::Result<()> {
+    std::fs::create_dir_all(directory)?;
+    std::fs::write(directory.join(PULL_MARKER), marker_contents(key))
+}
+
+/// Every directory anywhere under `parent` that was marked with `key`.
+/// Marked directories aren't searched any further, they're a job's output and hold nothing but images.
+pub fn pull_directories(parent: &Path, key: PullKey) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(parent) else {
+        return vec![];
+    };
+    let wanted = marker_contents(key);
+    let mut found = vec![];
+    for path in entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+    {
+        if !path.is_dir() {
+            continue;
+        }
+        match std::fs::read_to_string(path.join(PULL_MARKER)) {
+            Ok(marker) if marker.trim() == wanted => found.push(path),
+            Ok(_) => {}
+            Err(_) => found.extend(pull_directories(&path, key)),
+        }
+    }
+    found
+}
+
+/// Waits for the pull's jobs to finish, then deletes whatever `directories` comes up with.
+/// The directories are only looked up once it's idle, so ones the last job created are caught too.
+pub async fn remove_when_idle(key: PullKey, directories: impl FnOnce() -> Vec<PathBuf>) {
+    while is_in_flight(key) {
+        actix_web::rt::time::sleep(IDLE_POLL_INTERVAL).await;
+    }
+
+    for directory in directories() {
+        match std::fs::remove_dir_all(&directory) {
+            Ok(()) => log::info!("Removed images in {}", directory.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => log::error!("Failed to remove {}: {e}", directory.display()),
+        }
+    }
+}
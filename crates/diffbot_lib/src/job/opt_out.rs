@@ -0,0 +1,28 @@
+//! Pulls whose renders were turned off with a comment command, until they're turned back on or the pull is closed.
+//! Kept as marker files so it survives restarts.
+
+use std::path::{Path, PathBuf};
+
+use super::cooldown::PullKey;
+
+const DIRECTORY: &str = "./opted_out";
+
+fn marker((repo, pull_request): PullKey) -> PathBuf {
+    Path::new(DIRECTORY).join(format!("{repo}-{pull_request}"))
+}
+
+pub fn opt_out(key: PullKey) -> std::io::Result<()> {
+    std::fs::create_dir_all(DIRECTORY)?;
+    std::fs::write(marker(key), "")
+}
+
+pub fn opt_in(key: PullKey) -> std::io::Result<()> {
+    match std::fs::remove_file(marker(key)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+pub fn is_opted_out(key: PullKey) -> bool {
+    marker(key).exists()
+}
@@ -0,0 +1,65 @@
+//! Caps how many jobs render at once across every bot on the host, not just within one process.
+//! Permits are lock files in a directory the bots share, the OS drops a lock when the process holding it dies so nothing goes stale.
+
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use eyre::{Context, Result};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+pub fn default_directory() -> PathBuf {
+    std::env::temp_dir().join("diffbot-render-permits")
+}
+
+pub struct RenderLimiter {
+    directory: PathBuf,
+    permits: usize,
+}
+
+/// Releases its permit when dropped
+pub struct RenderPermit {
+    _lock: File,
+}
+
+impl RenderLimiter {
+    pub fn new(directory: &Path, permits: usize) -> Result<Self> {
+        std::fs::create_dir_all(directory).context("Creating render permit directory")?;
+        Ok(Self {
+            directory: directory.to_owned(),
+            // Zero would just hang every job
+            permits: permits.max(1),
+        })
+    }
+
+    /// Blocks until a permit frees up, call it from a blocking context
+    pub fn acquire(&self) -> Result<RenderPermit> {
+        let mut waiting = false;
+        loop {
+            for slot in 0..self.permits {
+                let path = self.directory.join(format!("permit-{slot}.lock"));
+                let file = OpenOptions::new()
+                    .create(true)
+                    .truncate(false)
+                    .write(true)
+                    .open(&path)
+                    .with_context(|| format!("Opening {}", path.display()))?;
+                match file.try_lock() {
+                    Ok(()) => return Ok(RenderPermit { _lock: file }),
+                    Err(TryLockError::WouldBlock) => continue,
+                    Err(TryLockError::Error(e)) => {
+                        return Err(e).with_context(|| format!("Locking {}", path.display()))
+                    }
+                }
+            }
+            if !waiting {
+                log::info!("All {} render permits are taken, waiting", self.permits);
+                waiting = true;
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+/// Logs the CPU time and memory the process used while a job ran once it's dropped, so it covers early returns too.
+/// The figures are process-wide, webhook handlers, cooldown tasks and anything else running meanwhile are counted too,
+/// so they're logged as such rather than as the job's own.
+/// Only Linux is supported, everywhere else the numbers are just missing from the log.
+pub struct ResourceMeter {
+    label: String,
+    details: Vec<String>,
+    start_cpu: Option<Duration>,
+}
+
+impl ResourceMeter {
+    pub fn start(label: String) -> Self {
+        Self {
+            label,
+            details: Vec::new(),
+            start_cpu: cpu_time(),
+        }
+    }
+
+    /// Extra context for the log line, like how many maps or states the job had
+    pub fn add_detail(&mut self, detail: String) {
+        self.details.push(detail);
+    }
+}
+
+impl Drop for ResourceMeter {
+    fn drop(&mut self) {
+        let cpu = match (self.start_cpu, cpu_time()) {
+            (Some(start), Some(end)) => format!("{:.2}s", end.saturating_sub(start).as_secs_f64()),
+            _ => "unknown".to_owned(),
+        };
+        let mib = |kib: Option<u64>| {
+            kib.map_or_else(|| "unknown".to_owned(), |kib| format!("{} MiB", kib / 1024))
+        };
+        let rss = mib(status_kib("VmRSS:"));
+        let peak = mib(status_kib("VmHWM:"));
+        log::info!(
+            "{} Process resource usage: cpu time while running {}, rss {}, peak rss since startup {}, {}",
+            self.label,
+            cpu,
+            rss,
+            peak,
+            self.details.join(", ")
+        );
+    }
+}
+
+/// Userspace facing clock ticks are always USER_HZ, which is 100 everywhere that matters
+const USER_HZ: u64 = 100;
+
+/// User + system time of the whole process, jobs fan out over rayon so a single thread's time would undercount
+fn cpu_time() -> Option<Duration> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    // The command name can contain spaces, so skip past its closing paren before splitting
+    let fields: Vec<&str> = stat.get(stat.rfind(')')? + 2..)?.split(' ').collect();
+    // utime and stime are the 14th and 15th fields, the first two were cut off above
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    Some(Duration::from_millis((utime + stime) * 1000 / USER_HZ))
+}
+
+/// A memory figure from /proc/self/status, like `VmRSS:`
+fn status_kib(field: &str) -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix(field))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()
+}
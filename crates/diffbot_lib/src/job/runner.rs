@@ -1,13 +1,214 @@
-use crate::github::{github_api::CheckRun, github_types::Output};
+use crate::github::{
+    github_api::{add_labels, repo_availability, CheckRun, FailureDetails, RepoAvailability},
+    github_types::{Output, Repository},
+};
+use crate::job::types::Job;
+use crate::upload::probe_store;
+use octocrab::models::InstallationId;
+use serde::Serialize;
+use std::time::Duration;
 
-pub async fn handle_output<S: AsRef<str>>(output: Vec<Output>, check_run: CheckRun, name: S) {
+/// Skips the job's check if the repository was archived or access to it was revoked while the job was queued.
+/// Returns whether the job should go ahead.
+pub async fn check_repo_available(job: &Job) -> bool {
+    let reason = match repo_availability(&job.installation, &job.repo).await {
+        Ok(RepoAvailability::Available) => return true,
+        Ok(RepoAvailability::Archived) => "The repository has been archived.".to_owned(),
+        Ok(RepoAvailability::Inaccessible(message)) => {
+            format!("The repository could not be accessed, it may have been deleted or had the app's access revoked: {message}")
+        }
+        // Not conclusive, let the job run into whatever is going on
+        Err(err) => {
+            log::warn!("Checking repository availability failed: {:?}", err);
+            return true;
+        }
+    };
+
+    log::info!("[{}] Skipping job: {}", job.repo.full_name(), reason);
+
+    // Archived repos are read only, so this may well fail too
+    let _ = job
+        .check_run
+        .mark_skipped(Output {
+            title: "Repository archived or access revoked",
+            summary: reason,
+            text: "".to_owned(),
+        })
+        .await;
+    false
+}
+
+/// Holds the job back while the image store can't be written to, probing again with doubling backoff for up to `max_wait`.
+/// Renders that can't be written are wasted work, better to wait out an outage than to fail at upload time.
+/// Returns whether the job should go ahead, its check is failed if the store stayed down.
+pub async fn wait_for_image_store(
+    job: &Job,
+    max_wait: Duration,
+    notify: (Option<&str>, Option<&str>),
+) -> bool {
+    let mut waited = Duration::ZERO;
+    let mut backoff = Duration::from_secs(5);
+    let error = loop {
+        let error = match probe_store() {
+            Ok(()) => return true,
+            Err(e) => e,
+        };
+        if waited >= max_wait {
+            break error;
+        }
+        let wait = backoff.min(max_wait - waited);
+        log::warn!(
+            "[{}#{}] Image store unavailable, holding the job for {wait:?}: {error:?}",
+            job.repo.full_name(),
+            job.pull_request
+        );
+        actix_web::rt::time::sleep(wait).await;
+        waited += wait;
+        backoff *= 2;
+    };
+
+    fail_job(
+        &job.check_run,
+        (&job.repo, job.pull_request),
+        FailureCategory::Error,
+        &format!(
+            "The image store was unavailable for {} seconds, so nothing was rendered: {error:?}",
+            waited.as_secs()
+        ),
+        notify,
+    )
+    .await;
+    false
+}
+
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum FailureCategory {
+    Timeout,
+    /// The job panicked or its thread otherwise died
+    Panic,
+    /// The job returned an error
+    Error,
+}
+
+impl FailureCategory {
+    fn description(self) -> &'static str {
+        match self {
+            FailureCategory::Timeout => "The job took too long and was stopped.",
+            FailureCategory::Panic => "The job crashed.",
+            FailureCategory::Error => "The job ran into an error.",
+        }
+    }
+
+    /// Nothing gets retried automatically, this is so nobody sits around waiting on one
+    fn retry_status(self) -> &'static str {
+        match self {
+            FailureCategory::Timeout => {
+                "No, and it will likely time out again on the same changes. Splitting the PR up may help."
+            }
+            FailureCategory::Panic | FailureCategory::Error => {
+                "No, pushing a new commit will render the PR again."
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct FailureReport<'a> {
+    repo: String,
+    pull_request: u64,
+    category: FailureCategory,
+    error: &'a str,
+    /// The check run id, every log line about the job carries it too
+    correlation_id: u64,
+}
+
+/// Marks the check as failed, and lets the operators know through `webhook` if there is one.
+/// The check is what PR participants see, the webhook is for piping failures into chat or paging.
+/// `support_link` is shown on the check, for wherever people should go asking about it.
+pub async fn fail_job(
+    check_run: &CheckRun,
+    (repo, pull_request): (&Repository, u64),
+    category: FailureCategory,
+    error: &str,
+    (webhook, support_link): (Option<&str>, Option<&str>),
+) {
+    let _ = check_run
+        .mark_failed_with(&FailureDetails {
+            stack_trace: error,
+            category: category.description(),
+            retry_status: category.retry_status(),
+            support_link,
+        })
+        .await;
+
+    let Some(url) = webhook else {
+        return;
+    };
+    let report = FailureReport {
+        repo: repo.full_name(),
+        pull_request,
+        category,
+        error,
+        correlation_id: check_run.id(),
+    };
+    let sent = match serde_json::to_vec(&report) {
+        Ok(body) => reqwest::Client::new()
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map(|_| ())
+            .map_err(eyre::Report::from),
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = sent {
+        log::error!(
+            "[{}#{}] [{}] Failed to send failure webhook: {:?}",
+            repo.full_name(),
+            pull_request,
+            check_run.id(),
+            e
+        );
+    }
+}
+
+/// Labels the PR after a render that found changes, so teams can triage off of what the bot saw.
+/// Failing to is only logged, the render itself went fine.
+pub async fn apply_labels(
+    installation: &InstallationId,
+    repo: &Repository,
+    pull_request: u64,
+    labels: &[String],
+) {
+    if labels.is_empty() {
+        return;
+    }
+    if let Err(e) = add_labels(installation, repo, pull_request, labels).await {
+        log::error!(
+            "[{}#{}] Failed to apply labels: {:?}",
+            repo.full_name(),
+            pull_request,
+            e
+        );
+    }
+}
+
+/// An empty `output` means the files were touched but nothing visually changed, `no_changes_message` is reported instead
+pub async fn handle_output<S: AsRef<str>>(
+    output: Vec<Output>,
+    check_run: CheckRun,
+    name: S,
+    no_changes_message: &str,
+) {
     match output.len() {
         0 => {
             let _ = check_run
                 .mark_succeeded(Output {
-                    title: "No relevant changes",
-                    summary: "No relevant changes detected, have metadatas been modified?"
-                        .to_owned(),
+                    title: "No visual changes",
+                    summary: no_changes_message.to_owned(),
                     text: "".to_owned(),
                 })
                 .await;
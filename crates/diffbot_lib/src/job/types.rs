@@ -1,15 +1,102 @@
 use crate::github::{
     github_api::CheckRun,
-    github_types::{self, Branch, CheckOutputs, FileDiff},
+    github_types::{self, Branch, CheckOutputs, FileDiff, PullRequest},
 };
 use eyre::Result;
+use futures_lite::Future;
 use octocrab::models::InstallationId;
 use serde::{Deserialize, Serialize};
-use yaque::Sender;
+use std::{collections::HashSet, time::Duration};
+use yaque::{Receiver, Sender};
 
 pub type JobRunner = fn(Job) -> Result<CheckOutputs>;
 
-pub type JobSender = Sender;
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum JobPriority {
+    #[default]
+    Normal,
+    High,
+}
+
+impl JobPriority {
+    /// High priority if the PR carries one of `labels`, or the repository is one of `repos`
+    pub fn for_pull(
+        repo_id: u64,
+        pull: &PullRequest,
+        labels: &HashSet<String>,
+        repos: &HashSet<u64>,
+    ) -> Self {
+        if repos.contains(&repo_id) || pull.labels.iter().any(|label| labels.contains(&label.name))
+        {
+            JobPriority::High
+        } else {
+            JobPriority::Normal
+        }
+    }
+}
+
+/// Opens the on-disk job queues, one per priority level
+pub fn job_channel(location: &str) -> std::io::Result<(JobSender, JobReceiver)> {
+    let (normal_sender, normal_receiver) = yaque::channel(location)?;
+    let (priority_sender, priority_receiver) = yaque::channel(format!("{location}-priority"))?;
+    Ok((
+        JobSender {
+            normal: normal_sender,
+            priority: priority_sender,
+        },
+        JobReceiver {
+            normal: normal_receiver,
+            priority: priority_receiver,
+        },
+    ))
+}
+
+pub struct JobSender {
+    normal: Sender,
+    priority: Sender,
+}
+
+impl JobSender {
+    pub async fn send(&mut self, priority: JobPriority, job: Vec<u8>) -> std::io::Result<()> {
+        match priority {
+            JobPriority::Normal => self.normal.send(job).await,
+            JobPriority::High => self.priority.send(job).await,
+        }
+    }
+}
+
+pub struct JobReceiver {
+    normal: Receiver,
+    priority: Receiver,
+}
+
+impl JobReceiver {
+    /// Hands queued jobs to `handler` forever, high priority ones first.
+    /// Jobs of the same priority are handled in the order they were sent.
+    pub async fn run<F, Fut>(mut self, mut handler: F)
+    where
+        F: FnMut(Vec<u8>) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        loop {
+            // Waits on both queues at once, `or` polls the priority one first so it wins when both have a job
+            let received = futures_lite::future::or(self.priority.recv(), self.normal.recv()).await;
+            match received {
+                Ok(jobguard) => {
+                    handler(jobguard.to_vec()).await;
+                    if let Err(err) = jobguard.commit() {
+                        log::error!("Failed to commit change to queue: {}", err)
+                    };
+                }
+                Err(err) => {
+                    log::error!("{}", err);
+                    // Don't spin on a queue that keeps failing
+                    actix_web::rt::time::sleep(Duration::from_millis(500)).await;
+                }
+            }
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum JobType {
@@ -26,4 +113,42 @@ pub struct Job {
     pub files: Vec<FileDiff>,
     pub check_run: CheckRun,
     pub installation: InstallationId,
+    #[serde(default)]
+    pub priority: JobPriority,
+    /// Github's files list for the PR hit its limit, `files` may be missing some
+    #[serde(default)]
+    pub files_truncated: bool,
+    /// Unix timestamp of when the job was sent to the queue, jobs queued by older versions don't have one
+    #[serde(default)]
+    pub queued_at: Option<i64>,
+}
+
+/// For [`Job::queued_at`]
+pub fn unix_now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+impl Job {
+    /// How long the job sat in the queue, if it knows when it was queued
+    pub fn queue_wait(&self) -> Option<Duration> {
+        let waited = unix_now() - self.queued_at?;
+        Some(Duration::from_secs(waited.max(0) as u64))
+    }
+
+    /// Fills in `{repo}`, `{repo_id}`, `{pull_request}` and `{check_run}` in an operator supplied url
+    pub fn details_url(&self, template: &str) -> String {
+        template
+            .replace("{repo}", &self.repo.full_name())
+            .replace("{repo_id}", &self.repo.id.to_string())
+            .replace("{pull_request}", &self.pull_request.to_string())
+            .replace("{check_run}", &self.check_run.id().to_string())
+    }
+
+    /// Where this job's images go under ./images, from an operator supplied template.
+    /// Takes the same placeholders as [`Job::details_url`], plus `{installation}` and `{head_sha}`.
+    pub fn image_directory(&self, template: &str) -> String {
+        self.details_url(template)
+            .replace("{installation}", &self.installation.0.to_string())
+            .replace("{head_sha}", &self.head.sha)
+    }
 }
@@ -1,7 +1,11 @@
 pub mod github;
 pub mod job;
 pub mod logger;
+pub mod png_encoding;
+pub mod toolchain;
+pub mod upload;
 pub mod verify;
+pub mod watermark;
 pub use async_fs;
 pub use async_mutex;
 pub use log;
@@ -0,0 +1,127 @@
+//! PNG encoding with an operator chosen trade off between CPU time and file size
+
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use eyre::{Context, Result};
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PngCompression {
+    /// Less CPU, bigger files
+    Fast,
+    #[default]
+    Balanced,
+    /// Smallest files, slowest to encode
+    Best,
+}
+
+impl From<PngCompression> for png::Compression {
+    fn from(compression: PngCompression) -> Self {
+        match compression {
+            PngCompression::Fast => png::Compression::Fast,
+            PngCompression::Balanced => png::Compression::Default,
+            PngCompression::Best => png::Compression::Best,
+        }
+    }
+}
+
+/// Encodes 8-bit RGBA pixels, row-major, into an in-memory PNG
+pub fn encode_rgba(
+    width: u32,
+    height: u32,
+    data: &[u8],
+    compression: PngCompression,
+) -> Result<Vec<u8>> {
+    encode(
+        width,
+        height,
+        png::ColorType::Rgba,
+        png::BitDepth::Eight,
+        data,
+        compression,
+    )
+}
+
+/// Encodes 8-bit RGBA pixels, row-major, straight into a file, returning how big it came out.
+/// Goes through a temporary file next to `path`, so there's never more than a buffer's worth of PNG in memory
+/// and nothing half-written ever sits at `path`.
+pub fn encode_rgba_to_file(
+    width: u32,
+    height: u32,
+    data: &[u8],
+    compression: PngCompression,
+    path: &Path,
+) -> Result<u64> {
+    let partial = path.with_extension("partial");
+    let file = std::fs::File::create(&partial)
+        .with_context(|| format!("Creating {}", partial.display()))?;
+    let mut writer = BufWriter::new(file);
+    let encoded = encode_into(
+        &mut writer,
+        width,
+        height,
+        png::ColorType::Rgba,
+        png::BitDepth::Eight,
+        data,
+        compression,
+    )
+    .and_then(|()| writer.flush().context("Flushing PNG"));
+    if let Err(e) = encoded {
+        let _ = std::fs::remove_file(&partial);
+        return Err(e);
+    }
+    std::fs::rename(&partial, path).with_context(|| format!("Moving PNG to {}", path.display()))?;
+    Ok(std::fs::metadata(path)?.len())
+}
+
+/// Re-encodes a PNG produced elsewhere with the given compression, keeping its color type and depth
+pub fn recompress(bytes: &[u8], compression: PngCompression) -> Result<Vec<u8>> {
+    let mut reader = png::Decoder::new(bytes)
+        .read_info()
+        .context("Reading PNG header")?;
+    let mut data = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut data).context("Decoding PNG")?;
+    data.truncate(info.buffer_size());
+    encode(
+        info.width,
+        info.height,
+        info.color_type,
+        info.bit_depth,
+        &data,
+        compression,
+    )
+}
+
+fn encode(
+    width: u32,
+    height: u32,
+    color: png::ColorType,
+    depth: png::BitDepth,
+    data: &[u8],
+    compression: PngCompression,
+) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    encode_into(&mut bytes, width, height, color, depth, data, compression)?;
+    Ok(bytes)
+}
+
+fn encode_into<W: Write>(
+    output: W,
+    width: u32,
+    height: u32,
+    color: png::ColorType,
+    depth: png::BitDepth,
+    data: &[u8],
+    compression: PngCompression,
+) -> Result<()> {
+    let mut encoder = png::Encoder::new(output, width, height);
+    encoder.set_color(color);
+    encoder.set_depth(depth);
+    encoder.set_compression(compression.into());
+    let mut writer = encoder.write_header().context("Writing PNG header")?;
+    writer.write_image_data(data).context("Encoding PNG")?;
+    writer.finish().context("Finishing PNG")?;
+    Ok(())
+}
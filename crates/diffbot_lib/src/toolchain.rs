@@ -0,0 +1,9 @@
+/// dmm-tools version and commit the bots were built against, from the lockfile
+pub const DMM_TOOLS_VERSION: &str = env!("DMM_TOOLS_VERSION");
+
+/// Footer for check outputs, so a render that looks off can be traced back to a toolchain update
+pub fn footer() -> String {
+    format!(
+        "\n\n<sub>Rendered with [SpacemanDMM](https://github.com/SpaceManiac/SpacemanDMM) dmm-tools {DMM_TOOLS_VERSION}</sub>\n"
+    )
+}
@@ -0,0 +1,157 @@
+//! Writing rendered images to the image store, retrying transient failures so one flaky write doesn't throw away a whole render
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use eyre::Result;
+
+/// Images that couldn't be written even after retrying get a line in here, for someone to look at later
+pub const DEAD_LETTER_LOG: &str = "failed_uploads.log";
+
+/// Shown in place of an image that never made it to the store
+pub const UPLOAD_FAILED: &str = "*Failed to upload image*";
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(200);
+
+/// Where both bots keep their images
+pub const IMAGE_STORE: &str = "./images";
+
+/// Writes and removes a small file in the image store, catching a full disk or a network mount that went away
+pub fn probe_store() -> Result<()> {
+    std::fs::create_dir_all(IMAGE_STORE)?;
+    let probe = Path::new(IMAGE_STORE).join(format!(".probe-{}", std::process::id()));
+    std::fs::write(&probe, b"probe")?;
+    std::fs::remove_file(&probe)?;
+    Ok(())
+}
+
+/// Calls `write` until it succeeds or `retries` more attempts have failed, doubling the wait every time.
+/// Permanent failures are recorded to [`DEAD_LETTER_LOG`] before the last error is returned.
+/// It sleeps between attempts, writes made on a rayon pool go through a [`RetryQueue`] instead.
+pub fn write_with_retries(
+    path: &Path,
+    retries: u32,
+    mut write: impl FnMut(&Path) -> Result<()>,
+) -> Result<()> {
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 0;
+    loop {
+        match write(path) {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < retries => {
+                log::warn!("Writing {path:?} failed, retrying in {backoff:?}: {e:?}");
+                std::thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(e) => {
+                log::error!("Giving up on writing {path:?}: {e:?}");
+                dead_letter(path, &e);
+                return Err(e);
+            }
+        }
+    }
+}
+
+type PendingWrite = (
+    PathBuf,
+    eyre::Report,
+    Box<dyn FnMut(&Path) -> Result<u64> + Send>,
+);
+
+/// Writes made on a rayon pool, where a failed one is queued instead of retried on the spot.
+/// Sleeping through the backoff there would hold up everything else sharing the pool, [`RetryQueue::retry`] goes through them once the parallel work's done.
+#[derive(Default)]
+pub struct RetryQueue {
+    pending: Mutex<Vec<PendingWrite>>,
+}
+
+impl std::fmt::Debug for RetryQueue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RetryQueue")
+            .field("pending", &self.pending.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl RetryQueue {
+    /// Tries `write` once, returning how many bytes it wrote. If it fails it's queued for [`RetryQueue::retry`] and None is returned
+    pub fn write(
+        &self,
+        path: &Path,
+        mut write: impl FnMut(&Path) -> Result<u64> + Send + 'static,
+    ) -> Option<u64> {
+        match write(path) {
+            Ok(size) => Some(size),
+            Err(e) => {
+                log::warn!("Writing {path:?} failed, retrying once the rest are written: {e:?}");
+                self.pending
+                    .lock()
+                    .unwrap()
+                    .push((path.to_owned(), e, Box::new(write)));
+                None
+            }
+        }
+    }
+
+    /// Gives every queued write the `retries` more attempts [`write_with_retries`] would have, with the same backoff.
+    /// Call it off the pool. Anything a write that still fails left behind is removed, so a partial image is never served
+    pub fn retry(&self, retries: u32) -> Vec<(PathBuf, Result<u64>)> {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        pending
+            .into_iter()
+            .map(|(path, error, mut write)| {
+                let written = match retries {
+                    0 => {
+                        log::error!("Giving up on writing {path:?}: {error:?}");
+                        dead_letter(&path, &error);
+                        Err(error)
+                    }
+                    retries => {
+                        let mut size = 0;
+                        write_with_retries(&path, retries - 1, |path| {
+                            size = write(path)?;
+                            Ok(())
+                        })
+                        .map(|()| size)
+                    }
+                };
+                if written.is_err() {
+                    let _ = std::fs::remove_file(&path);
+                }
+                (path, written)
+            })
+            .collect()
+    }
+}
+
+fn dead_letter(path: &Path, error: &eyre::Report) {
+    let res = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(DEAD_LETTER_LOG)
+        .and_then(|mut file| {
+            writeln!(
+                file,
+                "{}\t{}\t{:?}",
+                chrono::Utc::now().to_rfc3339(),
+                path.display(),
+                error.to_string()
+            )
+        });
+    if let Err(e) = res {
+        log::error!("Failed to write to {DEAD_LETTER_LOG}: {e}");
+    }
+}
+
+/// Short, stable hash of a stored image's contents, so reviewers can tell at a glance whether a re-render changed it
+pub fn content_hash(path: &Path) -> Option<String> {
+    use sha2::{Digest, Sha256};
+    // Map renders can be huge, no need to have the whole thing in memory just to hash it
+    let mut file = std::fs::File::open(path).ok()?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).ok()?;
+    Some(hex::encode(&hasher.finalize()[..6]))
+}
@@ -13,13 +13,29 @@ pub fn verify_signature(
 ) -> Result<(), actix_web::error::Error> {
     if let Some(sekrit) = secret {
         let Some(sig) = signature else {
-            return Err(actix_web::error::ErrorBadRequest("Expected signature in header"))
+            return Err(actix_web::error::ErrorUnauthorized("Expected signature in header"))
         };
 
         log::trace!("Received sig: {:?}", sig);
 
+        let Some(sig) = sig.strip_prefix(b"sha256=") else {
+            return Err(actix_web::error::ErrorUnauthorized(
+                "Signature is missing its sha256= prefix",
+            ));
+        };
+        let Ok(sig) = hex::decode(sig) else {
+            return Err(actix_web::error::ErrorUnauthorized("Signature isn't hex"));
+        };
+
+        // clone_from_slice panics on anything that isn't exactly one SHA-256 long
+        if sig.len() != 32 {
+            return Err(actix_web::error::ErrorUnauthorized(
+                "Signature is the wrong length",
+            ));
+        }
+
         //have to wrap it to stop timing attacks on comparison
-        let actual_signature = CtOutput::new(GenericArray::clone_from_slice(sig));
+        let actual_signature = CtOutput::new(GenericArray::clone_from_slice(&sig));
 
         let mut mac = HmacSha256::new_from_slice(sekrit.as_bytes()).unwrap();
         mac.update(payload.as_bytes());
@@ -31,10 +47,20 @@ pub fn verify_signature(
         );
 
         if actual_signature.ne(&computed_signature) {
-            return Err(actix_web::error::ErrorBadRequest(
+            return Err(actix_web::error::ErrorUnauthorized(
                 "Signature does not match!",
             ));
         };
     }
     Ok(())
 }
+
+/// Constant time, so the token can't be guessed byte by byte from response times
+pub fn tokens_match(expected: &str, provided: &str) -> bool {
+    expected.len() == provided.len()
+        && expected
+            .bytes()
+            .zip(provided.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
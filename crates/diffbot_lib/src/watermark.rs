@@ -0,0 +1,156 @@
+//! Stamps a small text label onto rendered images, so they aren't mistaken for authoritative renders when shared around
+
+const GLYPH_WIDTH: usize = 3;
+const GLYPH_HEIGHT: usize = 5;
+const SCALE: usize = 2;
+const PADDING: usize = 2;
+
+/// 3x5 glyphs, one row per entry, most significant of the 3 bits is the leftmost pixel
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c.to_ascii_uppercase() {
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b110, 0b001, 0b010, 0b100, 0b111],
+        '3' => [0b110, 0b001, 0b010, 0b001, 0b110],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b110, 0b001, 0b110],
+        '6' => [0b011, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b110],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        ',' => [0b000, 0b000, 0b000, 0b010, 0b100],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '(' => [0b010, 0b100, 0b100, 0b100, 0b010],
+        ')' => [0b010, 0b001, 0b001, 0b001, 0b010],
+        '!' => [0b010, 0b010, 0b010, 0b000, 0b010],
+        '?' => [0b110, 0b001, 0b010, 0b000, 0b010],
+        _ => [0; GLYPH_HEIGHT],
+    }
+}
+
+fn blend(pixel: &mut [u8], color: [u8; 3], alpha: u16) {
+    for (channel, value) in pixel.iter_mut().zip(color) {
+        *channel = ((*channel as u16 * (255 - alpha) + value as u16 * alpha) / 255) as u8;
+    }
+    pixel[3] = pixel[3].max(alpha as u8);
+}
+
+/// Width in pixels `text` takes up when drawn
+pub fn text_width(text: &str) -> usize {
+    text.chars().count() * (GLYPH_WIDTH + 1) * SCALE
+}
+
+pub const TEXT_HEIGHT: usize = GLYPH_HEIGHT * SCALE;
+
+/// Draws `text` with its top left corner at (`left`, `top`) of a row-major RGBA8 image.
+/// Anything falling outside the image is clipped.
+pub fn draw_text(
+    width: usize,
+    height: usize,
+    pixels: &mut [u8],
+    (left, top): (usize, usize),
+    text: &str,
+    color: [u8; 3],
+) {
+    if pixels.len() < width * height * 4 {
+        return;
+    }
+
+    for (n, c) in text.chars().enumerate() {
+        let rows = glyph(c);
+        let glyph_left = left + n * (GLYPH_WIDTH + 1) * SCALE;
+        for (row, bits) in rows.iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                if bits & (0b100 >> col) == 0 {
+                    continue;
+                }
+                for dy in 0..SCALE {
+                    for dx in 0..SCALE {
+                        let x = glyph_left + col * SCALE + dx;
+                        let y = top + row * SCALE + dy;
+                        if x >= width || y >= height {
+                            continue;
+                        }
+                        let idx = (y * width + x) * 4;
+                        blend(&mut pixels[idx..idx + 4], color, 160);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Darkens a rectangle, to keep text readable on busy backgrounds. Clipped like [`draw_text`].
+pub fn draw_backdrop(
+    width: usize,
+    height: usize,
+    pixels: &mut [u8],
+    (left, top): (usize, usize),
+    (box_width, box_height): (usize, usize),
+) {
+    if pixels.len() < width * height * 4 {
+        return;
+    }
+
+    for y in top..(top + box_height).min(height) {
+        for x in left..(left + box_width).min(width) {
+            let idx = (y * width + x) * 4;
+            blend(&mut pixels[idx..idx + 4], [0, 0, 0], 96);
+        }
+    }
+}
+
+/// Draws `text` in the bottom left corner of a row-major RGBA8 image.
+/// Images too small to fit the label are left alone.
+pub fn draw_watermark(width: usize, height: usize, pixels: &mut [u8], text: &str) {
+    let box_width = text_width(text) + PADDING * 2;
+    let box_height = TEXT_HEIGHT + PADDING * 2;
+    if text.is_empty()
+        || box_width > width
+        || box_height > height
+        || pixels.len() < width * height * 4
+    {
+        return;
+    }
+
+    let top = height - box_height;
+    draw_backdrop(width, height, pixels, (0, top), (box_width, box_height));
+    draw_text(
+        width,
+        height,
+        pixels,
+        (PADDING, top + PADDING),
+        text,
+        [255, 255, 255],
+    );
+}
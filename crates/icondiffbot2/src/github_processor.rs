@@ -1,35 +1,132 @@
 use diffbot_lib::{
     github::{
         github_api::CheckRun,
-        github_types::{ChangeType, Output, PullRequestEventPayload},
-        graphql::get_pull_files,
+        github_types::{
+            title_skip_marker, ChangeType, Output, PullRequestEventPayload, Repository,
+        },
+        graphql::{get_pull_files, pull_files_truncated},
+    },
+    job::{
+        cooldown::Submission,
+        in_flight::{pull_directories, remove_when_idle},
+        types::{unix_now, Job, JobPriority},
     },
-    job::types::Job,
 };
 use eyre::Result;
 use octocrab::models::InstallationId;
 
 use diffbot_lib::github::github_types::FileDiff;
 
-use crate::DataJobSender;
+use crate::{DataCooldown, DataJobSender};
+use std::path::Path;
+
+/// The name checks are created with, `check_name_suffix` and all
+fn check_name(repo: &Repository) -> String {
+    diffbot_lib::github::github_api::check_name(
+        "IconDiffBot2",
+        crate::CONFIG.get().unwrap().check_name_suffix.as_deref(),
+        repo,
+    )
+}
+
+/// Closed or merged, so nobody's looking at its renders anymore
+fn clean_up_closed_pull(payload: &PullRequestEventPayload, cooldown: &DataCooldown) {
+    let key = (payload.repository.id, payload.pull_request.number);
+    // No point rendering a deferred push either
+    let _ = cooldown.take(key);
+
+    actix_web::rt::spawn(remove_when_idle(key, move || {
+        pull_directories(Path::new("./images"), key)
+    }));
+}
 
 async fn handle_pull_request(
     payload: PullRequestEventPayload,
     job_sender: DataJobSender,
+    cooldown: DataCooldown,
 ) -> Result<()> {
     match payload.action.as_str() {
+        "closed" => {
+            clean_up_closed_pull(&payload, &cooldown);
+            return Ok(());
+        }
         "opened" => {}
         #[cfg(debug_assertions)]
         "reopened" => {}
         "synchronize" => {}
+        "edited"
+            if payload.skip_marker_removed(&crate::CONFIG.get().unwrap().skip_title_patterns) => {}
         _ => return Ok(()),
     }
 
+    let key = (payload.repository.id, payload.pull_request.number);
+    let submission = cooldown.submit(key, payload);
+    match submission {
+        Submission::Ready(payload) => render_pull_request(payload, job_sender).await,
+        Submission::Deferred(delay) => {
+            actix_web::rt::spawn(async move {
+                actix_web::rt::time::sleep(delay).await;
+                if let Some(payload) = cooldown.take(key) {
+                    if let Err(e) = render_pull_request(payload, job_sender).await {
+                        diffbot_lib::log::error!("Error handling deferred event: {:?}", e);
+                    }
+                }
+            });
+            Ok(())
+        }
+        Submission::Coalesced => Ok(()),
+    }
+}
+
+async fn render_pull_request(
+    payload: PullRequestEventPayload,
+    job_sender: DataJobSender,
+) -> Result<()> {
+    let files = get_pull_files(
+        payload.repository.name_tuple(),
+        payload.installation.id,
+        &payload.pull_request,
+    )
+    .await?;
+    let files_truncated = pull_files_truncated(&files);
+
+    // Nothing to clone or queue, it gets its answer right away
+    if files.is_empty() {
+        if crate::CONFIG.get().unwrap().empty_pull_check {
+            CheckRun::create(
+                &payload.repository.full_name(),
+                &payload.pull_request.head.sha,
+                payload.installation.id,
+                Some(&check_name(&payload.repository)),
+            )
+            .await?
+            .mark_skipped(Output::empty_pull())
+            .await?;
+        }
+        return Ok(());
+    }
+
+    let changed_dmis: Vec<FileDiff> = files
+        .into_iter()
+        .filter(|e| e.has_extension("dmi"))
+        .filter(|e| {
+            matches!(
+                e.status,
+                ChangeType::Added | ChangeType::Deleted | ChangeType::Modified
+            )
+        })
+        .collect();
+
+    // No point in putting a skipped check on every push or title edit of a PR that never touched an icon
+    if payload.action != "opened" && changed_dmis.is_empty() {
+        return Ok(());
+    }
+
     let check_run = CheckRun::create(
         &payload.repository.full_name(),
         &payload.pull_request.head.sha,
         payload.installation.id,
-        Some("IconDiffBot2"),
+        Some(&check_name(&payload.repository)),
     )
     .await?;
 
@@ -51,6 +148,22 @@ async fn handle_pull_request(
         return Ok(());
     }
 
+    if let Some(marker) = title_skip_marker(
+        payload.pull_request.title.as_deref(),
+        &crate::CONFIG.get().unwrap().skip_title_patterns,
+    ) {
+        let output = Output {
+            title: "PR Skipped",
+            summary: format!(
+                "This PR has `{marker}` in the title, it will be rendered once that's removed."
+            ),
+            text: "".to_owned(),
+        };
+
+        check_run.mark_skipped(output).await?;
+        return Ok(());
+    }
+
     let conf = &crate::CONFIG.get().unwrap();
     let (blacklist, contact) = (&conf.blacklist, &conf.blacklist_contact);
 
@@ -70,24 +183,6 @@ async fn handle_pull_request(
         return Ok(());
     }
 
-    let files = get_pull_files(
-        payload.repository.name_tuple(),
-        payload.installation.id,
-        &payload.pull_request,
-    )
-    .await?;
-
-    let changed_dmis: Vec<FileDiff> = files
-        .into_iter()
-        .filter(|e| e.filename.ends_with(".dmi"))
-        .filter(|e| {
-            matches!(
-                e.status,
-                ChangeType::Added | ChangeType::Deleted | ChangeType::Modified
-            )
-        })
-        .collect();
-
     if changed_dmis.is_empty() {
         let output = Output {
             title: "No icon changes",
@@ -105,6 +200,13 @@ async fn handle_pull_request(
     let pull = payload.pull_request;
     let installation = payload.installation;
 
+    let priority = JobPriority::for_pull(
+        payload.repository.id,
+        &pull,
+        &conf.priority_labels,
+        &conf.priority_repos,
+    );
+
     let job = Job {
         repo: payload.repository,
         base: pull.base,
@@ -113,11 +215,14 @@ async fn handle_pull_request(
         files: changed_dmis,
         check_run,
         installation: InstallationId(installation.id),
+        priority,
+        files_truncated,
+        queued_at: Some(unix_now()),
     };
 
     let job = serde_json::to_vec(&job)?;
 
-    job_sender.lock().await.send(job).await?;
+    job_sender.lock().await.send(priority, job).await?;
 
     Ok(())
 }
@@ -127,6 +232,7 @@ pub async fn process_github_payload_actix(
     event: diffbot_lib::github::github_api::GithubEvent,
     payload: String,
     job_sender: DataJobSender,
+    cooldown: DataCooldown,
 ) -> actix_web::Result<&'static str> {
     // TODO: Handle reruns
     if event.0 != "pull_request" {
@@ -146,7 +252,7 @@ pub async fn process_github_payload_actix(
 
     let payload: PullRequestEventPayload = serde_json::from_str(&payload)?;
 
-    handle_pull_request(payload, job_sender)
+    handle_pull_request(payload, job_sender, cooldown)
         .await
         .map_err(actix_web::error::ErrorBadRequest)?;
 
@@ -1,158 +1,692 @@
 use crate::{
-    sha::{sha_to_iconfile, status_to_sha, IconFileWithName},
+    sha::{get_if_exists, sha_to_iconfile, status_to_sha, IconFileWithName},
     table_builder::OutputTableBuilder,
-    CONFIG,
+    CompareAgainst, DiffFrames, CONFIG,
+};
+use diffbot_lib::log::{error, warn};
+use diffbot_lib::png_encoding::{encode_rgba, recompress, PngCompression};
+use diffbot_lib::toolchain;
+use diffbot_lib::upload::{RetryQueue, UPLOAD_FAILED};
+use diffbot_lib::watermark::draw_watermark;
+use diffbot_lib::{
+    github::github_api::default_branch_head,
+    github::github_types::{CheckOutputs, FileDiff, Output},
+    github::graphql::warn_truncated_files,
+    job::{
+        budget::OutputBudget, in_flight, render_limiter::RenderLimiter, resources::ResourceMeter,
+        types::Job,
+    },
 };
-use diffbot_lib::log::error;
-use diffbot_lib::{github::github_types::CheckOutputs, job::types::Job};
 use dmm_tools::dmi::render::{IconRenderer, RenderType};
-use dmm_tools::dmi::State;
-use dreammaker::dmi::StateIndex;
+use dmm_tools::dmi::{IconFile, Image, State};
 use eyre::{Context, Result};
-use hashbrown::HashSet;
+use hashbrown::{HashMap, HashSet};
+use once_cell::sync::OnceCell;
 use rayon::prelude::*;
 use std::{
-    fs::File,
     hash::{Hash, Hasher},
-    io::{BufWriter, Write},
-    path::Path,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::RecvTimeoutError,
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
 };
 
+/// Identifies a state across both versions of a file.
+/// Movement and non-movement states can share a name, so the name alone isn't enough to pair them up.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct StateKey {
+    name: String,
+    movement: bool,
+    /// How many states with the same name and movement flag came before this one
+    nth: u32,
+}
+
+impl std::fmt::Display for StateKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.name.is_empty() {
+            write!(f, "{}", CONFIG.get().unwrap().default_state_label)?;
+        } else {
+            write!(f, "{}", self.name)?;
+        }
+        if self.movement {
+            write!(f, " (movement)")?;
+        }
+        if self.nth > 0 {
+            write!(f, " ({})", self.nth)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where a rendered image ended up
+enum Upload {
+    Url(String),
+    /// Written once the rest of the job's renders are done, see [`Row::settle_retries`]
+    Retrying {
+        url: String,
+        path: PathBuf,
+    },
+    Failed,
+}
+
+impl Upload {
+    /// The markdown for its table cell, embedded while `embeds` has any left and linked after that
+    fn markdown(&self, embeds: &mut usize) -> String {
+        match self {
+            Upload::Url(url) | Upload::Retrying { url, .. } if *embeds > 0 => {
+                *embeds -= 1;
+                format!("![{url}]({url})")
+            }
+            Upload::Url(url) | Upload::Retrying { url, .. } => format!("[{url}]({url})"),
+            Upload::Failed => UPLOAD_FAILED.to_owned(),
+        }
+    }
+}
+
+/// A state's line in its file's table, only formatted once the job knows how many images it can embed
+struct Row {
+    state_name: String,
+    old: Option<Upload>,
+    new: Option<Upload>,
+    diff: Vec<Upload>,
+    change_text: String,
+}
+
+impl Row {
+    /// A line with nothing but a status
+    fn note(change_text: impl Into<String>) -> Self {
+        Self {
+            state_name: String::new(),
+            old: None,
+            new: None,
+            diff: vec![],
+            change_text: change_text.into(),
+        }
+    }
+
+    fn modified(&self) -> bool {
+        self.old.is_some() && self.new.is_some()
+    }
+
+    fn uploads(&self) -> impl Iterator<Item = &Upload> {
+        self.old.iter().chain(&self.new).chain(&self.diff)
+    }
+
+    /// Swaps in how the writes that had to be retried turned out, `failed` holding the paths that never got written
+    fn settle_retries(&mut self, failed: &HashSet<PathBuf>) {
+        for upload in self
+            .old
+            .iter_mut()
+            .chain(&mut self.new)
+            .chain(&mut self.diff)
+        {
+            if let Upload::Retrying { url, path } = upload {
+                *upload = if failed.contains(path) {
+                    Upload::Failed
+                } else {
+                    Upload::Url(std::mem::take(url))
+                };
+            }
+        }
+    }
+
+    fn images(&self) -> usize {
+        self.uploads()
+            .filter(|upload| !matches!(upload, Upload::Failed))
+            .count()
+    }
+
+    fn format(&self, embeds: &mut usize) -> String {
+        let mut cell = |upload: &Option<Upload>| {
+            upload
+                .as_ref()
+                .map(|upload| upload.markdown(embeds))
+                .unwrap_or_default()
+        };
+        let (old, new) = (cell(&self.old), cell(&self.new));
+        let diff = self
+            .diff
+            .iter()
+            .map(|upload| upload.markdown(embeds))
+            .collect::<Vec<_>>()
+            .join(" ");
+        format!(
+            include_str!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/templates/diff_line.txt"
+            )),
+            state_name = self.state_name,
+            old = old,
+            new = new,
+            diff = diff,
+            change_text = self.change_text,
+        )
+    }
+}
+
+fn keyed_states(icon: &IconFile) -> HashMap<StateKey, &State, ahash::RandomState> {
+    let mut seen: HashMap<(&str, bool), u32, ahash::RandomState> = Default::default();
+    icon.metadata
+        .states
+        .iter()
+        .map(|state| {
+            let nth = seen
+                .entry((state.name.as_str(), state.movement))
+                .or_insert(0);
+            let key = StateKey {
+                name: state.name.clone(),
+                movement: state.movement,
+                nth: *nth,
+            };
+            *nth += 1;
+            (key, state)
+        })
+        .collect()
+}
+
+/// States sharing a name and movement flag, BYOND will only ever pick one of them
+fn duplicate_states(icon: &IconFile) -> Vec<StateKey> {
+    keyed_states(icon)
+        .into_keys()
+        .filter(|key| key.nth == 1)
+        .map(|key| StateKey { nth: 0, ..key })
+        .collect()
+}
+
+/// A file's rows, waiting to be formatted
+struct RenderedFile<'a> {
+    filename: &'a str,
+    status: &'static str,
+    rows: Vec<Row>,
+    /// Too big to embed, every image is linked and none count against the inline limit
+    link_only: bool,
+}
+
+/// Formats every file's rows, embedding the first `limit` images and linking the rest.
+/// Modified states get first dibs since those are what reviewers look at.
+fn format_rows<'a>(
+    rendered: Vec<RenderedFile<'a>>,
+    limit: Option<usize>,
+) -> Vec<(&'a str, (&'static str, Vec<String>))> {
+    let mut remaining = limit.unwrap_or(usize::MAX);
+    let mut allowances: Vec<Vec<usize>> = rendered
+        .iter()
+        .map(|file| vec![0; file.rows.len()])
+        .collect();
+    for modified in [true, false] {
+        for (file, allowance) in rendered.iter().zip(&mut allowances) {
+            if file.link_only {
+                continue;
+            }
+            for (row, allowed) in file.rows.iter().zip(allowance) {
+                if row.modified() == modified {
+                    *allowed = row.images().min(remaining);
+                    remaining -= *allowed;
+                }
+            }
+        }
+    }
+
+    rendered
+        .into_iter()
+        .zip(allowances)
+        .map(|(file, allowance)| {
+            let lines = file
+                .rows
+                .iter()
+                .zip(allowance)
+                .map(|(row, mut allowed)| row.format(&mut allowed))
+                .collect();
+            (file.filename, (file.status, lines))
+        })
+        .collect()
+}
+
+/// Why a job with changed files could come out with nothing rendered, as list items for the summary
+fn nothing_rendered_reasons(out_of_budget: bool, warned: bool) -> Vec<String> {
+    let conf = CONFIG.get().unwrap();
+    let mut reasons = vec![];
+    if out_of_budget {
+        reasons
+            .push("- The output size limit was reached before anything was rendered.".to_owned());
+    }
+    if warned {
+        reasons.push("- Some files couldn't be rendered, see the notes below.".to_owned());
+    }
+    if conf.min_changed_pixels > 0 || conf.min_changed_percent > 0.0 {
+        reasons.push(format!(
+            "- States changing fewer than {} pixels or {}% of their pixels aren't shown.",
+            conf.min_changed_pixels, conf.min_changed_percent
+        ));
+    }
+    reasons
+}
+
+/// Downloads get their own pool, sized by `concurrency.downloads`, so they don't hog the global one rendering runs on
+fn download_pool() -> Option<&'static rayon::ThreadPool> {
+    static POOL: OnceCell<Option<rayon::ThreadPool>> = OnceCell::new();
+    POOL.get_or_init(|| {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(CONFIG.get().unwrap().concurrency.downloads.max(1))
+            .thread_name(|idx| format!("download-{idx}"))
+            .build()
+            .map_err(|e| error!("Failed to build download thread pool, using the global one: {e}"))
+            .ok()
+    })
+    .as_ref()
+}
+
+/// Both versions of a file to diff, depending on what it's being compared against
+fn fetch_file(
+    job: &Job,
+    dmi: &FileDiff,
+    live_sha: Option<&str>,
+) -> Result<(Option<IconFileWithName>, Option<IconFileWithName>)> {
+    let shas = status_to_sha(job, &dmi.status);
+    match live_sha {
+        Some(live_sha) => {
+            let after = get_if_exists(job, &dmi.filename, shas.1)?;
+            // Added files may already be live, and modified or deleted ones may not be anymore
+            let before = get_if_exists(job, &dmi.filename, Some(live_sha))
+                .map_err(|e| {
+                    warn!(
+                        "{} isn't on the default branch, diffing as added: {e:?}",
+                        dmi.filename
+                    )
+                })
+                .ok()
+                .flatten();
+            Ok((before, after))
+        }
+        None => sha_to_iconfile(job, &dmi.filename, shas),
+    }
+}
+
 #[tracing::instrument]
-pub fn do_job(job: Job) -> Result<CheckOutputs> {
+pub fn do_job(job: Job, limiter: Option<&RenderLimiter>) -> Result<CheckOutputs> {
+    let started = Instant::now();
+    let mut meter = ResourceMeter::start(format!(
+        "[{}#{}] [{}]",
+        job.repo.full_name(),
+        job.pull_request,
+        job.check_run.id()
+    ));
+    meter.add_detail(format!("{} icons", job.files.len()));
+
     let handle = actix_web::rt::Runtime::new()?;
 
-    handle.block_on(async { job.check_run.mark_started().await })?;
+    handle.block_on(async { job.check_run.mark_started(None).await })?;
+
+    // So the images can be found and cleaned up once the PR is closed
+    let directory = Path::new(".").join("images").join(job_prefix(&job));
+    if let Err(e) = in_flight::mark_pull(&directory, (job.repo.id, job.pull_request)) {
+        warn!("Failed to mark image directory with its PR: {e}");
+    }
+
+    // Held until the job's done, so the other bot on this host holds back too
+    let _permit = limiter
+        .map(RenderLimiter::acquire)
+        .transpose()
+        .context("Acquiring a render permit")?;
 
     let mut map = OutputTableBuilder::new();
 
+    let budget = Arc::new(OutputBudget::new(
+        CONFIG.get().unwrap().max_upload_bytes_per_job,
+    ));
+
+    let mut warnings = vec![];
+    let mut state_count = 0;
+
+    // Resolved once, so every file is compared against the same commit
+    let live_head = match CONFIG.get().unwrap().compare_against {
+        CompareAgainst::Base => None,
+        CompareAgainst::DefaultBranch => Some(
+            handle
+                .block_on(default_branch_head(&job.installation, &job.repo))
+                .context("Resolving the default branch's head")?,
+        ),
+    };
+
+    // Fetched up front, a few at a time, the rendering below still goes through them in order
+    let live_sha = live_head.as_ref().map(|(_, sha)| sha.as_str());
+    let fetch_all = || -> Vec<_> {
+        job.files
+            .par_iter()
+            .map(|dmi| fetch_file(&job, dmi, live_sha))
+            .collect()
+    };
+    let mut downloaded = match download_pool() {
+        Some(pool) => pool.install(fetch_all),
+        None => fetch_all(),
+    }
+    .into_iter();
+
+    let mut rendered = vec![];
+    let retries = Arc::new(RetryQueue::default());
+    // Timed out renders, waited on before the job finishes so they don't outlive the permit or write after cleanup
+    let mut stragglers = vec![];
+
     for dmi in &job.files {
-        let file = sha_to_iconfile(&job, &dmi.filename, status_to_sha(&job, &dmi.status))?;
+        if budget.exceeded() {
+            break;
+        }
+
+        // One file Github won't hand over shouldn't cost the rest of the job
+        let file = match downloaded.next().expect("every file is fetched") {
+            Ok(file) => file,
+            Err(e) => {
+                error!("Failed to get {}: {e:?}", dmi.filename);
+                warnings.push(format!(
+                    "**Error:** `{}` couldn't be downloaded or read, so it's left out: {e}",
+                    dmi.filename
+                ));
+                continue;
+            }
+        };
+
+        // Only the new version matters, no point nagging about duplicates the PR is deleting
+        if let Some(after) = &file.1 {
+            warnings.extend(duplicate_states(&after.icon).into_iter().map(|state| {
+                format!(
+                    "**Warning:** file `{}` has duplicate state '{state}'",
+                    dmi.filename
+                )
+            }));
+        }
+
+        let oversized = CONFIG.get().unwrap().max_icon_dimension.and_then(|max| {
+            [&file.0, &file.1]
+                .into_iter()
+                .flatten()
+                .map(|file| (file.icon.metadata.width, file.icon.metadata.height))
+                .find(|&(width, height)| width > max || height > max)
+                .map(|dimensions| (dimensions, max))
+        });
 
-        let states = render(&job, file)?;
+        let file_budget = FileBudget::new(&budget, &retries);
+        let (status, rows) = match CONFIG.get().unwrap().icon_render_timeout_secs {
+            Some(secs) => render_with_timeout(
+                &job,
+                file,
+                file_budget,
+                Duration::from_secs(secs),
+                &mut stragglers,
+            )?,
+            None => render(&job, file, &file_budget)?,
+        };
 
-        map.insert(dmi.filename.as_str(), states);
+        if let Some(((width, height), max)) = oversized {
+            warnings.push(format!(
+                "**Note:** `{}` is {width}x{height}, over the {max}px limit for embedding, so its states are linked instead",
+                dmi.filename
+            ));
+        }
+        state_count += rows.len();
+
+        rendered.push(RenderedFile {
+            filename: dmi.filename.as_str(),
+            status,
+            rows,
+            link_only: oversized.is_some(),
+        });
+    }
+
+    for thread in stragglers {
+        let _ = thread.join();
     }
 
-    map.build()
+    // Retried here rather than where they failed, so no thread of the rendering pool sits out a backoff
+    let mut failed = HashSet::new();
+    for (path, written) in retries.retry(CONFIG.get().unwrap().image_write_retries) {
+        match written {
+            Ok(size) => budget.add(size),
+            Err(_) => {
+                failed.insert(path);
+            }
+        }
+    }
+    for row in rendered.iter_mut().flat_map(|file| &mut file.rows) {
+        row.settle_retries(&failed);
+    }
+
+    meter.add_detail(format!("{state_count} state changes"));
+
+    for (filename, states) in format_rows(rendered, CONFIG.get().unwrap().max_inline_images) {
+        map.insert(filename, states);
+    }
+
+    let conf = CONFIG.get().unwrap();
+    let mut outputs = map.build(conf.collapse_states_over)?;
+    let rendered_any = !outputs.is_empty();
+
+    // Otherwise the check is left with nothing but the notes below, or a bare "no changes" that hides the thresholds
+    if !rendered_any {
+        let reasons = nothing_rendered_reasons(budget.exceeded(), !warnings.is_empty());
+        if !reasons.is_empty() {
+            outputs.push(Output {
+                title: "No visual changes",
+                summary: format!("{}\n\n{}", conf.no_changes_message, reasons.join("\n")),
+                text: "".to_owned(),
+            });
+        }
+    }
+
+    let warnings_note = (!warnings.is_empty()).then(|| format!("\n\n{}", warnings.join("\n\n")));
+
+    // Nothing to caveat if nothing changed
+    let live_note = live_head.filter(|_| rendered_any).map(|(branch, sha)| {
+        format!(
+            "\n\nCompared against `{branch}` as of {sha}, not the PR's base, so this is the net change against what's live."
+        )
+    });
+
+    for note in budget
+        .note()
+        .into_iter()
+        .chain(warnings_note)
+        .chain(live_note)
+        .chain(rendered_any.then(|| budget.effort_note(started.elapsed())))
+        .chain(rendered_any.then(toolchain::footer))
+    {
+        match outputs.last_mut() {
+            Some(output) => output.text.push_str(&note),
+            None => outputs.push(Output {
+                title: "Icon difference rendering",
+                summary: note,
+                text: "".to_owned(),
+            }),
+        }
+    }
+
+    if job.files_truncated {
+        warn_truncated_files(&mut outputs, "Icon difference rendering");
+    }
+
+    Ok(outputs)
+}
+
+/// Where this job's images go under ./images, see `image_directory` in the config
+fn job_prefix(job: &Job) -> String {
+    job.image_directory(&CONFIG.get().unwrap().image_directory)
+}
+
+/// The job's output budget and failed writes, plus whether the file being rendered timed out
+#[derive(Debug)]
+struct FileBudget {
+    job: Arc<OutputBudget>,
+    retries: Arc<RetryQueue>,
+    timed_out: AtomicBool,
+}
+
+impl FileBudget {
+    fn new(job: &Arc<OutputBudget>, retries: &Arc<RetryQueue>) -> Self {
+        Self {
+            job: job.clone(),
+            retries: retries.clone(),
+            timed_out: AtomicBool::new(false),
+        }
+    }
+
+    fn exceeded(&self) -> bool {
+        self.job.exceeded()
+    }
+
+    fn add(&self, bytes: u64) {
+        self.job.add(bytes)
+    }
+
+    fn time_out(&self) {
+        self.timed_out.store(true, Ordering::Relaxed);
+    }
+
+    fn timed_out(&self) -> bool {
+        self.timed_out.load(Ordering::Relaxed)
+    }
+}
+
+/// Renders on a thread of its own, so a file that's still going after `timeout` can be reported as timed out while the rest carry on.
+/// A timed out render stops at the next state and writes nothing more, its thread goes in `stragglers` for the job to wait on.
+fn render_with_timeout(
+    job: &Job,
+    diff: (Option<IconFileWithName>, Option<IconFileWithName>),
+    budget: FileBudget,
+    timeout: Duration,
+    stragglers: &mut Vec<JoinHandle<()>>,
+) -> Result<(&'static str, Vec<Row>)> {
+    let filename = diff
+        .1
+        .as_ref()
+        .or(diff.0.as_ref())
+        .map(|file| file.full_name.clone())
+        .unwrap_or_default();
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let file_budget = Arc::new(budget);
+    let (job, thread_budget) = (job.clone(), file_budget.clone());
+    let thread = std::thread::spawn(move || {
+        let _ = sender.send(render(&job, diff, &thread_budget));
+    });
+
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(RecvTimeoutError::Timeout) => {
+            file_budget.time_out();
+            stragglers.push(thread);
+            error!(
+                "Rendering {filename} timed out after {}s, skipping it",
+                timeout.as_secs()
+            );
+            Ok((
+                "TIMED OUT",
+                vec![Row::note(format!(
+                    "Rendering took longer than {}s, skipped",
+                    timeout.as_secs()
+                ))],
+            ))
+        }
+        Err(RecvTimeoutError::Disconnected) => Err(eyre::anyhow!("Rendering {filename} panicked")),
+    }
 }
 
 #[tracing::instrument]
 fn render(
     job: &Job,
     diff: (Option<IconFileWithName>, Option<IconFileWithName>),
-) -> Result<(&'static str, Vec<String>)> {
+    budget: &FileBudget,
+) -> Result<(&'static str, Vec<Row>)> {
     // TODO: Alphabetize
     // TODO: Test more edge cases
     match diff {
-        (None, None) => Ok((
-            "UNCHANGED",
-            vec![format!(
-                include_str!(concat!(
-                    env!("CARGO_MANIFEST_DIR"),
-                    "/templates/diff_line.txt"
-                )),
-                state_name = "",
-                old = "",
-                new = "",
-                change_text = "UNCHANGED",
-            )],
-        )),
+        (None, None) => Ok(("UNCHANGED", vec![Row::note("UNCHANGED")])),
         (None, Some(after)) => {
-            let urls = full_render(job, &after).context("Failed to render new icon file")?;
+            let urls =
+                full_render(job, &after, budget).context("Failed to render new icon file")?;
 
             Ok((
                 "ADDED",
-                urls.par_iter()
-                    .map(|(state_name, url)| {
-                        format!(
-                            include_str!(concat!(
-                                env!("CARGO_MANIFEST_DIR"),
-                                "/templates/diff_line.txt"
-                            )),
-                            state_name = state_name,
-                            old = "",
-                            new = url,
-                            change_text = "Created",
-                        )
+                urls.into_iter()
+                    .map(|(state_name, url)| Row {
+                        state_name: state_name.to_string(),
+                        old: None,
+                        new: Some(url),
+                        diff: vec![],
+                        change_text: "Created".to_owned(),
                     })
                     .collect(),
             ))
         }
         (Some(before), None) => {
-            let urls = full_render(job, &before).context("Failed to render deleted icon file")?;
+            let urls =
+                full_render(job, &before, budget).context("Failed to render deleted icon file")?;
 
             Ok((
                 "DELETED",
-                urls.par_iter()
-                    .map(|(state_name, url)| {
-                        format!(
-                            include_str!(concat!(
-                                env!("CARGO_MANIFEST_DIR"),
-                                "/templates/diff_line.txt"
-                            )),
-                            state_name = state_name,
-                            old = url,
-                            new = "",
-                            change_text = "Deleted",
-                        )
+                urls.into_iter()
+                    .map(|(state_name, url)| Row {
+                        state_name: state_name.to_string(),
+                        old: Some(url),
+                        new: None,
+                        diff: vec![],
+                        change_text: "Deleted".to_owned(),
                     })
                     .collect(),
             ))
         }
         (Some(before), Some(after)) => {
-            let before_states: HashSet<&StateIndex, ahash::RandomState> =
-                before.icon.metadata.state_names.keys().collect();
-            let after_states: HashSet<&StateIndex, ahash::RandomState> =
-                after.icon.metadata.state_names.keys().collect();
+            let before_keyed = keyed_states(&before.icon);
+            let after_keyed = keyed_states(&after.icon);
+            let before_states: HashSet<&StateKey, ahash::RandomState> =
+                before_keyed.keys().collect();
+            let after_states: HashSet<&StateKey, ahash::RandomState> = after_keyed.keys().collect();
 
-            let prefix = format!("{}/{}", job.installation, job.pull_request);
+            let prefix = job_prefix(job);
 
             let before_renderer = IconRenderer::new(&before.icon);
             let after_renderer = IconRenderer::new(&after.icon);
 
-            let mut table: Vec<String> = before_states
+            let mut table: Vec<Row> = before_states
                 .par_symmetric_difference(&after_states)
                 .map(|state| {
                     if before_states.contains(state) {
-                        let (name, url) = render_state(
+                        let url = render_state(
                             &prefix,
                             &before,
-                            before.icon.metadata.get_icon_state(state).unwrap(),
+                            before_keyed[*state],
                             &before_renderer,
+                            budget,
                         )
                         .with_context(|| format!("Failed to render before-state {state}"))?;
-                        Ok(format!(
-                            include_str!(concat!(
-                                env!("CARGO_MANIFEST_DIR"),
-                                "/templates/diff_line.txt"
-                            )),
-                            state_name = name,
-                            old = url,
-                            new = "",
-                            change_text = "Deleted",
-                        ))
+                        Ok(Row {
+                            state_name: state.to_string(),
+                            old: Some(url),
+                            new: None,
+                            diff: vec![],
+                            change_text: with_delays("Deleted", before_keyed[*state], None),
+                        })
                     } else {
-                        let (name, url) = render_state(
+                        let url = render_state(
                             &prefix,
                             &after,
-                            after.icon.metadata.get_icon_state(state).unwrap(),
+                            after_keyed[*state],
                             &after_renderer,
+                            budget,
                         )
                         .with_context(|| format!("Failed to render after-state {state}"))?;
-                        Ok(format!(
-                            include_str!(concat!(
-                                env!("CARGO_MANIFEST_DIR"),
-                                "/templates/diff_line.txt"
-                            )),
-                            state_name = name,
-                            old = "",
-                            new = url,
-                            change_text = "Created",
-                        ))
+                        Ok(Row {
+                            state_name: state.to_string(),
+                            old: None,
+                            new: Some(url),
+                            diff: vec![],
+                            change_text: with_delays("Created", after_keyed[*state], None),
+                        })
                     }
                 })
-                .filter_map(|r: Result<String, eyre::Error>| {
+                .filter_map(|r: Result<Row, eyre::Error>| {
                     r.map_err(|e| {
                         error!("Error encountered during parse: {}", e);
                     })
@@ -164,58 +698,81 @@ fn render(
                 before_states
                     .par_intersection(&after_states)
                     .map(|state| {
-                        let before_state = before.icon.metadata.get_icon_state(state).unwrap();
-                        let after_state = after.icon.metadata.get_icon_state(state).unwrap();
-
-                        let difference = {
-                            // #[cfg(debug_assertions)]
-                            // dbg!(before_state, after_state);
-                            if before_state != after_state {
-                                true
-                            } else {
-                                let before_state_render =
-                                    before_renderer.render_to_images(state)?;
-                                let after_state_render = after_renderer.render_to_images(state)?;
-                                before_state_render != after_state_render
-                            }
-                        };
+                        if budget.timed_out() {
+                            return Err(eyre::anyhow!(
+                                "Not comparing state {state}, its file timed out"
+                            ));
+                        }
 
-                        if difference {
-                            let before_state = before.icon.metadata.get_icon_state(state).unwrap();
-                            let after_state = after.icon.metadata.get_icon_state(state).unwrap();
+                        let before_state = before_keyed[*state];
+                        let after_state = after_keyed[*state];
 
-                            let (_, before_url) =
-                                render_state(&prefix, &before, before_state, &before_renderer)
-                                    .with_context(|| {
-                                        format!("Failed to render modified before-state {state}")
-                                    })?;
-                            let (_, after_url) =
-                                render_state(&prefix, &after, after_state, &after_renderer)
+                        let before_state_render = before_renderer
+                            .render_to_images(&before_state.get_state_name_index())?;
+                        let after_state_render =
+                            after_renderer.render_to_images(&after_state.get_state_name_index())?;
+
+                        let difference = before_state != after_state
+                            || renders_differ(&before_state_render, &after_state_render);
+
+                        if difference {
+                            let before_url = render_state(
+                                &prefix,
+                                &before,
+                                before_state,
+                                &before_renderer,
+                                budget,
+                            )
+                            .with_context(|| {
+                                format!("Failed to render modified before-state {state}")
+                            })?;
+                            let after_url =
+                                render_state(&prefix, &after, after_state, &after_renderer, budget)
                                     .with_context(|| {
                                         format!("Failed to render modified before-state {state}")
                                     })?;
+                            let (frames, left_out) =
+                                frames_to_diff(&before_state_render, &after_state_render);
+                            let diff_urls = render_diff(
+                                &prefix,
+                                (&before, before_state, &before_state_render),
+                                (&after, after_state, &after_state_render),
+                                &frames,
+                                budget,
+                            )
+                            .with_context(|| format!("Failed to render diff of state {state}"))?;
+                            let mut change_text =
+                                with_delays("Modified", after_state, Some(before_state));
+                            if left_out {
+                                change_text.push_str("<br>Other frames changed too, not diffed");
+                            }
+                            if let Some(remapped) =
+                                palette_remap(&before_state_render, &after_state_render)
+                            {
+                                change_text.push_str(&format!(
+                                    "<br>Palette changed: {remapped} color{} remapped",
+                                    if remapped == 1 { "" } else { "s" }
+                                ));
+                            }
 
-                            Ok(format!(
-                                include_str!(concat!(
-                                    env!("CARGO_MANIFEST_DIR"),
-                                    "/templates/diff_line.txt"
-                                )),
-                                state_name = state,
-                                old = before_url,
-                                new = after_url,
-                                change_text = "Modified",
-                            ))
+                            Ok(Some(Row {
+                                state_name: state.to_string(),
+                                old: Some(before_url),
+                                new: Some(after_url),
+                                diff: diff_urls,
+                                change_text,
+                            }))
                         } else {
-                            Ok("".to_string())
+                            Ok(None)
                         }
                     })
-                    .filter_map(|r: Result<String, eyre::Error>| {
+                    .filter_map(|r: Result<Option<Row>, eyre::Error>| {
                         r.map_err(|e| {
                             error!("Error encountered during parse: {}", e);
                         })
                         .ok()
-                    })
-                    .filter(|s| !s.is_empty()),
+                        .flatten()
+                    }),
             );
 
             Ok(("MODIFIED", table))
@@ -223,25 +780,285 @@ fn render(
     }
 }
 
+/// Per-frame delays in ticks, None for states that aren't animated
+fn frame_delays(state: &State) -> Option<Vec<f32>> {
+    let count = state.frames.count();
+    (count > 1).then(|| (0..count).map(|idx| state.frames.delay(idx)).collect())
+}
+
+/// Appends each frame's delay to the status, a timing change doesn't show up comparing single frames.
+/// Against `before`, frames whose delay changed (or that were added or removed) are bolded.
+fn with_delays(status: &str, state: &State, before: Option<&State>) -> String {
+    let after = frame_delays(state);
+    let before = before.and_then(frame_delays);
+    if after.is_none() && before.is_none() {
+        return status.to_owned();
+    }
+    let after = after.unwrap_or_default();
+
+    let frames = match &before {
+        None => after
+            .iter()
+            .map(|delay| delay.to_string())
+            .collect::<Vec<_>>(),
+        Some(before) => (0..after.len().max(before.len()))
+            .map(|idx| match (before.get(idx), after.get(idx)) {
+                (Some(old), Some(new)) if old == new => new.to_string(),
+                (Some(old), Some(new)) => format!("**{old}→{new}**"),
+                (None, Some(new)) => format!("**+{new}**"),
+                (Some(old), None) => format!("**~~{old}~~**"),
+                (None, None) => unreachable!(),
+            })
+            .collect(),
+    };
+    format!("{status}<br>Delays: {}", frames.join(", "))
+}
+
+fn state_hash(target: &IconFileWithName, state: &State) -> u64 {
+    let mut hasher = ahash::AHasher::default();
+    target.sha.hash(&mut hasher);
+    target.full_name.hash(&mut hasher);
+    target.hash.hash(&mut hasher);
+    state.duplicate_index.hash(&mut hasher);
+    state.name.hash(&mut hasher);
+    state.movement.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn changed_pixels(before: &Image, after: &Image) -> usize {
+    (0..after.height as usize)
+        .flat_map(|y| (0..after.width as usize).map(move |x| (y, x)))
+        .filter(|&(y, x)| (0..4).any(|c| before.data[[y, x, c]] != after.data[[y, x, c]]))
+        .count()
+}
+
+/// Whether two renders of a state differ by enough pixels to count, per `min_changed_pixels` and `min_changed_percent`.
+/// A different frame count or size always does.
+fn renders_differ(before_images: &[Image], after_images: &[Image]) -> bool {
+    if before_images.len() != after_images.len()
+        || before_images
+            .iter()
+            .zip(after_images)
+            .any(|(before, after)| before.width != after.width || before.height != after.height)
+    {
+        return true;
+    }
+
+    let changed: usize = before_images
+        .iter()
+        .zip(after_images)
+        .map(|(before, after)| changed_pixels(before, after))
+        .sum();
+    if changed == 0 {
+        return false;
+    }
+
+    let config = CONFIG.get().unwrap();
+    let total: usize = after_images
+        .iter()
+        .map(|image| image.width as usize * image.height as usize)
+        .sum();
+    changed >= config.min_changed_pixels
+        && changed as f32 * 100.0 >= config.min_changed_percent * total as f32
+}
+
+/// How many colors changed, if the only change is a recolor: every pixel of one color became the same other color,
+/// across every frame. None if anything else changed, or nothing did.
+fn palette_remap(before_images: &[Image], after_images: &[Image]) -> Option<usize> {
+    if before_images.len() != after_images.len() {
+        return None;
+    }
+    let mut remap: HashMap<[u8; 4], [u8; 4]> = HashMap::new();
+    for (before, after) in before_images.iter().zip(after_images) {
+        if before.width != after.width || before.height != after.height {
+            return None;
+        }
+        for y in 0..after.height as usize {
+            for x in 0..after.width as usize {
+                let from = [0, 1, 2, 3].map(|c| before.data[[y, x, c]]);
+                let to = [0, 1, 2, 3].map(|c| after.data[[y, x, c]]);
+                if *remap.entry(from).or_insert(to) != to {
+                    return None;
+                }
+            }
+        }
+    }
+    let remapped = remap.iter().filter(|(from, to)| from != to).count();
+    (remapped > 0).then_some(remapped)
+}
+
+/// Highlights every pixel that differs between two renders of a state, fading out the rest.
+/// Returns None if the renders can't be lined up, e.g. when the icon size changed.
+fn diff_image(before: &Image, after: &Image) -> Option<Image> {
+    if before.width != after.width || before.height != after.height {
+        return None;
+    }
+
+    let mut diff = Image::new_rgba(after.width, after.height);
+    for y in 0..after.height as usize {
+        for x in 0..after.width as usize {
+            let changed = (0..4).any(|c| before.data[[y, x, c]] != after.data[[y, x, c]]);
+            let pixel = if changed {
+                [255, 0, 255, 255]
+            } else {
+                [
+                    after.data[[y, x, 0]],
+                    after.data[[y, x, 1]],
+                    after.data[[y, x, 2]],
+                    after.data[[y, x, 3]] / 3,
+                ]
+            };
+            for (c, value) in pixel.into_iter().enumerate() {
+                diff.data[[y, x, c]] = value;
+            }
+        }
+    }
+    Some(diff)
+}
+
+/// Frames of a modified state to diff, per `diff_frames`. The first always is, later ones only if they changed.
+/// Also returns whether any changed frame was left out.
+fn frames_to_diff(before_images: &[Image], after_images: &[Image]) -> (Vec<usize>, bool) {
+    let shared = before_images.len().min(after_images.len());
+    let changed = |frame: &usize| before_images[*frame] != after_images[*frame];
+    let candidates: Vec<usize> = match CONFIG.get().unwrap().diff_frames {
+        DiffFrames::First => vec![],
+        DiffFrames::FirstAndLast => (shared > 1).then(|| shared - 1).into_iter().collect(),
+        DiffFrames::All => (1..shared).collect(),
+    };
+    let mut frames: Vec<usize> = (shared > 0).then_some(0).into_iter().collect();
+    frames.extend(candidates.into_iter().filter(changed));
+
+    let left_out = before_images.len() != after_images.len()
+        || (1..shared)
+            .filter(changed)
+            .any(|frame| !frames.contains(&frame));
+    (frames, left_out)
+}
+
+/// Renders the pixel difference of the selected frames of a modified state
+fn render_diff<S: AsRef<str> + std::fmt::Debug>(
+    prefix: S,
+    (before, before_state, before_images): (&IconFileWithName, &State, &[Image]),
+    (after, after_state, after_images): (&IconFileWithName, &State, &[Image]),
+    frames: &[usize],
+    budget: &FileBudget,
+) -> Result<Vec<Upload>> {
+    frames
+        .iter()
+        .filter_map(|&frame| {
+            render_frame_diff(
+                prefix.as_ref(),
+                (before, before_state, &before_images[frame]),
+                (after, after_state, &after_images[frame]),
+                frame,
+                budget,
+            )
+            .transpose()
+        })
+        .collect()
+}
+
+fn render_frame_diff(
+    prefix: &str,
+    (before, before_state, before_image): (&IconFileWithName, &State, &Image),
+    (after, after_state, after_image): (&IconFileWithName, &State, &Image),
+    frame: usize,
+    budget: &FileBudget,
+) -> Result<Option<Upload>> {
+    if budget.timed_out() {
+        return Err(eyre::anyhow!(
+            "Not diffing state {}, its file timed out",
+            after_state.name
+        ));
+    }
+
+    let Some(mut diff) = diff_image(before_image, after_image) else {
+        return Ok(None);
+    };
+
+    if let (Some(text), Some(pixels)) = (
+        CONFIG.get().unwrap().watermark_text.as_deref(),
+        diff.data.as_slice_mut(),
+    ) {
+        draw_watermark(diff.width as usize, diff.height as usize, pixels, text);
+    }
+
+    let directory = Path::new(".").join("images").join(prefix);
+    std::fs::create_dir_all(&directory)
+        .with_context(|| format!("Failed to create directory {directory:?}"))?;
+
+    // The first frame keeps the name it always had
+    let suffix = match frame {
+        0 => String::new(),
+        frame => format!("-{frame}"),
+    };
+    let filename = format!(
+        "{}-{}-diff{suffix}",
+        state_hash(before, before_state),
+        state_hash(after, after_state)
+    );
+    let path = directory.join(&filename).with_extension("png");
+
+    let conf = CONFIG.get().unwrap();
+    let png = encode_rgba(
+        diff.width,
+        diff.height,
+        diff.data
+            .as_slice()
+            .ok_or_else(|| eyre::anyhow!("Diff image data isn't contiguous"))?,
+        conf.png_compression,
+    )
+    .with_context(|| format!("Failed to encode diff of state {}", after_state.name))?;
+
+    let url = format!(
+        "{}/{}/{}.png",
+        CONFIG.get().unwrap().web.file_hosting_url,
+        prefix,
+        filename,
+    );
+    let state_name = after_state.name.clone();
+    let written = budget.retries.write(&path, move |path| {
+        std::fs::write(path, &png)
+            .with_context(|| format!("Failed to write diff of state {state_name} to {path:?}"))?;
+        Ok(png.len() as u64)
+    });
+    Ok(Some(match written {
+        Some(size) => {
+            budget.add(size);
+            Upload::Url(url)
+        }
+        None => Upload::Retrying { url, path },
+    }))
+}
+
 #[tracing::instrument]
 fn render_state<'a, S: AsRef<str> + std::fmt::Debug>(
     prefix: S,
     target: &IconFileWithName,
     state: &State,
     renderer: &IconRenderer<'a>,
-) -> Result<(StateIndex, String)> {
+    budget: &FileBudget,
+) -> Result<Upload> {
+    if budget.timed_out() {
+        return Err(eyre::anyhow!(
+            "Not rendering state {}, its file timed out",
+            state.name
+        ));
+    }
+    if budget.exceeded() {
+        return Err(eyre::anyhow!(
+            "Not rendering state {}, the job's output size limit was reached",
+            state.name
+        ));
+    }
+
     let directory = Path::new(".").join("images").join(prefix.as_ref());
     // Always remember to mkdir -p your paths
     std::fs::create_dir_all(&directory)
         .with_context(|| format!("Failed to create directory {directory:?}"))?;
 
-    let mut hasher = ahash::AHasher::default();
-    target.sha.hash(&mut hasher);
-    target.full_name.hash(&mut hasher);
-    target.hash.hash(&mut hasher);
-    state.duplicate_index.hash(&mut hasher);
-    state.name.hash(&mut hasher);
-    let filename = hasher.finish().to_string();
+    let filename = state_hash(target, state).to_string();
 
     // TODO: Calculate file extension separately so that we can Error here if we overwrite a file
     let mut path = directory.join(&filename);
@@ -256,11 +1073,27 @@ fn render_state<'a, S: AsRef<str> + std::fmt::Debug>(
     };
     path.set_extension(extension);
 
-    let mut buffer = BufWriter::new(File::create(&path)?);
+    // Rendered to memory first, so a failed write can be retried without rendering again
+    let mut buffer = Vec::new();
 
     render_guard
         .render(&mut buffer)
-        .with_context(|| format!("Failed to render state {} to file {:?}", state.name, &path))?;
+        .with_context(|| format!("Failed to render state {}", state.name))?;
+
+    // dmm_tools always encodes with the default level, and there's nothing to gain recompressing gifs
+    let compression = CONFIG.get().unwrap().png_compression;
+    if extension == "png" && compression != PngCompression::Balanced {
+        buffer = recompress(&buffer, compression)
+            .with_context(|| format!("Failed to recompress state {}", state.name))?;
+    }
+
+    // Timed out while rendering, the job may already be done with this directory
+    if budget.timed_out() {
+        return Err(eyre::anyhow!(
+            "Not writing state {}, its file timed out",
+            state.name
+        ));
+    }
 
     let url = format!(
         "{}/{}/{}.{}",
@@ -269,31 +1102,41 @@ fn render_state<'a, S: AsRef<str> + std::fmt::Debug>(
         filename,
         extension,
     );
-
-    buffer.flush().with_context(|| {
-        format!("Failed to flush BufWriter to disk for state {state:?} at {path:?}")
-    })?;
-
-    Ok((state.get_state_name_index(), url))
+    let state_name = state.name.clone();
+    let written = budget.retries.write(&path, move |path| {
+        std::fs::write(path, &buffer)
+            .with_context(|| format!("Failed to write state {state_name} to file {path:?}"))?;
+        Ok(buffer.len() as u64)
+    });
+    Ok(match written {
+        Some(size) => {
+            budget.add(size);
+            Upload::Url(url)
+        }
+        None => Upload::Retrying { url, path },
+    })
 }
 
 #[tracing::instrument]
-fn full_render(job: &Job, target: &IconFileWithName) -> Result<Vec<(StateIndex, String)>> {
+fn full_render(
+    job: &Job,
+    target: &IconFileWithName,
+    budget: &FileBudget,
+) -> Result<Vec<(StateKey, Upload)>> {
     let icon = &target.icon;
 
     let renderer = IconRenderer::new(icon);
 
-    let prefix = format!("{}/{}", job.installation, job.pull_request);
+    let prefix = job_prefix(job);
 
-    let vec: Vec<(StateIndex, String)> = icon
-        .metadata
-        .states
-        .par_iter()
-        .map(|state| {
-            render_state(&prefix, target, state, &renderer)
-                .with_context(|| format!("Failed to render state {}", state.name))
+    let vec: Vec<(StateKey, Upload)> = keyed_states(icon)
+        .into_par_iter()
+        .map(|(key, state)| {
+            let url = render_state(&prefix, target, state, &renderer, budget)
+                .with_context(|| format!("Failed to render state {key}"))?;
+            Ok((key, url))
         })
-        .filter_map(|r: Result<(StateIndex, String), eyre::Error>| {
+        .filter_map(|r: Result<(StateKey, Upload), eyre::Error>| {
             r.map_err(|e| {
                 error!("Error encountered during parse: {}", e);
             })
@@ -4,7 +4,11 @@ mod runner;
 mod sha;
 mod table_builder;
 
-use diffbot_lib::{async_fs, async_mutex::Mutex, job::types::JobSender};
+use diffbot_lib::{
+    async_fs,
+    async_mutex::Mutex,
+    job::{render_limiter::RenderLimiter, types::JobSender},
+};
 use octocrab::OctocrabBuilder;
 use once_cell::sync::OnceCell;
 use serde::Deserialize;
@@ -19,6 +23,21 @@ use std::{
 static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 
 pub type DataJobSender = actix_web::web::Data<Mutex<JobSender>>;
+pub type DataCooldown = actix_web::web::Data<
+    diffbot_lib::job::cooldown::Cooldown<
+        diffbot_lib::github::github_types::PullRequestEventPayload,
+    >,
+>;
+
+/// 503s while the image store can't be written to, jobs hold off rendering until it's back
+#[actix_web::get("/healthz")]
+async fn healthz() -> actix_web::HttpResponse {
+    match diffbot_lib::upload::probe_store() {
+        Ok(()) => actix_web::HttpResponse::Ok().body("ok"),
+        Err(e) => actix_web::HttpResponse::ServiceUnavailable()
+            .body(format!("image store unavailable: {e}")),
+    }
+}
 
 #[actix_web::get("/")]
 async fn index() -> &'static str {
@@ -43,26 +62,196 @@ pub struct WebConfig {
     pub port: u16,
     pub file_hosting_url: String,
     pub limits: Option<WebLimitsConfig>,
+    #[serde(default = "default_serve_images")]
+    pub serve_images: bool,
+    #[serde(default = "default_images_route")]
+    pub images_route: String,
+}
+
+fn default_serve_images() -> bool {
+    true
+}
+
+fn default_images_route() -> String {
+    "/images".to_string()
+}
+
+/// What the PR's version of an icon gets diffed against
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CompareAgainst {
+    /// The PR's base commit
+    #[default]
+    Base,
+    /// Whatever's currently on the default branch, shows the net change against what's live even if the PR base is stale
+    DefaultBranch,
+}
+
+/// Which frames of a modified animated state get a diff image
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DiffFrames {
+    /// Changes past the first frame are only mentioned
+    #[default]
+    First,
+    FirstAndLast,
+    All,
+}
+
+/// Every limit on how much runs at once, laid out like mapdiffbot2's so both bots are configured the same way.
+/// Jobs are taken off the queue one at a time, so there's no worker count or per-repo lock to set.
+#[derive(Debug, Deserialize)]
+pub struct ConcurrencyConfig {
+    /// Jobs rendering at once across every bot on the host
+    pub render_permits: Option<usize>,
+    #[serde(default = "diffbot_lib::job::render_limiter::default_directory")]
+    pub render_permits_dir: PathBuf,
+    /// Files downloaded from Github at once
+    #[serde(default = "default_downloads")]
+    pub downloads: usize,
+}
+
+impl Default for ConcurrencyConfig {
+    fn default() -> Self {
+        Self {
+            render_permits: None,
+            render_permits_dir: diffbot_lib::job::render_limiter::default_directory(),
+            downloads: default_downloads(),
+        }
+    }
+}
+
+impl ConcurrencyConfig {
+    /// Icons render on every core, so more jobs rendering at once than there are cores just thrashes
+    fn validate(&self) -> eyre::Result<()> {
+        if self.render_permits == Some(0) {
+            eyre::bail!("concurrency.render_permits can't be 0, leave it out for no limit");
+        }
+        if self.downloads == 0 {
+            eyre::bail!("concurrency.downloads can't be 0");
+        }
+        let cores = std::thread::available_parallelism().map_or(1, usize::from);
+        if let Some(permits) = self.render_permits.filter(|permits| *permits > cores) {
+            eyre::bail!(
+                "concurrency.render_permits is {permits} but this host only has {cores} cores, lower it"
+            );
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub github: GithubConfig,
     pub web: WebConfig,
+    #[serde(default)]
+    pub concurrency: ConcurrencyConfig,
     #[serde(default = "std::collections::HashSet::new")]
     pub blacklist: std::collections::HashSet<u64>,
     #[serde(default = "String::new")]
     pub blacklist_contact: String,
     #[serde(default = "default_log_level")]
     pub logging: String,
+    #[serde(alias = "webhook_secret")]
     pub secret: Option<String>,
+    #[serde(default = "default_state_label")]
+    pub default_state_label: String,
+    pub max_upload_bytes_per_job: Option<u64>,
+    pub watermark_text: Option<String>,
+    #[serde(default)]
+    pub priority_labels: std::collections::HashSet<String>,
+    #[serde(default)]
+    pub priority_repos: std::collections::HashSet<u64>,
+    #[serde(default)]
+    pub render_cooldown_secs: u64,
+    #[serde(default = "default_image_directory")]
+    pub image_directory: String,
+    #[serde(default)]
+    pub check_debounce_secs: u64,
+    #[serde(default = "default_no_changes_message")]
+    pub no_changes_message: String,
+    #[serde(default = "default_image_write_retries")]
+    pub image_write_retries: u32,
+    #[serde(default)]
+    pub png_compression: diffbot_lib::png_encoding::PngCompression,
+    pub max_inline_images: Option<usize>,
+    #[serde(default = "default_collapse_states_over")]
+    pub collapse_states_over: usize,
+    pub max_icon_dimension: Option<u32>,
+    #[serde(default)]
+    pub skip_title_patterns: Vec<String>,
+    #[serde(default = "default_empty_pull_check")]
+    pub empty_pull_check: bool,
+    #[serde(default)]
+    pub compare_against: CompareAgainst,
+    pub details_url: Option<String>,
+    #[serde(default)]
+    pub change_labels: Vec<String>,
+    pub failure_webhook_url: Option<String>,
+    pub support_link: Option<String>,
+    #[serde(default = "default_image_store_wait_secs")]
+    pub image_store_wait_secs: u64,
+    pub check_name_suffix: Option<String>,
+    pub icon_render_timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub diff_frames: DiffFrames,
+    #[serde(default = "default_download_timeout_secs")]
+    pub download_timeout_secs: u64,
+    #[serde(default = "default_download_retries")]
+    pub download_retries: u32,
+    #[serde(default)]
+    pub min_changed_pixels: usize,
+    #[serde(default)]
+    pub min_changed_percent: f32,
+}
+
+fn default_image_directory() -> String {
+    "{installation}/{repo_id}/{pull_request}/{check_run}".to_string()
+}
+
+fn default_empty_pull_check() -> bool {
+    true
+}
+
+fn default_download_timeout_secs() -> u64 {
+    60
+}
+
+fn default_download_retries() -> u32 {
+    2
+}
+
+fn default_collapse_states_over() -> usize {
+    10
+}
+
+fn default_downloads() -> usize {
+    4
+}
+
+fn default_image_store_wait_secs() -> u64 {
+    300
+}
+
+fn default_image_write_retries() -> u32 {
+    3
+}
+
+fn default_no_changes_message() -> String {
+    "No visual changes detected, the changed files render identically.".to_string()
 }
 
 fn default_log_level() -> String {
     "info".to_string()
 }
 
+fn default_state_label() -> String {
+    "{DEFAULT}".to_string()
+}
+
 static CONFIG: OnceCell<Config> = OnceCell::new();
+/// Only set if `concurrency.render_permits` is configured
+static RENDER_LIMITER: OnceCell<RenderLimiter> = OnceCell::new();
 // static FLAME_LAYER_GUARD: OnceCell<tracing_flame::FlushGuard<std::io::BufWriter<File>>> =
 // OnceCell::new();
 
@@ -70,7 +259,8 @@ fn init_config(path: &Path) -> eyre::Result<&'static Config> {
     let mut config_str = String::new();
     File::open(path)?.read_to_string(&mut config_str)?;
 
-    let config = toml::from_str(&config_str)?;
+    let config: Config = toml::from_str(&config_str)?;
+    config.concurrency.validate()?;
 
     CONFIG.set(config).expect("Failed to set config");
     Ok(CONFIG.get().unwrap())
@@ -113,11 +303,17 @@ async fn main() -> eyre::Result<()> {
     // init_global_subscriber();
 
     let config_path = Path::new(".").join("config.toml");
-    let config =
-        init_config(&config_path).unwrap_or_else(|_| panic!("Failed to read {config_path:?}"));
+    let config = init_config(&config_path)
+        .unwrap_or_else(|e| panic!("Failed to read {config_path:?}: {e:?}"));
 
     diffbot_lib::logger::init_logger(&config.logging).expect("Log init failed!");
 
+    if let Some(permits) = config.concurrency.render_permits {
+        let limiter = RenderLimiter::new(&config.concurrency.render_permits_dir, permits)
+            .expect("Couldn't create the render permit directory");
+        let _ = RENDER_LIMITER.set(limiter);
+    }
+
     let key = read_key(&PathBuf::from(&config.github.private_key_path));
 
     octocrab::initialise(OctocrabBuilder::new().app(
@@ -126,15 +322,24 @@ async fn main() -> eyre::Result<()> {
     ))
     .expect("Octocrab failed to initialise");
 
+    diffbot_lib::github::github_api::verify_app_credentials(config.github.app_id).await?;
+
     async_fs::create_dir_all("./images").await.unwrap();
 
-    let (job_sender, job_receiver) = yaque::channel(JOB_JOURNAL_LOCATION)
+    let (job_sender, job_receiver) = diffbot_lib::job::types::job_channel(JOB_JOURNAL_LOCATION)
         .expect("Couldn't open an on-disk queue, check permissions or drive space?");
 
     actix_web::rt::spawn(runner::handle_jobs("IconDiffBot2", job_receiver));
 
     let job_sender: DataJobSender = actix_web::web::Data::new(Mutex::new(job_sender));
 
+    let cooldown: DataCooldown = actix_web::web::Data::new(
+        diffbot_lib::job::cooldown::Cooldown::new(std::time::Duration::from_secs(
+            config.render_cooldown_secs,
+        ))
+        .with_debounce(std::time::Duration::from_secs(config.check_debounce_secs)),
+    );
+
     actix_web::HttpServer::new(move || {
         use actix_web::web::{FormConfig, PayloadConfig};
         //absolutely rancid
@@ -151,9 +356,18 @@ async fn main() -> eyre::Result<()> {
             .app_data(form_config)
             .app_data(string_config)
             .app_data(job_sender.clone())
+            .app_data(cooldown.clone())
             .service(index)
+            .service(healthz)
             .service(github_processor::process_github_payload_actix)
-            .service(actix_files::Files::new("/images", "./images"))
+            .configure(|cfg| {
+                if config.web.serve_images {
+                    cfg.service(actix_files::Files::new(
+                        &config.web.images_route,
+                        "./images",
+                    ));
+                }
+            })
     })
     .bind((config.web.address.as_ref(), config.web.port))?
     .run()
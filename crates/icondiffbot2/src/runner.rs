@@ -1,32 +1,35 @@
 use std::time::Duration;
 
 use super::job_processor::do_job;
-use diffbot_lib::job::types::Job;
+use diffbot_lib::job::{
+    in_flight::InFlight,
+    runner::{fail_job, FailureCategory},
+    types::{Job, JobReceiver},
+};
 
 use diffbot_lib::log::{error, info};
 
-pub async fn handle_jobs<S: AsRef<str>>(name: S, mut job_receiver: yaque::Receiver) {
-    loop {
-        match job_receiver.recv().await {
-            Ok(jobguard) => {
-                info!("Job received from queue");
-                let job = serde_json::from_slice(&jobguard);
-                match job {
-                    Ok(job) => job_handler(name.as_ref(), job).await,
-                    Err(err) => error!("Failed to parse job from queue: {}", err),
-                }
-                if let Err(err) = jobguard.commit() {
-                    error!("Failed to commit change to queue: {}", err)
-                };
+pub async fn handle_jobs<S: AsRef<str>>(name: S, job_receiver: JobReceiver) {
+    let name = name.as_ref();
+    job_receiver
+        .run(|job| async move {
+            info!("Job received from queue");
+            let job = serde_json::from_slice(&job);
+            match job {
+                Ok(job) => job_handler(name, job).await,
+                Err(err) => error!("Failed to parse job from queue: {}", err),
             }
-            Err(err) => error!("{}", err),
-        }
-    }
+        })
+        .await
 }
 
 async fn job_handler(name: &str, job: Job) {
-    let (repo, pull_request, check_run) =
-        (job.repo.clone(), job.pull_request, job.check_run.clone());
+    let (repo, pull_request, check_run, installation) = (
+        job.repo.clone(),
+        job.pull_request,
+        job.check_run.clone(),
+        job.installation,
+    );
     info!(
         "[{}#{}] [{}] Starting",
         repo.full_name(),
@@ -34,11 +37,33 @@ async fn job_handler(name: &str, job: Job) {
         check_run.id()
     );
 
-    let _ = check_run.mark_started().await;
+    if !diffbot_lib::job::runner::check_repo_available(&job).await {
+        return;
+    }
+
+    let conf = crate::CONFIG.get().unwrap();
+    let notify = (
+        conf.failure_webhook_url.as_deref(),
+        conf.support_link.as_deref(),
+    );
+
+    let store_wait = Duration::from_secs(conf.image_store_wait_secs);
+    if !diffbot_lib::job::runner::wait_for_image_store(&job, store_wait, notify).await {
+        return;
+    }
+
+    let _ = check_run.mark_started(job.queue_wait()).await;
+
+    if let Some(template) = &crate::CONFIG.get().unwrap().details_url {
+        let _ = check_run.set_details_url(&job.details_url(template)).await;
+    }
+
+    // Held until the job's output is posted, a closed PR's images are only cleaned up after that
+    let _in_flight = InFlight::start((repo.id, pull_request));
 
     let output = actix_web::rt::time::timeout(
         Duration::from_secs(3600),
-        actix_web::rt::task::spawn_blocking(move || do_job(job)),
+        actix_web::rt::task::spawn_blocking(move || do_job(job, crate::RENDER_LIMITER.get())),
     )
     .await;
 
@@ -52,7 +77,14 @@ async fn job_handler(name: &str, job: Job) {
     let output = {
         if output.is_err() {
             error!("Job timed out!");
-            let _ = check_run.mark_failed("Job timed out after 1 hours!").await;
+            fail_job(
+                &check_run,
+                (&repo, pull_request),
+                FailureCategory::Timeout,
+                "Job timed out after 1 hours!",
+                notify,
+            )
+            .await;
             return;
         }
         output.unwrap()
@@ -67,7 +99,14 @@ async fn job_handler(name: &str, job: Job) {
             Err(e) => e.to_string(),
         };
         error!("Join Handle error: {}", fuckup);
-        let _ = check_run.mark_failed(&fuckup).await;
+        fail_job(
+            &check_run,
+            (&repo, pull_request),
+            FailureCategory::Panic,
+            &fuckup,
+            notify,
+        )
+        .await;
         return;
     }
 
@@ -75,10 +114,37 @@ async fn job_handler(name: &str, job: Job) {
     if let Err(e) = output {
         let fuckup = format!("{e:?}");
         error!("Other rendering error: {}", fuckup);
-        let _ = check_run.mark_failed(&fuckup).await;
+        fail_job(
+            &check_run,
+            (&repo, pull_request),
+            FailureCategory::Error,
+            &fuckup,
+            notify,
+        )
+        .await;
         return;
     }
 
     let output = output.unwrap();
-    diffbot_lib::job::runner::handle_output(output, check_run, name).await;
+    // An empty output means nothing visually changed
+    if !output.is_empty() {
+        diffbot_lib::job::runner::apply_labels(
+            &installation,
+            &repo,
+            pull_request,
+            &crate::CONFIG.get().unwrap().change_labels,
+        )
+        .await;
+    }
+    diffbot_lib::job::runner::handle_output(
+        output,
+        check_run,
+        diffbot_lib::github::github_api::check_name(
+            name,
+            crate::CONFIG.get().unwrap().check_name_suffix.as_deref(),
+            &repo,
+        ),
+        &crate::CONFIG.get().unwrap().no_changes_message,
+    )
+    .await;
 }
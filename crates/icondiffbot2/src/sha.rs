@@ -1,6 +1,9 @@
 use actix_web::rt::Runtime;
 use diffbot_lib::{
-    github::{github_api::download_url, github_types::ChangeType},
+    github::{
+        github_api::{download_url, DownloadPolicy},
+        github_types::ChangeType,
+    },
     job::types::Job,
 };
 use dmm_tools::dmi::IconFile;
@@ -8,6 +11,7 @@ use eyre::{Context, Result};
 use std::{
     collections::hash_map::DefaultHasher,
     hash::{Hash, Hasher},
+    time::Duration,
 };
 
 #[derive(Debug)]
@@ -47,7 +51,12 @@ pub fn get_if_exists(
     if let Some(sha) = sha {
         let rt = Runtime::new()?;
         let raw = rt.block_on(async {
-            download_url(&job.installation, &job.repo, filename, sha)
+            let conf = crate::CONFIG.get().unwrap();
+            let policy = DownloadPolicy {
+                timeout: Duration::from_secs(conf.download_timeout_secs),
+                retries: conf.download_retries,
+            };
+            download_url(&job.installation, &job.repo, filename, sha, policy)
                 .await
                 .with_context(|| format!("Failed to download file {filename:?}"))
         })?;
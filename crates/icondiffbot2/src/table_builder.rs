@@ -2,6 +2,30 @@ use diffbot_lib::github::github_types::{CheckOutputs, Output};
 use eyre::Result;
 use std::collections::HashMap;
 
+/// " (2 added, 1 modified)" for a file's summary line, going by the status column of its rows
+fn change_counts(states: &[String]) -> String {
+    let statuses: Vec<&str> = states
+        .iter()
+        .filter_map(|row| row.trim_end().trim_end_matches('|').rsplit('|').next())
+        .collect();
+    let counts: Vec<String> = [
+        ("added", "Created"),
+        ("removed", "Deleted"),
+        ("modified", "Modified"),
+    ]
+    .into_iter()
+    .filter_map(|(label, status)| {
+        let count = statuses.iter().filter(|s| s.starts_with(status)).count();
+        (count > 0).then(|| format!("{count} {label}"))
+    })
+    .collect();
+    if counts.is_empty() {
+        String::new()
+    } else {
+        format!(" ({})", counts.join(", "))
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct OutputTableBuilder<'a> {
     map: HashMap<&'a str, (&'static str, Vec<String>)>,
@@ -21,44 +45,50 @@ impl<'a> OutputTableBuilder<'a> {
         self.map.insert(k, v)
     }
 
+    /// One collapsible block per file, files with more than `collapse_over` state changes start out collapsed
     #[tracing::instrument]
-    pub fn build(&self) -> Result<CheckOutputs> {
-        // TODO: Make this not shit
-        let mut file_names: HashMap<&str, u32> = HashMap::new();
-        let mut details: Vec<(String, &str, String)> = Vec::new();
-        let mut current_table = String::new();
+    pub fn build(&self, collapse_over: usize) -> Result<CheckOutputs> {
+        let mut details: Vec<(String, &str, String, &str, String)> = Vec::new();
 
-        for (file_name, (change_type, states)) in self.map.iter() {
-            let entry = file_names.entry(file_name).or_insert(0);
+        let mut files: Vec<_> = self.map.iter().collect();
+        files.sort_by_key(|(file_name, _)| **file_name);
 
+        for (file_name, (change_type, states)) in files {
+            let mut parts = vec![];
+            let mut current_table = String::new();
             for state in states {
                 // A little extra buffer room for the <detail> block
                 if current_table.len() + state.len() > 55_000 {
-                    details.push((
-                        format!("{} ({})", file_name, *entry),
-                        change_type,
-                        std::mem::take(&mut current_table),
-                    ));
-                    *entry += 1;
+                    parts.push(std::mem::take(&mut current_table));
                 }
                 current_table.push_str(state.as_str());
                 current_table.push('\n');
             }
-
             if !current_table.is_empty() {
-                details.push((
-                    format!("{} ({})", file_name, *entry),
-                    change_type,
-                    std::mem::take(&mut current_table),
-                ));
-                *entry += 1;
+                parts.push(current_table);
+            }
+
+            let counts = change_counts(states);
+            let open = if states.len() > collapse_over {
+                ""
+            } else {
+                " open"
+            };
+            let total = parts.len();
+            for (idx, table) in parts.into_iter().enumerate() {
+                let name = if total > 1 {
+                    format!("{file_name} (part {}/{total})", idx + 1)
+                } else {
+                    file_name.to_string()
+                };
+                details.push((name, change_type, counts.clone(), open, table));
             }
         }
 
         let mut chunks: Vec<Output> = Vec::new();
         let mut current_output_text = String::new();
 
-        for (file_name, change_type, table) in details.iter() {
+        for (file_name, change_type, counts, open, table) in details.iter() {
             // TODO: use an <img> tag so i can set a style that upscales 32x32 to 64x64
             // and sets all the browser flags for nearest neighbor scaling
             let diff_block = format!(
@@ -69,6 +99,8 @@ impl<'a> OutputTableBuilder<'a> {
                 filename = file_name,
                 table = table,
                 typ = change_type,
+                counts = counts,
+                open = open,
             );
 
             if current_output_text.len() + diff_block.len() > 60_000 {
@@ -0,0 +1,46 @@
+//! Read only REST api for tooling that wants a pull's renders without scraping the check output.
+//! Only mounted if `api_token` is set, callers send it as `Authorization: Bearer <token>`.
+
+use actix_web::{error, get, http::header, web, HttpRequest, HttpResponse};
+use diffbot_lib::verify::tokens_match;
+
+use crate::results::latest_result_path;
+use crate::CONFIG;
+
+fn authorize(request: &HttpRequest) -> actix_web::Result<()> {
+    let Some(expected) = CONFIG.get().unwrap().api_token.as_deref() else {
+        return Err(error::ErrorNotFound("Not found"));
+    };
+    let provided = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match provided {
+        Some(provided) if tokens_match(expected, provided) => Ok(()),
+        _ => Err(error::ErrorUnauthorized("Missing or wrong api token")),
+    }
+}
+
+/// The latest result for a pull, same format as result.json
+#[get("/api/v1/results/{repo_id}/{pull_request}")]
+async fn get_result(
+    request: HttpRequest,
+    path: web::Path<(u64, u64)>,
+) -> actix_web::Result<HttpResponse> {
+    authorize(&request)?;
+    let (repo_id, pull_request) = path.into_inner();
+
+    match std::fs::read(latest_result_path(repo_id, pull_request)) {
+        Ok(result) => Ok(HttpResponse::Ok()
+            .content_type("application/json")
+            .body(result)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Err(error::ErrorNotFound("No result for that pull yet"))
+        }
+        Err(e) => {
+            diffbot_lib::log::error!("Failed to read result for {repo_id}#{pull_request}: {e:?}");
+            Err(error::ErrorInternalServerError("Couldn't read the result"))
+        }
+    }
+}
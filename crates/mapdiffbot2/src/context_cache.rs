@@ -0,0 +1,62 @@
+//! Parsed environments kept around between jobs, parsing the .dme and loading its icons is a good chunk of a job.
+//! On a busy repo most PRs render against the same base commit, so they can share it.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use eyre::Result;
+
+use crate::rendering::RenderingContext;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ContextKey {
+    repo: PathBuf,
+    commit: git2::Oid,
+    environment: Option<PathBuf>,
+}
+
+/// Least recently used first
+static CACHE: Mutex<Vec<(ContextKey, Arc<RenderingContext>)>> = Mutex::new(Vec::new());
+
+/// Returns the context for `environment` at `commit`, calling `parse` if it isn't cached.
+/// Keeps the `capacity` most recently used contexts, nothing is cached with a capacity of 0.
+/// `commit` has to be checked out, icons are loaded lazily while rendering.
+pub fn get_or_parse(
+    repo: &Path,
+    commit: git2::Oid,
+    environment: Option<&Path>,
+    capacity: usize,
+    parse: impl FnOnce() -> Result<RenderingContext>,
+) -> Result<Arc<RenderingContext>> {
+    if capacity == 0 {
+        return parse().map(Arc::new);
+    }
+
+    let key = ContextKey {
+        repo: repo.to_path_buf(),
+        commit,
+        environment: environment.map(Path::to_path_buf),
+    };
+
+    {
+        let mut cache = CACHE.lock().unwrap();
+        if let Some(idx) = cache.iter().position(|(cached, _)| *cached == key) {
+            let entry = cache.remove(idx);
+            let context = entry.1.clone();
+            cache.push(entry);
+            return Ok(context);
+        }
+    }
+
+    // Parsed without holding the lock, two jobs missing at once just both parse
+    let context = Arc::new(parse()?);
+
+    let mut cache = CACHE.lock().unwrap();
+    if !cache.iter().any(|(cached, _)| *cached == key) {
+        cache.push((key, context.clone()));
+    }
+    let excess = cache.len().saturating_sub(capacity);
+    cache.drain(..excess);
+
+    Ok(context)
+}
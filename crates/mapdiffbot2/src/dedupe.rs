@@ -0,0 +1,64 @@
+//! Identical images within a job, like copy-pasted areas or z-levels that render the same, are only kept once.
+//! Duplicates become hard links to the first copy, and the check output links all of them to the first copy's URL.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use diffbot_lib::log;
+use diffbot_lib::upload::content_hash;
+
+/// Swaps `duplicate` for a hard link to `original`, through a temporary file so it's never missing
+fn link_to_original(original: &Path, duplicate: &Path) -> std::io::Result<()> {
+    let temporary = duplicate.with_extension("png.dedupe");
+    std::fs::hard_link(original, &temporary)?;
+    std::fs::rename(&temporary, duplicate)
+}
+
+/// Every image under `directory` that's a copy of another one, with the one it's a copy of. Both relative to `directory`
+pub fn dedupe_images(directory: &Path) -> HashMap<String, String> {
+    let mut images = walkdir::WalkDir::new(directory)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry.file_type().is_file() && entry.path().extension().is_some_and(|ext| ext == "png")
+        })
+        .filter_map(|entry| {
+            let relative = entry
+                .path()
+                .strip_prefix(directory)
+                .ok()?
+                .to_string_lossy()
+                .into_owned();
+            let size = entry.metadata().ok()?.len();
+            Some((relative, entry.into_path(), size))
+        })
+        .collect::<Vec<_>>();
+    // So the same copy is kept every time
+    images.sort();
+
+    let mut originals: HashMap<(u64, String), (String, PathBuf)> = HashMap::new();
+    let mut duplicates = HashMap::new();
+    for (relative, path, size) in images {
+        let Some(hash) = content_hash(&path) else {
+            continue;
+        };
+        match originals.get(&(size, hash.clone())) {
+            // The hash is short, make sure it really is the same image
+            Some((original, original_path))
+                if std::fs::read(original_path).ok() == std::fs::read(&path).ok() =>
+            {
+                if let Err(e) = link_to_original(original_path, &path) {
+                    log::debug!(
+                        "Couldn't hard link {path:?} to {original_path:?}, keeping the copy: {e}"
+                    );
+                }
+                duplicates.insert(relative, original.clone());
+            }
+            Some(_) => {}
+            None => {
+                originals.insert((size, hash), (relative, path));
+            }
+        }
+    }
+    duplicates
+}
@@ -3,7 +3,7 @@ use std::sync::Arc;
 use delay_timer::prelude::*;
 use diffbot_lib::{
     async_mutex::Mutex,
-    job::types::{JobSender, JobType},
+    job::types::{JobPriority, JobSender, JobType},
     log,
 };
 
@@ -23,7 +23,12 @@ pub async fn gc_scheduler(cron_str: String, job: Arc<Mutex<JobSender>>) {
                         serde_json::to_vec(&JobType::CleanupJob("GC_REQUEST_DUMMY".to_owned()))
                             .expect("Cannot serialize cleanupjob, what the fuck");
                     async move {
-                        if let Err(err) = sender_clone.lock().await.send(job).await {
+                        if let Err(err) = sender_clone
+                            .lock()
+                            .await
+                            .send(JobPriority::Normal, job)
+                            .await
+                        {
                             log::error!("Cannot send cleanup job: {}", err)
                         }
                     }
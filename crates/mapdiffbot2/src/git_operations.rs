@@ -1,30 +1,128 @@
+use diffbot_lib::log;
 use eyre::{Context, Result};
+use std::collections::HashMap;
 use std::path::Path;
+use std::sync::{Condvar, Mutex};
 
-use git2::{build::CheckoutBuilder, FetchOptions, Repository};
+use git2::{build::CheckoutBuilder, Cred, FetchOptions, RemoteCallbacks, Repository};
+
+/// Clones, fetches and checkouts across all jobs, bounded by `concurrency.git_ops`.
+/// They're IO bound where rendering is CPU bound, so they get a limit of their own.
+static GIT_OPS_RUNNING: Mutex<usize> = Mutex::new(0);
+static GIT_OP_DONE: Condvar = Condvar::new();
+
+/// Lets the next git operation in once dropped
+struct GitOpPermit;
+
+impl Drop for GitOpPermit {
+    fn drop(&mut self) {
+        *GIT_OPS_RUNNING.lock().unwrap() -= 1;
+        GIT_OP_DONE.notify_one();
+    }
+}
+
+/// Blocks until there's room for another git operation, never nest these
+fn git_op_permit() -> Option<GitOpPermit> {
+    let limit = crate::CONFIG.get().unwrap().concurrency.git_ops?.max(1);
+    let mut running = GIT_OPS_RUNNING.lock().unwrap();
+    while *running >= limit {
+        running = GIT_OP_DONE.wait(running).unwrap();
+    }
+    *running += 1;
+    Some(GitOpPermit)
+}
+
+/// Fetch options authenticating with an installation token, if we have one
+fn fetch_options(token: Option<&str>) -> FetchOptions<'_> {
+    let mut options = FetchOptions::new();
+    options.prune(git2::FetchPrune::On);
+    if let Some(token) = token {
+        let mut callbacks = RemoteCallbacks::new();
+        let mut attempted = false;
+        callbacks.credentials(move |_, _, _| {
+            // libgit2 keeps asking for as long as we keep handing out credentials it rejects
+            if attempted {
+                return Err(git2::Error::from_str("Installation token was rejected"));
+            }
+            attempted = true;
+            Cred::userpass_plaintext("x-access-token", token)
+        });
+        options.remote_callbacks(callbacks);
+    }
+    options
+}
+
+/// Name of a branch a job makes for itself, unique to the pull and run so jobs can't trip over each other
+fn internal_branch(prefix: &str, kind: &str, (pull_request, run): (u64, &str)) -> String {
+    format!("{prefix}-{kind}-{pull_request}-{run}")
+}
+
+/// The branches a job makes for itself, only these get deleted once it's done
+pub struct InternalBranches {
+    pull_request: u64,
+    fetched: String,
+    head: String,
+    merge_base: String,
+    /// Set once none of the names turned out to be taken, until then they could be someone's real branches
+    claimed: std::cell::Cell<bool>,
+}
+
+impl InternalBranches {
+    /// `run` has to be unique to this attempt at the job, see `run_token`
+    pub fn new(pull_request: u64, run: &str) -> Self {
+        let prefix = &crate::CONFIG.get().unwrap().branch_prefix;
+        Self {
+            pull_request,
+            fetched: internal_branch(prefix, "fetched", (pull_request, run)),
+            head: internal_branch(prefix, "head", (pull_request, run)),
+            merge_base: internal_branch(prefix, "merge-base", (pull_request, run)),
+            claimed: std::cell::Cell::new(false),
+        }
+    }
+
+    fn names(&self) -> [&str; 3] {
+        [&self.fetched, &self.head, &self.merge_base]
+    }
+}
+
+/// The check run's id plus when the job started, a job picked up again after a crash mustn't run into its own leftovers
+pub fn run_token(check_run: u64) -> String {
+    let started = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    format!("{check_run}-{started}")
+}
+
+/// Internal branch names are force-updated, make sure that can't clobber a real branch that happens to share the name
+fn ensure_unused(repo: &Repository, name: &str) -> Result<()> {
+    match repo.find_branch(name, git2::BranchType::Local) {
+        Ok(_) => Err(eyre::anyhow!(
+            "Refusing to overwrite existing branch {name}, is it a real branch using `branch_prefix`?"
+        )),
+        Err(_) => Ok(()),
+    }
+}
 
 pub fn fetch_and_get_branches<'a>(
     base_sha: &str,
     head_sha: &str,
     repo: &'a git2::Repository,
-    head_branch_name: &str,
+    branches: &InternalBranches,
     base_branch_name: &str,
+    merge_base: bool,
+    token: Option<&str>,
 ) -> Result<(git2::Reference<'a>, git2::Reference<'a>)> {
+    let _permit = git_op_permit();
+
     let base_id = git2::Oid::from_str(base_sha).context("Parsing base sha")?;
     let head_id = git2::Oid::from_str(head_sha).context("Parsing head sha")?;
 
-    let mut remote = repo.find_remote("origin")?;
-
-    remote
-        .connect(git2::Direction::Fetch)
-        .context("Connecting to remote")?;
+    let mut remote = find_remote(repo)?;
 
+    // Fetching connects on its own, with credentials, if there are any
     remote
-        .fetch(
-            &[base_branch_name],
-            Some(FetchOptions::new().prune(git2::FetchPrune::On)),
-            None,
-        )
+        .fetch(&[base_branch_name], Some(&mut fetch_options(token)), None)
         .context("Fetching base")?;
     let fetch_head = repo
         .find_reference("FETCH_HEAD")
@@ -67,23 +165,47 @@ pub fn fetch_and_get_branches<'a>(
         .resolve_reference_from_short_name(base_branch_name)
         .context("Getting the base reference")?;
 
-    remote
-        .fetch(
-            &[head_branch_name],
-            Some(FetchOptions::new().prune(git2::FetchPrune::On)),
-            None,
-        )
-        .context("Fetching head")?;
+    let pull_request = branches.pull_request;
+    let (fetched_name, head_name, merge_base_name) =
+        (&branches.fetched, &branches.head, &branches.merge_base);
+    for name in branches.names() {
+        ensure_unused(repo, name)?;
+    }
+    branches.claimed.set(true);
+
+    // pull/N/head can go missing, like when the fork the PR came from was deleted
+    let fetched = remote.fetch(
+        &[format!("pull/{pull_request}/head:{fetched_name}")],
+        Some(&mut fetch_options(token)),
+        None,
+    );
+    if let Err(e) = fetched {
+        if !crate::CONFIG.get().unwrap().fetch_head_by_sha {
+            return Err(e).context(format!(
+                "PR head unavailable (fork deleted?), couldn't fetch pull/{pull_request}/head"
+            ));
+        }
+        log::warn!("Fetching pull/{pull_request}/head failed, trying {head_sha} directly: {e}");
+        remote
+            .fetch(
+                &[format!("{head_sha}:refs/heads/{fetched_name}")],
+                Some(&mut fetch_options(token)),
+                None,
+            )
+            .with_context(|| {
+                format!(
+                    "PR head unavailable (fork deleted?), neither pull/{pull_request}/head nor {head_sha} could be fetched: {e}"
+                )
+            })?;
+    }
 
     let fetch_head = repo
         .find_reference("FETCH_HEAD")
         .context("Getting FETCH_HEAD")?;
 
-    let head_name = format!("mdb-pull-{base_sha}-{head_sha}");
-
     let mut head_branch = repo
         .branch_from_annotated_commit(
-            &head_name,
+            head_name,
             &repo.reference_to_annotated_commit(&fetch_head)?,
             true,
         )
@@ -93,9 +215,15 @@ pub fn fetch_and_get_branches<'a>(
     repo.set_head(head_branch.name().unwrap())
         .context("Setting HEAD to head")?;
 
-    let head_commit = match repo.find_commit(head_id).context("Finding head commit") {
-        Ok(commit) => commit,
-        Err(_) => repo.head()?.peel_to_commit()?,
+    // In merge base mode the fetched PR head is used as is, whatever sha the job was created with
+    let head_commit = match repo
+        .find_commit(head_id)
+        .context("Finding head commit")
+        .ok()
+        .filter(|_| !merge_base)
+    {
+        Some(commit) => commit,
+        None => repo.head()?.peel_to_commit()?,
     };
 
     head_branch.set_target(
@@ -104,9 +232,23 @@ pub fn fetch_and_get_branches<'a>(
     )?;
 
     let head_branch = repo
-        .resolve_reference_from_short_name(&head_name)
+        .resolve_reference_from_short_name(head_name)
         .context("Getting the head reference")?;
 
+    let base_branch = if merge_base {
+        let merge_base_id = repo
+            .merge_base(base_commit.id(), head_commit.id())
+            .context("Finding the merge base of base and head")?;
+        let merge_base_commit = repo
+            .find_commit(merge_base_id)
+            .context("Finding merge base commit")?;
+        repo.branch(merge_base_name, &merge_base_commit, true)
+            .context("Creating merge base branch")?
+            .into_reference()
+    } else {
+        base_branch
+    };
+
     remote.disconnect().context("Disconnecting from remote")?;
 
     repo.set_head(
@@ -127,7 +269,122 @@ pub fn fetch_and_get_branches<'a>(
     Ok((base_branch, head_branch))
 }
 
-pub fn clean_up_references(repo: &Repository, branch: &str) -> Result<()> {
+/// Finds the remote to fetch from, preferring `origin` but falling back to whichever remote is configured
+pub fn find_remote(repo: &Repository) -> Result<git2::Remote<'_>> {
+    if let Ok(remote) = repo.find_remote("origin") {
+        return Ok(remote);
+    }
+    let remotes = repo.remotes().context("Listing remotes")?;
+    let name = remotes.iter().flatten().next().ok_or_else(|| {
+        eyre::anyhow!("Repository has no remotes configured, the checkout may need to be deleted and recloned")
+    })?;
+    log::warn!("No remote named origin, falling back to {name}");
+    repo.find_remote(name)
+        .with_context(|| format!("Finding remote {name}"))
+}
+
+/// Files renamed between `base` and `head` by git's reckoning, new path to old path.
+/// Only renames at least `threshold` percent similar are paired up, anything less is left out.
+pub fn find_renames(
+    repo: &Repository,
+    base: &git2::Reference,
+    head: &git2::Reference,
+    threshold: u16,
+) -> Result<HashMap<String, String>> {
+    let base_tree = base.peel_to_tree().context("Getting base tree")?;
+    let head_tree = head.peel_to_tree().context("Getting head tree")?;
+    let mut diff = repo
+        .diff_tree_to_tree(Some(&base_tree), Some(&head_tree), None)
+        .context("Diffing base and head")?;
+
+    let mut options = git2::DiffFindOptions::new();
+    options.renames(true).rename_threshold(threshold);
+    diff.find_similar(Some(&mut options))
+        .context("Finding renames")?;
+
+    Ok(diff
+        .deltas()
+        .filter(|delta| delta.status() == git2::Delta::Renamed)
+        .filter_map(|delta| {
+            let new = delta.new_file().path()?.to_str()?;
+            let old = delta.old_file().path()?.to_str()?;
+            Some((new.to_owned(), old.to_owned()))
+        })
+        .collect())
+}
+
+/// A commit as shown in the output, (short sha, summary)
+pub type CommitSummary = (String, String);
+
+/// For each of `files`, the commits from the merge base of `base` and `head` up to `head` that changed it, oldest first.
+/// Merge commits are compared against their first parent, so a merged in base branch doesn't count.
+pub fn commits_touching(
+    repo: &Repository,
+    base: git2::Oid,
+    head: git2::Oid,
+    files: &[&str],
+) -> Result<HashMap<String, Vec<CommitSummary>>> {
+    let merge_base = repo
+        .merge_base(base, head)
+        .context("Finding the merge base of base and head")?;
+
+    let mut walk = repo.revwalk().context("Creating revwalk")?;
+    walk.push(head).context("Pushing head")?;
+    walk.hide(merge_base).context("Hiding merge base")?;
+    walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)
+        .context("Sorting revwalk")?;
+
+    let mut options = git2::DiffOptions::new();
+    options.disable_pathspec_match(true);
+    for file in files {
+        options.pathspec(file);
+    }
+
+    let mut touched: HashMap<String, Vec<CommitSummary>> = HashMap::new();
+    for oid in walk {
+        let commit = repo
+            .find_commit(oid.context("Walking commits")?)
+            .context("Finding commit")?;
+        let parent_tree = match commit.parents().next() {
+            Some(parent) => Some(parent.tree().context("Getting parent tree")?),
+            None => None,
+        };
+        let diff = repo
+            .diff_tree_to_tree(
+                parent_tree.as_ref(),
+                Some(&commit.tree().context("Getting commit tree")?),
+                Some(&mut options),
+            )
+            .context("Diffing commit")?;
+
+        let mut paths = diff
+            .deltas()
+            .flat_map(|delta| [delta.old_file().path(), delta.new_file().path()])
+            .flatten()
+            .filter_map(|path| path.to_str())
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        paths.dedup();
+
+        let id = commit.id().to_string();
+        let summary = (
+            id[..7].to_owned(),
+            commit.summary().unwrap_or("").to_owned(),
+        );
+        for path in paths {
+            touched.entry(path).or_default().push(summary.clone());
+        }
+    }
+    Ok(touched)
+}
+
+pub fn clean_up_references(
+    repo: &Repository,
+    branch: &str,
+    branches: &InternalBranches,
+) -> Result<()> {
+    let _permit = git_op_permit();
+
     repo.set_head(
         repo.resolve_reference_from_short_name(branch)?
             .name()
@@ -141,22 +398,18 @@ pub fn clean_up_references(repo: &Repository, branch: &str) -> Result<()> {
             .remove_untracked(true),
     ))
     .context("Checkout to head")?;
-    let mut references = repo.references().context("Getting all references")?;
-    let references = references
-        .names()
-        .filter_map(move |reference| {
-            (reference.as_ref().ok()?.contains("pull-"))
-                .then(move || reference.ok())
-                .flatten()
-        })
-        .map(|item| item.to_owned())
-        .collect::<Vec<_>>();
-
-    for refname in references {
-        let mut reference = repo
-            .find_reference(&refname)
-            .context("Looking for ref to delete")?;
-        reference.delete().context("Deleting reference")?;
+    // Only what this job made, a name that was already taken belongs to someone else
+    if !branches.claimed.get() {
+        return Ok(());
+    }
+    for name in branches.names() {
+        // Not every job gets as far as making all of them
+        if let Ok(branch) = repo.find_branch(name, git2::BranchType::Local) {
+            branch
+                .into_reference()
+                .delete()
+                .context("Deleting reference")?;
+        }
     }
     Ok(())
 }
@@ -166,17 +419,24 @@ pub fn with_checkout<T>(
     repo: &Repository,
     f: impl FnOnce() -> Result<T>,
 ) -> Result<T> {
-    repo.set_head(checkout_ref.name().unwrap())?;
-    repo.checkout_head(Some(
-        CheckoutBuilder::new()
-            .force()
-            .remove_ignored(true)
-            .remove_untracked(true),
-    ))?;
+    {
+        let _permit = git_op_permit();
+        repo.set_head(checkout_ref.name().unwrap())?;
+        repo.checkout_head(Some(
+            CheckoutBuilder::new()
+                .force()
+                .remove_ignored(true)
+                .remove_untracked(true),
+        ))?;
+    }
     f()
 }
 
-pub fn clone_repo(url: &str, dir: &Path) -> Result<()> {
-    git2::Repository::clone(url, dir.as_os_str()).context("Cloning repo")?;
+pub fn clone_repo(url: &str, dir: &Path, token: Option<&str>) -> Result<()> {
+    let _permit = git_op_permit();
+    git2::build::RepoBuilder::new()
+        .fetch_options(fetch_options(token))
+        .clone(url, dir)
+        .context("Cloning repo")?;
     Ok(())
 }
@@ -1,24 +1,73 @@
 use diffbot_lib::log;
 use eyre::{Context, Result};
 use octocrab::models::InstallationId;
+use std::collections::HashSet;
 
-use crate::DataJobSender;
+use crate::{DataCooldown, DataJobSender};
 use diffbot_lib::{
     github::{
-        github_api::CheckRun,
+        github_api::{changed_between, get_pull, has_write_access, open_pulls_into, CheckRun},
         github_types::{
-            ChangeType, Installation, Output, PullRequest, PullRequestEventPayload, Repository,
+            title_skip_marker, ChangeType, FileDiff, Installation, IssueCommentEventPayload,
+            Output, PullRequest, PullRequestEventPayload, PushEventPayload, Repository,
         },
-        graphql::get_pull_files,
+        graphql::{get_pull_files, pull_files_truncated},
+    },
+    job::{
+        cooldown::Submission,
+        in_flight::{pull_directories, remove_when_idle},
+        opt_out::{is_opted_out, opt_in, opt_out},
+        types::{unix_now, Job, JobPriority, JobType},
     },
-    job::types::{Job, JobType},
 };
 
+/// The name checks are created with, `check_name_suffix` and all
+fn check_name(repo: &Repository) -> String {
+    diffbot_lib::github::github_api::check_name(
+        "MapDiffBot2",
+        crate::CONFIG.get().unwrap().check_name_suffix.as_deref(),
+        repo,
+    )
+}
+
+fn relevant_files(files: Vec<FileDiff>) -> Vec<FileDiff> {
+    files
+        .into_iter()
+        .filter(|f| f.has_extension("dmm"))
+        .filter(|f| {
+            matches!(
+                f.status,
+                ChangeType::Added
+                    | ChangeType::Deleted
+                    | ChangeType::Modified
+                    | ChangeType::Renamed
+            )
+        })
+        .filter(|f| {
+            let conf = crate::CONFIG.get().unwrap();
+            conf.render_statuses.contains(&f.status)
+                || (f.status == ChangeType::Renamed && conf.rename_similarity.is_some())
+        })
+        .collect()
+}
+
+pub fn opted_out_output() -> Output {
+    let mention = &crate::CONFIG.get().unwrap().command_mention;
+    Output {
+        title: "Rendering turned off",
+        summary: format!(
+            "Rendering was turned off for this PR with `{mention} skip`, comment `{mention} render` to turn it back on."
+        ),
+        text: "".to_owned(),
+    }
+}
+
 async fn process_pull(
     repo: Repository,
     pull: PullRequest,
     check_run: CheckRun,
     installation: &Installation,
+    (files, files_truncated): (Result<Vec<FileDiff>>, bool),
     job_sender: DataJobSender,
 ) -> Result<()> {
     log::trace!("Processing pull request");
@@ -41,6 +90,29 @@ async fn process_pull(
         return Ok(());
     }
 
+    if let Some(marker) = title_skip_marker(
+        pull.title.as_deref(),
+        &crate::CONFIG.get().unwrap().skip_title_patterns,
+    ) {
+        let output = Output {
+            title: "PR Skipped",
+            summary: format!(
+                "This PR has `{marker}` in the title, it will be rendered once that's removed."
+            ),
+            text: "".to_owned(),
+        };
+
+        check_run.mark_skipped(output).await?;
+
+        return Ok(());
+    }
+
+    if is_opted_out((repo.id, pull.number)) {
+        check_run.mark_skipped(opted_out_output()).await?;
+
+        return Ok(());
+    }
+
     let (blacklist, contact) = {
         let conf = &crate::CONFIG.get().unwrap();
         (&conf.blacklist, &conf.blacklist_contact)
@@ -62,20 +134,8 @@ async fn process_pull(
         return Ok(());
     }
 
-    let files = match get_pull_files(repo.name_tuple(), installation.id, &pull)
-        .await
-        .context("Getting files modified by PR")
-    {
-        Ok(files) => files
-            .into_iter()
-            .filter(|f| f.filename.ends_with(".dmm"))
-            .filter(|f| {
-                matches!(
-                    f.status,
-                    ChangeType::Added | ChangeType::Deleted | ChangeType::Modified
-                )
-            })
-            .collect::<Vec<_>>(),
+    let files = match files {
+        Ok(files) => files,
         Err(err) => {
             check_run.mark_failed(&format!("{:?}", err)).await?;
             return Ok(());
@@ -96,6 +156,11 @@ async fn process_pull(
 
     check_run.mark_queued().await?;
 
+    let priority = {
+        let conf = &crate::CONFIG.get().unwrap();
+        JobPriority::for_pull(repo.id, &pull, &conf.priority_labels, &conf.priority_repos)
+    };
+
     let job = Job {
         repo,
         base: pull.base,
@@ -104,30 +169,361 @@ async fn process_pull(
         files,
         check_run,
         installation: InstallationId(installation.id),
+        priority,
+        files_truncated,
+        queued_at: Some(unix_now()),
     };
 
     let job = serde_json::to_vec(&JobType::GithubJob(Box::new(job)))?;
 
-    job_sender.lock().await.send(job).await?;
+    job_sender.lock().await.send(priority, job).await?;
 
     log::trace!("Job sent to queue");
 
     Ok(())
 }
 
-async fn handle_pull_request(payload: String, job_sender: DataJobSender) -> Result<&'static str> {
+/// Closed or merged, so nobody's looking at its renders anymore
+fn clean_up_closed_pull(payload: &PullRequestEventPayload, cooldown: &DataCooldown) {
+    let key = (payload.repository.id, payload.pull_request.number);
+    // No point rendering a deferred push either
+    let _ = cooldown.take(key);
+    if let Err(e) = opt_in(key) {
+        log::warn!("Failed to clear opt out of closed PR: {e}");
+    }
+
+    actix_web::rt::spawn(remove_when_idle(key, move || {
+        pull_directories(std::path::Path::new("./images"), key)
+    }));
+}
+
+async fn handle_pull_request(
+    payload: String,
+    job_sender: DataJobSender,
+    cooldown: DataCooldown,
+) -> Result<&'static str> {
     let payload: PullRequestEventPayload = serde_json::from_str(&payload)?;
-    if payload.action != "opened" && payload.action != "synchronize" {
+    if payload.action == "closed" {
+        clean_up_closed_pull(&payload, &cooldown);
+        return Ok("Cleaning up images of closed PR");
+    }
+    if payload.action != "opened"
+        && payload.action != "synchronize"
+        && !payload.skip_marker_removed(&crate::CONFIG.get().unwrap().skip_title_patterns)
+    {
         return Ok("PR not opened or updated");
     }
 
+    submit_render(payload, job_sender, cooldown).await
+}
+
+async fn submit_render(
+    payload: PullRequestEventPayload,
+    job_sender: DataJobSender,
+    cooldown: DataCooldown,
+) -> Result<&'static str> {
+    let key = (payload.repository.id, payload.pull_request.number);
+    let submission = cooldown.submit(key, payload);
+    match submission {
+        Submission::Ready(payload) => render_pull_request(payload, job_sender).await,
+        Submission::Deferred(delay) => {
+            actix_web::rt::spawn(async move {
+                actix_web::rt::time::sleep(delay).await;
+                if let Some(payload) = cooldown.take(key) {
+                    if let Err(e) = render_pull_request(payload, job_sender).await {
+                        log::error!("Error handling deferred event: {:?}", e);
+                    }
+                }
+            });
+            Ok("Render deferred until cooldown is over")
+        }
+        Submission::Coalesced => Ok("Coalesced with an already deferred render"),
+    }
+}
+
+enum CommentCommand {
+    Skip,
+    Render,
+}
+
+/// The first line of the comment starting with `mention` followed by a command
+fn comment_command(body: &str, mention: &str) -> Option<CommentCommand> {
+    body.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        if !words.next()?.eq_ignore_ascii_case(mention) {
+            return None;
+        }
+        match words.next()?.to_ascii_lowercase().as_str() {
+            "skip" => Some(CommentCommand::Skip),
+            "render" => Some(CommentCommand::Render),
+            _ => None,
+        }
+    })
+}
+
+/// `skip` turns rendering off for the PR until someone comments `render`, only people who can push to the repo get to do either
+async fn handle_comment(
+    payload: String,
+    job_sender: DataJobSender,
+    cooldown: DataCooldown,
+) -> Result<&'static str> {
+    let conf = crate::CONFIG.get().unwrap();
+    if !conf.comment_commands {
+        return Ok("Comment commands are off");
+    }
+
+    let payload: IssueCommentEventPayload = serde_json::from_str(&payload)?;
+    if payload.action != "created" || payload.issue.pull_request.is_none() {
+        return Ok("Not a new comment on a PR");
+    }
+    let Some(command) = comment_command(&payload.comment.body, &conf.command_mention) else {
+        return Ok("No command in comment");
+    };
+
+    let installation = InstallationId(payload.installation.id);
+    if !has_write_access(
+        &installation,
+        &payload.repository,
+        &payload.comment.user.login,
+    )
+    .await?
+    {
+        return Ok("Commenter can't push to the repo");
+    }
+
+    let key = (payload.repository.id, payload.issue.number);
+    let pull = get_pull(&installation, &payload.repository, payload.issue.number).await?;
+
+    match command {
+        CommentCommand::Skip => {
+            opt_out(key).context("Opting PR out")?;
+            // Otherwise a render waiting out its cooldown would still go through
+            let _ = cooldown.take(key);
+            CheckRun::create(
+                &payload.repository.full_name(),
+                &pull.head.sha,
+                payload.installation.id,
+                Some(&check_name(&payload.repository)),
+            )
+            .await?
+            .mark_skipped(opted_out_output())
+            .await?;
+            Ok("Rendering turned off")
+        }
+        CommentCommand::Render => {
+            opt_in(key).context("Opting PR back in")?;
+            let event = PullRequestEventPayload {
+                action: "synchronize".to_owned(),
+                number: pull.number,
+                repository: payload.repository,
+                pull_request: pull,
+                installation: payload.installation,
+                changes: Default::default(),
+            };
+            submit_render(event, job_sender, cooldown).await
+        }
+    }
+}
+
+/// How many open PRs have their files checked at once after a base push
+const BASE_PUSH_CONCURRENCY: usize = 4;
+
+/// Re-renders the open PRs into the pushed branch that change maps the push changed too, their renders are stale against the new base.
+/// Github gives up on a webhook after 10 seconds, so the push is acknowledged right away and the PRs are gone through in the background.
+async fn handle_base_push(
+    payload: String,
+    job_sender: DataJobSender,
+    cooldown: DataCooldown,
+) -> Result<&'static str> {
+    if !crate::CONFIG.get().unwrap().rerender_on_base_push {
+        return Ok("Not re-rendering on base pushes");
+    }
+
+    let payload: PushEventPayload = serde_json::from_str(&payload)?;
+    if payload.branch().is_none() {
+        return Ok("Not a branch push");
+    }
+    if payload.deleted {
+        return Ok("Branch deleted");
+    }
+
+    // Only trustworthy if every commit was listed, otherwise it's up to the comparison
+    if !payload.commits_truncated()
+        && !payload
+            .changed_files()
+            .iter()
+            .any(|file| file.ends_with(".dmm"))
+    {
+        return Ok("Push didn't change any maps");
+    }
+
+    actix_web::rt::spawn(async move {
+        if let Err(e) = rerender_after_push(payload, job_sender, cooldown).await {
+            log::error!("Error re-rendering after base push: {:?}", e);
+        }
+    });
+
+    Ok("Re-rendering PRs affected by the push")
+}
+
+/// Maps the push changed, None if that can't be told and every open PR has to be checked
+async fn pushed_maps(payload: &PushEventPayload) -> Option<HashSet<String>> {
+    let maps = |files: Vec<String>| -> HashSet<String> {
+        files
+            .into_iter()
+            .filter(|file| file.ends_with(".dmm"))
+            .collect()
+    };
+    if !payload.commits_truncated() {
+        return Some(maps(
+            payload
+                .changed_files()
+                .into_iter()
+                .map(str::to_owned)
+                .collect(),
+        ));
+    }
+
+    match changed_between(
+        &InstallationId(payload.installation.id),
+        &payload.repository(),
+        &payload.before,
+        &payload.after,
+    )
+    .await
+    {
+        Ok(files) => files.map(maps),
+        Err(e) => {
+            log::warn!(
+                "Failed to compare {}...{}: {e:?}",
+                payload.before,
+                payload.after
+            );
+            None
+        }
+    }
+}
+
+async fn rerender_after_push(
+    payload: PushEventPayload,
+    job_sender: DataJobSender,
+    cooldown: DataCooldown,
+) -> Result<()> {
+    let pushed_maps = pushed_maps(&payload).await;
+    if pushed_maps.as_ref().is_some_and(HashSet::is_empty) {
+        return Ok(());
+    }
+
+    let repository = payload.repository();
+    let branch = payload.branch().unwrap_or_default().to_owned();
+    let pulls = open_pulls_into(
+        &InstallationId(payload.installation.id),
+        &repository,
+        &branch,
+    )
+    .await?;
+
+    // A handful of workers sharing the PRs, so a push to a busy branch doesn't fire 100 requests at once
+    let (sender, receiver) = flume::unbounded();
+    for pull in pulls {
+        let _ = sender.send(pull);
+    }
+    drop(sender);
+
+    let push = std::rc::Rc::new((payload, repository, branch, pushed_maps));
+    for _ in 0..BASE_PUSH_CONCURRENCY {
+        let (receiver, push) = (receiver.clone(), push.clone());
+        let (job_sender, cooldown) = (job_sender.clone(), cooldown.clone());
+        actix_web::rt::spawn(async move {
+            let (payload, repository, branch, pushed_maps) = &*push;
+            while let Ok(mut pull) = receiver.recv_async().await {
+                let files =
+                    match get_pull_files(repository.name_tuple(), payload.installation.id, &pull)
+                        .await
+                    {
+                        Ok(files) => relevant_files(files),
+                        Err(e) => {
+                            log::warn!("Failed to get files of #{}: {e:?}", pull.number);
+                            continue;
+                        }
+                    };
+                let affected = match pushed_maps {
+                    Some(maps) => files.iter().any(|file| maps.contains(&file.filename)),
+                    None => !files.is_empty(),
+                };
+                if !affected {
+                    continue;
+                }
+
+                log::info!(
+                    "[{}#{}] Re-rendering after a push to {branch}",
+                    repository.full_name(),
+                    pull.number
+                );
+                pull.base.sha = payload.after.clone();
+                let event = PullRequestEventPayload {
+                    action: "synchronize".to_owned(),
+                    number: pull.number,
+                    repository: repository.clone(),
+                    pull_request: pull,
+                    installation: payload.installation.clone(),
+                    changes: Default::default(),
+                };
+                if let Err(e) = submit_render(event, job_sender.clone(), cooldown.clone()).await {
+                    log::error!("Error re-rendering after base push: {:?}", e);
+                }
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn render_pull_request(
+    payload: PullRequestEventPayload,
+    job_sender: DataJobSender,
+) -> Result<&'static str> {
+    let files = get_pull_files(
+        payload.repository.name_tuple(),
+        payload.installation.id,
+        &payload.pull_request,
+    )
+    .await
+    .context("Getting files modified by PR");
+    let files_truncated = files
+        .as_ref()
+        .map_or(false, |files| pull_files_truncated(files));
+
+    // Nothing to clone or queue, it gets its answer right away
+    if files.as_ref().map_or(false, |files| files.is_empty()) {
+        if crate::CONFIG.get().unwrap().empty_pull_check {
+            CheckRun::create(
+                &payload.repository.full_name(),
+                &payload.pull_request.head.sha,
+                payload.installation.id,
+                Some(&check_name(&payload.repository)),
+            )
+            .await?
+            .mark_skipped(Output::empty_pull())
+            .await?;
+        }
+        return Ok("No files changed");
+    }
+
+    let files = files.map(relevant_files);
+
+    // No point in putting a skipped check on every push or title edit of a PR that never touched a map
+    if payload.action != "opened" && files.as_ref().map_or(false, |files| files.is_empty()) {
+        return Ok("No relevant files changed");
+    }
+
     log::trace!("Creating checkrun");
 
     let check_run = CheckRun::create(
         &payload.repository.full_name(),
         &payload.pull_request.head.sha,
         payload.installation.id,
-        Some("MapDiffBot2"),
+        Some(&check_name(&payload.repository)),
     )
     .await?;
 
@@ -136,6 +532,7 @@ async fn handle_pull_request(payload: String, job_sender: DataJobSender) -> Resu
         payload.pull_request,
         check_run,
         &payload.installation,
+        (files, files_truncated),
         job_sender,
     )
     .await?;
@@ -148,8 +545,9 @@ pub async fn process_github_payload(
     event: diffbot_lib::github::github_api::GithubEvent,
     payload: String,
     job_sender: DataJobSender,
+    cooldown: DataCooldown,
 ) -> actix_web::Result<&'static str> {
-    if event.0 != "pull_request" {
+    if !matches!(event.0.as_str(), "pull_request" | "push" | "issue_comment") {
         return Ok("Not a pull request event");
     }
 
@@ -166,7 +564,13 @@ pub async fn process_github_payload(
 
     log::trace!("Payload received, processing");
 
-    handle_pull_request(payload, job_sender).await.map_err(|e| {
+    let handled = match event.0.as_str() {
+        "push" => handle_base_push(payload, job_sender, cooldown).await,
+        "issue_comment" => handle_comment(payload, job_sender, cooldown).await,
+        _ => handle_pull_request(payload, job_sender, cooldown).await,
+    };
+
+    handled.map_err(|e| {
         log::error!("Error handling event: {:?}", e);
         actix_web::error::ErrorBadRequest(e)
     })
@@ -0,0 +1,190 @@
+//! Icons used on the tiles a PR changed, so a map change that swaps sprites shows which sprites went in and out
+//! without anyone having to spot it in the renders.
+
+use std::cmp::min;
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use diffbot_lib::job::budget::OutputBudget;
+use diffbot_lib::log;
+use diffbot_lib::png_encoding::encode_rgba;
+use diffbot_lib::upload::write_with_retries;
+use dmm_tools::dmm;
+use dreammaker::constants::Constant;
+use dreammaker::objtree::ObjectTree;
+
+use crate::rendering::{same_tile, RenderingContext};
+use crate::CONFIG;
+
+/// Past this a map's list stops being a quick glance
+const MAX_THUMBNAILS: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct IconRef {
+    pub icon: String,
+    pub state: String,
+}
+
+impl std::fmt::Display for IconRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.icon, self.state)
+    }
+}
+
+pub struct Thumbnail {
+    pub icon_ref: IconRef,
+    /// Relative to the job's image directory, None if the state couldn't be found or rendered
+    pub image: Option<String>,
+}
+
+pub struct ChangedIcons {
+    /// Only on the changed tiles after the PR
+    pub introduced: Vec<Thumbnail>,
+    /// Only on the changed tiles before the PR
+    pub dropped: Vec<Thumbnail>,
+    /// How many didn't make the cut of [`MAX_THUMBNAILS`] each
+    pub omitted: usize,
+}
+
+fn prefab_icon(objtree: &ObjectTree, prefab: &dmm::Prefab) -> Option<IconRef> {
+    let ty = objtree.find(&prefab.path)?;
+    let var = |name: &str| {
+        prefab
+            .vars
+            .get(name)
+            .or_else(|| ty.get_value(name).and_then(|value| value.constant.as_ref()))
+    };
+    let icon = match var("icon")? {
+        Constant::Resource(path) | Constant::String(path) => path.to_string(),
+        _ => return None,
+    };
+    let state = match var("icon_state") {
+        Some(Constant::String(state)) => state.to_string(),
+        _ => String::new(),
+    };
+    Some(IconRef { icon, state })
+}
+
+/// Icons on tiles that differ between the maps, split into (only after, only before).
+/// Each side is resolved against its own object tree, a type's default icon may be what changed.
+pub fn changed_icon_refs(
+    (base_tree, base_map): (&ObjectTree, &dmm::Map),
+    (head_tree, head_map): (&ObjectTree, &dmm::Map),
+) -> (BTreeSet<IconRef>, BTreeSet<IconRef>) {
+    let base_dims = base_map.dim_xyz();
+    let head_dims = head_map.dim_xyz();
+
+    let mut before = BTreeSet::new();
+    let mut after = BTreeSet::new();
+    for z in 0..min(base_dims.2, head_dims.2) {
+        for y in 0..min(base_dims.1, head_dims.1) {
+            for x in 0..min(base_dims.0, head_dims.0) {
+                let base_tile = &base_map.dictionary[&base_map.grid[(z, base_dims.1 - y - 1, x)]];
+                let head_tile = &head_map.dictionary[&head_map.grid[(z, head_dims.1 - y - 1, x)]];
+                if same_tile(base_tile, head_tile) {
+                    continue;
+                }
+                before.extend(
+                    base_tile
+                        .iter()
+                        .filter_map(|prefab| prefab_icon(base_tree, prefab)),
+                );
+                after.extend(
+                    head_tile
+                        .iter()
+                        .filter_map(|prefab| prefab_icon(head_tree, prefab)),
+                );
+            }
+        }
+    }
+
+    let introduced = after.difference(&before).cloned().collect();
+    let dropped = before.difference(&after).cloned().collect();
+    (introduced, dropped)
+}
+
+/// Renders the first frame of each icon state into `directory`, which is `relative` to the job's image directory.
+/// Must be called with the branch the icons are from checked out, the icon cache reads them off disk.
+pub fn write_thumbnails(
+    context: &RenderingContext,
+    refs: impl IntoIterator<Item = IconRef>,
+    (directory, relative): (&Path, &str),
+    budget: &OutputBudget,
+) -> Vec<Thumbnail> {
+    let conf = CONFIG.get().unwrap();
+    refs.into_iter()
+        .take(MAX_THUMBNAILS)
+        .enumerate()
+        .map(|(idx, icon_ref)| {
+            if budget.exceeded() {
+                return Thumbnail {
+                    icon_ref,
+                    image: None,
+                };
+            }
+            let image = context.icon_thumbnail(&icon_ref).and_then(|image| {
+                let png = encode_rgba(
+                    image.width,
+                    image.height,
+                    image.data.as_slice()?,
+                    conf.png_compression,
+                )
+                .map_err(|e| log::warn!("Encoding thumbnail of {icon_ref}: {e:?}"))
+                .ok()?;
+                std::fs::create_dir_all(directory).ok()?;
+                let path = directory.join(format!("{idx}.png"));
+                write_with_retries(&path, conf.image_write_retries, |path| {
+                    Ok(std::fs::write(path, &png)?)
+                })
+                .ok()?;
+                budget.add(png.len() as u64);
+                Some(format!("{relative}/{idx}.png"))
+            });
+            Thumbnail { icon_ref, image }
+        })
+        .collect()
+}
+
+impl ChangedIcons {
+    pub fn new(introduced: Vec<Thumbnail>, dropped: Vec<Thumbnail>, total: (usize, usize)) -> Self {
+        let omitted = total.0 + total.1 - introduced.len() - dropped.len();
+        Self {
+            introduced,
+            dropped,
+            omitted,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.introduced.is_empty() && self.dropped.is_empty()
+    }
+
+    pub fn to_markdown(&self, filename: &str, link_base: &str) -> String {
+        let list = |thumbnails: &[Thumbnail]| {
+            thumbnails
+                .iter()
+                .map(|thumbnail| match &thumbnail.image {
+                    Some(image) => {
+                        format!("![{0}]({link_base}/{image} \"{0}\")", thumbnail.icon_ref)
+                    }
+                    None => format!("`{}`", thumbnail.icon_ref),
+                })
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+
+        let mut section =
+            format!("<details>\n<summary>Icons on the changed tiles of {filename}</summary>\n\n");
+        if !self.introduced.is_empty() {
+            section.push_str(&format!("**Introduced:** {}\n\n", list(&self.introduced)));
+        }
+        if !self.dropped.is_empty() {
+            section.push_str(&format!("**No longer used:** {}\n\n", list(&self.dropped)));
+        }
+        if self.omitted > 0 {
+            section.push_str(&format!("...and {} more\n\n", self.omitted));
+        }
+        section.push_str("</details>\n\n");
+        section
+    }
+}
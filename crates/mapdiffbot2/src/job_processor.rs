@@ -1,33 +1,131 @@
 use diffbot_lib::log;
 use eyre::{Context, Result};
+use once_cell::sync::OnceCell;
 use path_absolutize::Absolutize;
 use rayon::prelude::*;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::path::Path;
-use std::path::PathBuf;
+use std::time::Instant;
 
 use super::git_operations::{
-    clean_up_references, clone_repo, fetch_and_get_branches, with_checkout,
+    clean_up_references, clone_repo, commits_touching, fetch_and_get_branches, find_renames,
+    run_token, with_checkout, CommitSummary, InternalBranches,
 };
 
 use crate::rendering::{
-    get_map_diff_bounding_boxes, load_maps, load_maps_with_whole_map_regions,
-    render_diffs_for_directory, render_map_regions, MapWithRegions, MapsWithRegions,
-    RenderingContext,
+    count_changed_tiles, get_map_diff_bounding_boxes, load_maps, load_maps_with_whole_map_regions,
+    pick_environment, region_image, render_diffs_for_directory, render_map_regions,
+    write_contact_sheet, write_zoom_inset, BlankRenders, MapWithRegions, MapsWithRegions,
+    RenderPasses, RenderingContext,
 };
 
-use crate::CONFIG;
+use crate::context_cache;
+use crate::dedupe::dedupe_images;
+use crate::icon_refs::{changed_icon_refs, write_thumbnails, ChangedIcons};
+use crate::metrics;
+use crate::repo_cache;
+use crate::report::{write_html_report, REPORT_FILENAME};
+use crate::results::{job_result, latest_result_path, write_result_json, RESULT_FILENAME};
+use crate::var_edits::{offset_only_changes, offsets_markdown, var_changes, var_changes_markdown};
+use crate::{RenderPassOverride, CONFIG};
 
 use diffbot_lib::{
+    github::github_api::installation_token,
     github::github_types::{
         Branch, ChangeType, CheckOutputBuilder, CheckOutputs, FileDiff, Output,
     },
-    job::types::Job,
+    github::graphql::warn_truncated_files,
+    job::{
+        budget::OutputBudget, in_flight, render_limiter::RenderLimiter, resources::ResourceMeter,
+        types::Job,
+    },
+    toolchain,
+    upload::{content_hash, RetryQueue, UPLOAD_FAILED},
 };
 
-struct RenderedMaps {
-    added_maps: Vec<MapWithRegions>,
-    removed_maps: Vec<MapWithRegions>,
-    modified_maps: MapsWithRegions,
+pub struct RenderedMaps {
+    pub added_maps: Vec<Result<MapWithRegions>>,
+    pub removed_maps: Vec<Result<MapWithRegions>>,
+    pub modified_maps: MapsWithRegions,
+    /// The head's .dme, relative to the repo
+    pub environment: String,
+    /// Images, relative to the job's image directory, that rendered (nearly) empty
+    pub blank_renders: HashSet<String>,
+    /// Everything dmm_tools complained about while parsing and rendering, deduplicated
+    pub warnings: BTreeSet<String>,
+    /// Per modified map, empty unless `icon_thumbnails` is on
+    pub changed_icons: Vec<Option<ChangedIcons>>,
+    /// The PR's commits that touched each map by filename, empty unless `map_commits` is on
+    pub commits: HashMap<String, Vec<CommitSummary>>,
+    /// Zoomed insets of small changes, relative to the job's image directory
+    pub zoom_insets: HashSet<String>,
+    /// Contact sheets of modified maps with several changed z-levels, relative to the job's image directory
+    pub contact_sheets: HashSet<String>,
+    /// Renamed maps diffed against their old path, new path to old path
+    pub renamed_from: HashMap<String, String>,
+    /// Images that are copies of another one, to the one they're a copy of, see `dedupe`
+    pub duplicates: HashMap<String, String>,
+}
+
+/// Replaces every map whose render failed with the error, so it's reported on its own
+fn fail_unrendered_maps(
+    maps: &mut [Result<MapWithRegions>],
+    results: Vec<Result<()>>,
+    context: &'static str,
+) {
+    for (map, result) in maps.iter_mut().zip(results.into_iter()) {
+        if let Err(e) = result {
+            log::error!("{context}: {e:?}");
+            *map = Err(e.wrap_err(context));
+        }
+    }
+}
+
+/// Diffing gets its own pool when `concurrency.diff_threads` is set, so it can't oversubscribe the global one
+fn diff_pool() -> Option<&'static rayon::ThreadPool> {
+    static POOL: OnceCell<Option<rayon::ThreadPool>> = OnceCell::new();
+    POOL.get_or_init(|| {
+        let threads = CONFIG.get().unwrap().concurrency.diff_threads?;
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|idx| format!("diff-{idx}"))
+            .build()
+            .map_err(|e| log::error!("Failed to build diff thread pool, using the global one: {e}"))
+            .ok()
+    })
+    .as_ref()
+}
+
+/// Maps render in a pool of their own when `concurrency.render_threads` is set, shared by every job so a few big PRs at once can't oversubscribe the cores
+fn render_pool() -> Option<&'static rayon::ThreadPool> {
+    static POOL: OnceCell<Option<rayon::ThreadPool>> = OnceCell::new();
+    POOL.get_or_init(|| {
+        let threads = CONFIG.get().unwrap().concurrency.render_threads?;
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|idx| format!("render-{idx}"))
+            .build()
+            .map_err(|e| {
+                log::error!("Failed to build render thread pool, using the global one: {e}")
+            })
+            .ok()
+    })
+    .as_ref()
+}
+
+/// Writes that failed in `work` are retried once it's done, off the pool, so no render thread sits out a backoff
+fn in_render_pool<T: Send>(budget: &OutputBudget, work: impl FnOnce(&RetryQueue) -> T + Send) -> T {
+    let retries = RetryQueue::default();
+    let result = match render_pool() {
+        Some(pool) => pool.install(|| work(&retries)),
+        None => work(&retries),
+    };
+    for (_, written) in retries.retry(CONFIG.get().unwrap().image_write_retries) {
+        if let Ok(size) = written {
+            budget.add(size);
+        }
+    }
+    result
 }
 
 fn render(
@@ -36,7 +134,10 @@ fn render(
     (added_files, modified_files, removed_files): (&[&FileDiff], &[&FileDiff], &[&FileDiff]),
     (repo, base_branch_name): (&git2::Repository, &str),
     (repo_dir, out_dir): (&Path, &Path),
-    pull_request_number: u64,
+    branches: &InternalBranches,
+    budget: &OutputBudget,
+    token: Option<&str>,
+    (environment, pass_overrides): (Option<&str>, &[RenderPassOverride]),
     // feel like this is a bit of a hack but it works for now
 ) -> Result<RenderedMaps> {
     log::trace!(
@@ -45,32 +146,60 @@ fn render(
         head
     );
 
-    let pull_branch = format!("mdb-{}-{}", base.sha, head.sha);
-    let head_branch = format!("pull/{pull_request_number}/head:{pull_branch}");
-
-    let (base_branch, head_branch) =
-        fetch_and_get_branches(&base.sha, &head.sha, repo, &head_branch, base_branch_name)
-            .context("Fetching and constructing diffs")?;
+    let (base_branch, head_branch) = fetch_and_get_branches(
+        &base.sha,
+        &head.sha,
+        repo,
+        branches,
+        base_branch_name,
+        CONFIG.get().unwrap().merge_base_diff,
+        token,
+    )
+    .context("Fetching and constructing diffs")?;
 
     let path = repo_dir.absolutize().context("Making repo path absolute")?;
 
-    let base_context = with_checkout(&base_branch, repo, || RenderingContext::new(&path))
-        .context("Parsing base")?;
+    let all_files = [added_files, modified_files, removed_files].concat();
+
+    // Only informational, a failure here shouldn't cost the renders
+    let commits = if CONFIG.get().unwrap().map_commits {
+        let filenames = all_files
+            .iter()
+            .map(|file| file.filename.as_str())
+            .collect::<Vec<_>>();
+        base_branch
+            .peel_to_commit()
+            .and_then(|base| Ok((base.id(), head_branch.peel_to_commit()?.id())))
+            .map_err(eyre::Report::from)
+            .and_then(|(base, head)| commits_touching(repo, base, head, &filenames))
+            .map_err(|e| log::warn!("Failed to find the commits touching each map: {e:?}"))
+            .unwrap_or_default()
+    } else {
+        Default::default()
+    };
+    let new_context = |branch: &git2::Reference| -> Result<std::sync::Arc<RenderingContext>> {
+        let commit = branch.peel_to_commit()?.id();
+        let environment = pick_environment(&path, &all_files, environment);
+        context_cache::get_or_parse(
+            &path,
+            commit,
+            environment.as_deref(),
+            CONFIG.get().unwrap().cached_environments,
+            || RenderingContext::new(&path, environment.as_deref()),
+        )
+    };
+
+    let base_context =
+        with_checkout(&base_branch, repo, || new_context(&base_branch)).context("Parsing base")?;
 
-    let head_context = with_checkout(&head_branch, repo, || RenderingContext::new(&path))
-        .context("Parsing head")?;
+    let head_context =
+        with_checkout(&head_branch, repo, || new_context(&head_branch)).context("Parsing head")?;
 
-    let base_render_passes = dmm_tools::render_passes::configure(
-        base_context.map_config(),
-        "",
-        "hide-space,hide-invisible,random",
-    );
+    let lighting_passes = &CONFIG.get().unwrap().lighting_passes;
+    let base_render_passes = RenderPasses::new(&base_context, lighting_passes, pass_overrides);
+    let head_render_passes = RenderPasses::new(&head_context, lighting_passes, pass_overrides);
 
-    let head_render_passes = dmm_tools::render_passes::configure(
-        head_context.map_config(),
-        "",
-        "hide-space,hide-invisible,random",
-    );
+    let blank_renders = BlankRenders::default();
 
     //do removed maps
     let removed_directory = format!("{}/r", out_dir.display());
@@ -78,17 +207,21 @@ fn render(
     let removed_errors = Default::default();
 
     let removed_maps = with_checkout(&base_branch, repo, || {
-        let maps = load_maps_with_whole_map_regions(removed_files, &path)
-            .context("Loading removed maps")?;
-        render_map_regions(
-            &base_context,
-            &maps.iter().collect::<Vec<_>>(),
-            &base_render_passes,
-            removed_directory,
-            "removed.png",
-            &removed_errors,
-        )
-        .context("Rendering removed maps")?;
+        let mut maps = load_maps_with_whole_map_regions(removed_files, &path);
+        let results = in_render_pool(budget, |retries| {
+            render_map_regions(
+                &base_context,
+                &maps.iter().map(|map| map.as_ref().ok()).collect::<Vec<_>>(),
+                &base_render_passes.for_files(removed_files),
+                removed_directory,
+                "removed.png",
+                &removed_errors,
+                &blank_renders,
+                budget,
+                retries,
+            )
+        });
+        fail_unrendered_maps(&mut maps, results, "Rendering removed map");
         Ok(maps)
     })?;
 
@@ -98,142 +231,772 @@ fn render(
     let added_errors = Default::default();
 
     let added_maps = with_checkout(&head_branch, repo, || {
-        let maps =
-            load_maps_with_whole_map_regions(added_files, &path).context("Loading added maps")?;
-        render_map_regions(
-            &head_context,
-            &maps.iter().collect::<Vec<_>>(),
-            &head_render_passes,
-            added_directory,
-            "added.png",
-            &added_errors,
-        )
-        .context("Rendering added maps")?;
+        let mut maps = load_maps_with_whole_map_regions(added_files, &path);
+        let results = in_render_pool(budget, |retries| {
+            render_map_regions(
+                &head_context,
+                &maps.iter().map(|map| map.as_ref().ok()).collect::<Vec<_>>(),
+                &head_render_passes.for_files(added_files),
+                added_directory,
+                "added.png",
+                &added_errors,
+                &blank_renders,
+                budget,
+                retries,
+            )
+        });
+        fail_unrendered_maps(&mut maps, results, "Rendering added map");
         Ok(maps)
     })
     .context("Rendering modified after and added maps")?;
 
     //do modified maps
-    let base_maps = with_checkout(&base_branch, repo, || Ok(load_maps(modified_files, &path)))
-        .context("Loading base maps")?;
+    // Renamed maps are only in here with rename_similarity set, they're diffed against their old path
+    let rename_similarity = CONFIG.get().unwrap().rename_similarity.unwrap_or(50);
+    let renamed_from = if modified_files
+        .iter()
+        .any(|file| file.status == ChangeType::Renamed)
+    {
+        find_renames(repo, &base_branch, &head_branch, rename_similarity)
+            .context("Finding renamed maps")?
+    } else {
+        HashMap::new()
+    };
+    let before_files = modified_files
+        .iter()
+        .map(|file| FileDiff {
+            filename: renamed_from
+                .get(&file.filename)
+                .unwrap_or(&file.filename)
+                .clone(),
+            status: file.status,
+        })
+        .collect::<Vec<_>>();
+
+    let base_maps = with_checkout(&base_branch, repo, || {
+        Ok(load_maps(&before_files.iter().collect::<Vec<_>>(), &path))
+    })
+    .context("Loading base maps")?;
     let head_maps = with_checkout(&head_branch, repo, || Ok(load_maps(modified_files, &path)))
         .context("Loading head maps")?;
 
-    let modified_maps = get_map_diff_bounding_boxes(base_maps, head_maps)?;
+    let mut modified_maps = get_map_diff_bounding_boxes(base_maps, head_maps);
+    for (idx, file) in modified_files.iter().enumerate() {
+        if file.status == ChangeType::Renamed && !renamed_from.contains_key(&file.filename) {
+            modified_maps.fail(
+                idx,
+                eyre::anyhow!(
+                    "Renamed with too many changes to diff against the old map, it's under {rename_similarity}% similar"
+                ),
+            );
+        }
+    }
 
     let modified_directory = format!("{}/m", out_dir.display());
     let modified_directory = Path::new(&modified_directory);
     let modified_before_errors = Default::default();
     let modified_after_errors = Default::default();
 
-    with_checkout(&base_branch, repo, || {
-        render_map_regions(
-            &base_context,
-            modified_maps
-                .befores
-                .iter()
-                .filter_map(|res| res.as_ref().ok())
-                .collect::<Vec<_>>()
-                .as_slice(),
-            &head_render_passes,
-            modified_directory,
-            "before.png",
-            &modified_before_errors,
-        )
-        .context("Rendering modified before maps")?;
-        Ok(())
+    let before_results = with_checkout(&base_branch, repo, || {
+        Ok(in_render_pool(budget, |retries| {
+            render_map_regions(
+                &base_context,
+                modified_maps
+                    .befores
+                    .iter()
+                    .map(|res| res.as_ref().ok())
+                    .collect::<Vec<_>>()
+                    .as_slice(),
+                &head_render_passes.for_files(modified_files),
+                modified_directory,
+                "before.png",
+                &modified_before_errors,
+                &blank_renders,
+                budget,
+                retries,
+            )
+        }))
     })?;
 
-    with_checkout(&head_branch, repo, || {
-        render_map_regions(
-            &head_context,
-            modified_maps
-                .afters
-                .iter()
-                .filter_map(|opt| opt.as_ref())
-                .collect::<Vec<_>>()
-                .as_slice(),
-            &head_render_passes,
-            modified_directory,
-            "after.png",
-            &modified_after_errors,
-        )
-        .context("Rendering modified after maps")?;
-        Ok(())
+    let after_results = with_checkout(&head_branch, repo, || {
+        Ok(in_render_pool(budget, |retries| {
+            render_map_regions(
+                &head_context,
+                modified_maps
+                    .afters
+                    .iter()
+                    .map(|opt| opt.as_ref())
+                    .collect::<Vec<_>>()
+                    .as_slice(),
+                &head_render_passes.for_files(modified_files),
+                modified_directory,
+                "after.png",
+                &modified_after_errors,
+                &blank_renders,
+                budget,
+                retries,
+            )
+        }))
     })?;
 
-    (0..modified_files.len()).into_par_iter().for_each(|i| {
-        render_diffs_for_directory(modified_directory.join(i.to_string()));
-    });
+    for (idx, (before, after)) in before_results
+        .into_iter()
+        .zip(after_results.into_iter())
+        .enumerate()
+    {
+        if let Err(e) = before {
+            modified_maps.fail(idx, e.wrap_err("Rendering modified before map"));
+        } else if let Err(e) = after {
+            modified_maps.fail(idx, e.wrap_err("Rendering modified after map"));
+        }
+    }
+
+    let diff_retries = RetryQueue::default();
+    let render_diffs = || {
+        (0..modified_files.len())
+            .into_par_iter()
+            .filter(|i| modified_maps.befores[*i].is_ok())
+            .for_each(|i| {
+                let (count, bytes) = render_diffs_for_directory(
+                    modified_directory.join(i.to_string()),
+                    &diff_retries,
+                );
+                budget.add_images(count);
+                budget.add(bytes);
+            });
+    };
+    match diff_pool() {
+        Some(pool) => pool.install(render_diffs),
+        None => render_diffs(),
+    }
+    // Same as in_render_pool, retried once the diff pool's done with them
+    for (_, written) in diff_retries.retry(CONFIG.get().unwrap().image_write_retries) {
+        if let Ok(size) = written {
+            budget.add_images(1);
+            budget.add(size);
+        }
+    }
+
+    // A one tile fix is next to invisible in a region render, so small changes get a zoomed in copy too
+    let mut zoom_insets = HashSet::new();
+    if let Some(max_tiles) = CONFIG.get().unwrap().zoom_inset_max_tiles {
+        for (idx, (before, after)) in modified_maps
+            .befores
+            .iter()
+            .zip(modified_maps.afters.iter())
+            .enumerate()
+        {
+            let (Ok(before), Some(after)) = (before, after) else {
+                continue;
+            };
+            for (level, cluster, _) in before.iter_regions() {
+                if budget.exceeded()
+                    || count_changed_tiles(&before.map, &after.map, level) > max_tiles
+                {
+                    continue;
+                }
+                let image = region_image(level, cluster);
+                match write_zoom_inset(&modified_directory.join(idx.to_string()), &image) {
+                    Ok(Some(size)) => {
+                        budget.add(size);
+                        zoom_insets.insert(format!("m/{idx}/{image}-zoom.png"));
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        log::warn!("Failed to write zoomed inset of map {idx}:{image}: {e:?}")
+                    }
+                }
+            }
+        }
+    }
+
+    // One look at every changed level, a map with only one would just get its diff again
+    let mut contact_sheets = HashSet::new();
+    if CONFIG.get().unwrap().contact_sheets {
+        for (idx, before) in modified_maps.befores.iter().enumerate() {
+            let Ok(before) = before else {
+                continue;
+            };
+            if budget.exceeded() || before.iter_levels().nth(1).is_none() {
+                continue;
+            }
+            let regions = before
+                .iter_regions()
+                .map(|(level, cluster, _)| {
+                    let label = match cluster {
+                        Some(cluster) => format!("Z{} ({})", level + 1, cluster + 1),
+                        None => format!("Z{}", level + 1),
+                    };
+                    (label, region_image(level, cluster))
+                })
+                .collect::<Vec<_>>();
+            match write_contact_sheet(&modified_directory.join(idx.to_string()), &regions) {
+                Ok(size) => {
+                    budget.add(size);
+                    contact_sheets.insert(format!("m/{idx}/overview.png"));
+                }
+                Err(e) => log::warn!("Failed to write contact sheet of map {idx}: {e:?}"),
+            }
+        }
+    }
+
+    // Opt in, it's another pass over every tile plus a render per icon state
+    let mut changed_icons = vec![];
+    if CONFIG.get().unwrap().icon_thumbnails {
+        let refs = modified_maps
+            .befores
+            .iter()
+            .zip(modified_maps.afters.iter())
+            .map(|(before, after)| match (before, after) {
+                (Ok(before), Some(after)) => Some(changed_icon_refs(
+                    (base_context.obj_tree(), &before.map),
+                    (head_context.obj_tree(), &after.map),
+                )),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let dropped = with_checkout(&base_branch, repo, || {
+            Ok(refs
+                .iter()
+                .enumerate()
+                .map(|(idx, refs)| {
+                    refs.as_ref().map(|(_, dropped)| {
+                        write_thumbnails(
+                            &base_context,
+                            dropped.iter().cloned(),
+                            (
+                                &modified_directory.join(format!("{idx}/icons/dropped")),
+                                &format!("m/{idx}/icons/dropped"),
+                            ),
+                            budget,
+                        )
+                    })
+                })
+                .collect::<Vec<_>>())
+        })?;
+        let introduced = with_checkout(&head_branch, repo, || {
+            Ok(refs
+                .iter()
+                .enumerate()
+                .map(|(idx, refs)| {
+                    refs.as_ref().map(|(introduced, _)| {
+                        write_thumbnails(
+                            &head_context,
+                            introduced.iter().cloned(),
+                            (
+                                &modified_directory.join(format!("{idx}/icons/introduced")),
+                                &format!("m/{idx}/icons/introduced"),
+                            ),
+                            budget,
+                        )
+                    })
+                })
+                .collect::<Vec<_>>())
+        })?;
+
+        changed_icons = refs
+            .iter()
+            .zip(introduced)
+            .zip(dropped)
+            .map(|((refs, introduced), dropped)| {
+                let (introduced_refs, dropped_refs) = refs.as_ref()?;
+                Some(ChangedIcons::new(
+                    introduced?,
+                    dropped?,
+                    (introduced_refs.len(), dropped_refs.len()),
+                ))
+            })
+            .collect();
+    }
+
+    let warnings: BTreeSet<String> = [
+        &removed_errors,
+        &added_errors,
+        &modified_before_errors,
+        &modified_after_errors,
+    ]
+    .into_iter()
+    .flat_map(|errors| errors.read().unwrap().iter().cloned().collect::<Vec<_>>())
+    .chain(base_context.parse_errors().iter().cloned())
+    .chain(head_context.parse_errors().iter().cloned())
+    .collect();
+    for warning in &warnings {
+        log::debug!("Rendering warning: {warning}");
+    }
 
     Ok(RenderedMaps {
         added_maps,
         modified_maps,
         removed_maps,
+        environment: head_context
+            .environment()
+            .strip_prefix(&path)
+            .unwrap_or(head_context.environment())
+            .display()
+            .to_string(),
+        blank_renders: blank_renders
+            .into_inner()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .filter_map(|image| image.strip_prefix(out_dir).ok())
+            .map(|image| image.to_string_lossy().into_owned())
+            .collect(),
+        warnings,
+        changed_icons,
+        commits,
+        zoom_insets,
+        contact_sheets,
+        renamed_from,
+        duplicates: dedupe_images(out_dir),
     })
 }
 
+/// Which of the PR's commits touched each map, in the order the maps are listed above
+fn commits_section(
+    commits: &HashMap<String, Vec<CommitSummary>>,
+    filenames: &[&str],
+) -> Option<String> {
+    let lines = filenames
+        .iter()
+        .filter_map(|filename| {
+            let commits = commits.get(*filename)?;
+            let list = commits
+                .iter()
+                .map(|(sha, summary)| format!("`{sha}` {summary}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            Some(format!("- `{filename}`: {list}"))
+        })
+        .collect::<Vec<_>>();
+    if lines.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "\n\n<details>\n<summary>Commits that touched each map</summary>\n\n{}\n\n</details>\n\n",
+        lines.join("\n")
+    ))
+}
+
+/// Past this the section would drown out the renders, the full list is logged at debug level
+const MAX_LISTED_WARNINGS: usize = 100;
+
+fn warnings_section(warnings: &BTreeSet<String>) -> Option<String> {
+    if warnings.is_empty() {
+        return None;
+    }
+    let mut listed = warnings
+        .iter()
+        .take(MAX_LISTED_WARNINGS)
+        .map(|warning| format!("- `{}`", warning.replace('`', "'")))
+        .collect::<Vec<_>>();
+    if warnings.len() > MAX_LISTED_WARNINGS {
+        listed.push(format!(
+            "- ...and {} more",
+            warnings.len() - MAX_LISTED_WARNINGS
+        ));
+    }
+    Some(format!(
+        "\n\n<details>\n<summary>Rendering warnings ({})</summary>\n\n{}\n\n</details>\n\n",
+        warnings.len(),
+        listed.join("\n")
+    ))
+}
+
+/// Github's image proxy and most browsers give up on images this large, they show up cropped or not at all
+const GITHUB_MAX_INLINE_SIDE: u32 = 16_384;
+const GITHUB_MAX_INLINE_PIXELS: u64 = 50_000_000;
+
+pub fn fits_github_inline(width: u32, height: u32) -> bool {
+    width <= GITHUB_MAX_INLINE_SIDE
+        && height <= GITHUB_MAX_INLINE_SIDE
+        && width as u64 * height as u64 <= GITHUB_MAX_INLINE_PIXELS
+}
+
+/// Paths, relative to the job's image directory, of the images that get embedded when there's a `limit`.
+/// Levels with the most changed tiles go first, whole added and removed maps after those.
+/// A level's images are kept together, half of a before/after/diff row isn't much use.
+fn inline_images(maps: &RenderedMaps, limit: Option<usize>) -> Option<HashSet<String>> {
+    let limit = limit?;
+
+    let mut candidates: Vec<(usize, Vec<String>)> = Vec::new();
+    for (file_index, (before, after)) in maps
+        .modified_maps
+        .befores
+        .iter()
+        .zip(maps.modified_maps.afters.iter())
+        .enumerate()
+    {
+        let (Ok(before), Some(after)) = (before, after) else {
+            continue;
+        };
+        for (level, cluster, _) in before.iter_regions() {
+            let image = region_image(level, cluster);
+            candidates.push((
+                count_changed_tiles(&before.map, &after.map, level),
+                ["before", "after", "diff"]
+                    .map(|kind| format!("m/{file_index}/{image}-{kind}.png"))
+                    .to_vec(),
+            ));
+        }
+    }
+    for (prefix, suffix, results) in [
+        ("a", "added", &maps.added_maps),
+        ("r", "removed", &maps.removed_maps),
+    ] {
+        for (file_index, map) in results.iter().enumerate() {
+            let Ok(map) = map else {
+                continue;
+            };
+            for (level, _) in map.iter_levels() {
+                candidates.push((
+                    0,
+                    vec![format!("{prefix}/{file_index}/{level}-{suffix}.png")],
+                ));
+            }
+        }
+    }
+
+    // Stable, so added and removed maps keep their order behind the modified ones
+    candidates.sort_by(|a, b| b.0.cmp(&a.0));
+
+    let mut inline = HashSet::new();
+    for (_, images) in candidates {
+        if inline.len() + images.len() > limit {
+            break;
+        }
+        inline.extend(images);
+    }
+    Some(inline)
+}
+
+/// Markdown for an image, embedded if Github will actually display it and it's within the inline limit, otherwise a prominent link
+fn embed_image(link: &str, path: &Path, inline: bool) -> String {
+    match image::image_dimensions(path) {
+        Err(_) if !path.exists() => UPLOAD_FAILED.to_owned(),
+        _ if !inline => format!("**[Not embedded to keep the page light. Click here to view it.]({link})**"),
+        Ok((width, height)) if !fits_github_inline(width, height) => format!(
+            "**[{width}x{height} image, too large for Github to display inline. Click here to view it.]({link})**"
+        ),
+        _ => format!("![If the image doesn't load, use the raw link above]({link})"),
+    }
+}
+
+/// Spells out how the levels of a map that gained or lost some were paired up
+fn z_level_note(filename: &str, map: &MapWithRegions) -> String {
+    let mut note = format!(
+        "**{filename}: z-levels are compared by position, levels after an added or removed one may be paired with the wrong one.**\n\n"
+    );
+    for (level, base_level) in &map.moved_levels {
+        let diffed = map
+            .bounding_boxes
+            .get(*level)
+            .is_some_and(|bbox| bbox.is_some());
+        note.push_str(&format!(
+            "- Z-level {} is identical to old z-level {}, so it was probably moved{}\n",
+            level + 1,
+            base_level + 1,
+            if diffed { "" } else { " and isn't diffed" }
+        ));
+    }
+    if !map.moved_levels.is_empty() {
+        note.push('\n');
+    }
+    note
+}
+
 fn generate_finished_output<P: AsRef<Path>>(
     added_files: &[&FileDiff],
     modified_files: &[&FileDiff],
     removed_files: &[&FileDiff],
     file_directory: &P,
     maps: RenderedMaps,
+    budget: &OutputBudget,
+    result_json: bool,
+    html_report: bool,
+    started: Instant,
 ) -> Result<CheckOutputs> {
+    // Maps that were touched without anything on them changing get no regions, let the runner say so instead of posting an empty list
+    let nothing_changed = added_files.is_empty()
+        && removed_files.is_empty()
+        && maps.renamed_from.is_empty()
+        && maps
+            .modified_maps
+            .befores
+            .iter()
+            .all(|map| matches!(map, Ok(map) if map.iter_levels().next().is_none()))
+        && maps
+            .modified_maps
+            .afters
+            .iter()
+            .flatten()
+            .all(|map| map.iter_levels().next().is_none());
+    if nothing_changed {
+        return Ok(vec![]);
+    }
+
     let conf = CONFIG.get().unwrap();
     let file_url = &conf.web.file_hosting_url;
     let non_abs_directory = file_directory.as_ref().to_string_lossy();
 
     let mut builder = CheckOutputBuilder::new(
     "Map renderings",
-    "*Please file any issues [here](https://github.com/spacestation13/BYONDDiffBots/issues).*\n\n*Github may fail to render some images, appearing as cropped on large map changes. Please use the raw links in this case.*\n\nMaps with diff:",
+    "*Please file any issues [here](https://github.com/spacestation13/BYONDDiffBots/issues).*\n\nMaps with diff:",
     );
 
     let link_base = format!("{file_url}/{non_abs_directory}");
+    let path_base = file_directory.as_ref();
+
+    let inline_images = inline_images(&maps, conf.max_inline_images);
+    let image_hash =
+        |image: &str| content_hash(&path_base.join(image)).unwrap_or_else(|| "missing".to_owned());
+    // Copies link to the original, so the same image isn't downloaded again for every place it shows up
+    let link_to = |image: &str| {
+        let image = maps.duplicates.get(image).map_or(image, String::as_str);
+        format!("{link_base}/{image}")
+    };
+    let is_inline = |image: &str| {
+        inline_images
+            .as_ref()
+            .map_or(true, |inline| inline.contains(image))
+    };
+
+    let blank_warning = |name: &str, images: &[(&str, String)]| {
+        let blank = images
+            .iter()
+            .filter(|(_, image)| maps.blank_renders.contains(image))
+            .map(|(kind, _)| *kind)
+            .collect::<Vec<_>>();
+        (!blank.is_empty()).then(|| {
+            format!(
+                "**{name}: render may have failed — image appears empty ({})**\n\n",
+                blank.join(", ")
+            )
+        })
+    };
+
+    if html_report {
+        builder.add_text(&format!(
+            "### [Open the full report, with every render on one page]({link_base}/{REPORT_FILENAME})\n\n"
+        ));
+    }
+
+    builder.add_text(&format!("Rendered against `{}`\n\n", maps.environment));
+
+    if !conf.lighting_passes.is_empty() {
+        builder.add_text(&format!(
+            "Lighting included, via the `{}` render pass(es)\n\n",
+            conf.lighting_passes.join("`, `")
+        ));
+    }
+
+    if result_json {
+        builder.add_text(&format!(
+            "[Machine readable results]({link_base}/{RESULT_FILENAME})\n\n"
+        ));
+    }
 
     // Those are CPU bound but parallelizing would require builder to be thread safe and it's probably not worth the overhead
     added_files
         .iter()
         .zip(maps.added_maps.iter())
         .enumerate()
-        .for_each(|(file_index, (file, map))| {
-            map.iter_levels().for_each(|(level, _)| {
-                let link = format!("{link_base}/a/{file_index}/{level}-added.png");
-                let name = format!("{}:{}", file.filename, level + 1);
+        .for_each(|(file_index, (file, map))| match map {
+            Ok(map) => {
+                map.iter_levels().for_each(|(level, _)| {
+                    let image = format!("a/{file_index}/{level}-added.png");
+                    let link = link_to(&image);
+                    let name = format!("{}:{}", file.filename, level + 1);
+
+                    if let Some(warning) = blank_warning(&name, &[("added", image.clone())]) {
+                        builder.add_text(&warning);
+                    }
 
+                    builder.add_text(&format!(
+                        include_str!("../templates/diff_template_add.txt"),
+                        filename = name,
+                        image_embed =
+                            embed_image(&link, &path_base.join(&image), is_inline(&image)),
+                        image_hash = image_hash(&image),
+                        image_link = link
+                    ));
+                });
+            }
+            Err(e) => {
+                let error = format!("{e:?}");
                 builder.add_text(&format!(
-                    include_str!("../templates/diff_template_add.txt"),
-                    filename = name,
-                    image_link = link
+                    include_str!("../templates/diff_template_error.txt"),
+                    status = "ADDED",
+                    filename = file.filename,
+                    error = error,
                 ));
-            });
+            }
         });
 
     modified_files
         .iter()
-        .zip(maps.modified_maps.befores.iter())
+        .zip(
+            maps.modified_maps
+                .befores
+                .iter()
+                .zip(maps.modified_maps.afters.iter()),
+        )
         .enumerate()
-        .for_each(|(file_index, (file, map))| match map {
+        .for_each(|(file_index, (file, (map, after)))| match map {
             Ok(map) => {
-                map.iter_levels().for_each(|(level, region)| {
-                    let link = format!("{link_base}/m/{file_index}/{level}");
-                    let name = format!("{}:{}", file.filename, level + 1);
+                let renamed_from = maps.renamed_from.get(&file.filename);
+                if let Some(old) = renamed_from {
+                    builder.add_text(&format!(
+                        "**{}: renamed from `{old}`{}**\n\n",
+                        file.filename,
+                        if map.iter_levels().next().is_none() {
+                            ", nothing else changed"
+                        } else {
+                            ", diffed against it"
+                        }
+                    ));
+                }
+                if let Some(after) = after {
+                    let (before_dims, after_dims) = (map.map.dim_xyz(), after.map.dim_xyz());
+                    if before_dims == after_dims
+                        && map.iter_levels().next().is_none()
+                        && renamed_from.is_none()
+                    {
+                        builder.add_text(&format!(
+                            "**{}: no content change (reordering only)**\n\n",
+                            file.filename
+                        ));
+                    }
+                    if before_dims != after_dims {
+                        builder.add_text(&format!(
+                            "**{} was resized from {}x{}x{} to {}x{}x{}, renders are aligned to the bottom left corner.**\n\n",
+                            file.filename,
+                            before_dims.0,
+                            before_dims.1,
+                            before_dims.2,
+                            after_dims.0,
+                            after_dims.1,
+                            after_dims.2,
+                        ));
+                    }
+                    if before_dims.2 != after_dims.2 {
+                        builder.add_text(&z_level_note(&file.filename, map));
+                    }
+                }
+                let overview = format!("m/{file_index}/overview.png");
+                if maps.contact_sheets.contains(&overview) {
+                    builder.add_text(&format!(
+                        "**{}, every changed z-level:**\n\n{}\n\n",
+                        file.filename,
+                        embed_image(
+                            &link_to(&overview),
+                            &path_base.join(&overview),
+                            is_inline(&overview)
+                        )
+                    ));
+                }
+                map.iter_regions().for_each(|(level, cluster, region)| {
+                    let image = format!("m/{file_index}/{}", region_image(level, cluster));
+                    let mut name = match cluster {
+                        Some(cluster) => {
+                            format!("{}:{} (region {})", file.filename, level + 1, cluster + 1)
+                        }
+                        None => format!("{}:{}", file.filename, level + 1),
+                    };
+                    if let Some((_, base_level)) =
+                        map.moved_levels.iter().find(|(moved, _)| *moved == level)
+                    {
+                        name.push_str(&format!(
+                            " (matches old z-level {}, compared by position)",
+                            base_level + 1
+                        ));
+                    }
+
+                    if let Some(warning) = blank_warning(
+                        &name,
+                        &["before", "after"].map(|kind| (kind, format!("{image}-{kind}.png"))),
+                    ) {
+                        builder.add_text(&warning);
+                    }
+                    let zoom_image = format!("{image}-zoom.png");
+                    let zoom = if maps.zoom_insets.contains(&zoom_image) {
+                        let link = link_to(&zoom_image);
+                        format!(
+                            "Zoomed in, old on the left and new on the right:\n\n{}\n\n",
+                            embed_image(&link, &path_base.join(&zoom_image), is_inline(&zoom_image))
+                        )
+                    } else {
+                        String::new()
+                    };
+                    let [before, after, diff] = ["before", "after", "diff"].map(|kind| {
+                        let file = format!("{image}-{kind}.png");
+                        let link = link_to(&file);
+                        let embed = embed_image(&link, &path_base.join(&file), is_inline(&file));
+                        (link, embed, image_hash(&file))
+                    });
 
-                    #[allow(clippy::format_in_format_args)]
                     builder.add_text(&format!(
                         include_str!("../templates/diff_template_mod.txt"),
                         bounds = region.to_string(),
                         filename = name,
-                        image_before_link = format!("{link}-before.png"),
-                        image_after_link = format!("{link}-after.png"),
-                        image_diff_link = format!("{link}-diff.png")
+                        image_before_link = before.0,
+                        image_after_link = after.0,
+                        image_diff_link = diff.0,
+                        image_before_embed = before.1,
+                        image_after_embed = after.1,
+                        image_diff_embed = diff.1,
+                        image_before_hash = before.2,
+                        image_after_hash = after.2,
+                        image_diff_hash = diff.2,
+                        zoom = zoom,
                     ));
                 });
+                // Only has boxes past the before's levels with render_added_z_levels on
+                if let Some(after) = after {
+                    after
+                        .iter_levels()
+                        .filter(|(level, _)| *level >= map.bounding_boxes.len())
+                        .for_each(|(level, _)| {
+                            let image = format!("m/{file_index}/{level}-after.png");
+                            let link = link_to(&image);
+                            let name = format!("{}:{} (new z-level)", file.filename, level + 1);
+
+                            if let Some(warning) = blank_warning(&name, &[("added", image.clone())])
+                            {
+                                builder.add_text(&warning);
+                            }
+
+                            builder.add_text(&format!(
+                                include_str!("../templates/diff_template_add.txt"),
+                                filename = name,
+                                image_embed =
+                                    embed_image(&link, &path_base.join(&image), is_inline(&image)),
+                                image_hash = image_hash(&image),
+                                image_link = link
+                            ));
+                        });
+                }
+                if let Some(Some(icons)) = maps.changed_icons.get(file_index) {
+                    if !icons.is_empty() {
+                        builder.add_text(&icons.to_markdown(&file.filename, &link_base));
+                    }
+                }
+                if let Some(after) = after {
+                    let nudged = offset_only_changes(&map.map, &after.map);
+                    if !nudged.is_empty() {
+                        builder.add_text(&offsets_markdown(&file.filename, &nudged));
+                    }
+                    let edited = var_changes(&map.map, &after.map);
+                    if !edited.is_empty() {
+                        builder.add_text(&var_changes_markdown(&file.filename, &edited));
+                    }
+                }
             }
             Err(e) => {
                 let error = format!("{e:?}");
                 builder.add_text(&format!(
                     include_str!("../templates/diff_template_error.txt"),
+                    status = "MODIFIED",
                     filename = file.filename,
                     error = error,
                 ));
@@ -244,23 +1007,73 @@ fn generate_finished_output<P: AsRef<Path>>(
         .iter()
         .zip(maps.removed_maps.iter())
         .enumerate()
-        .for_each(|(file_index, (file, map))| {
-            map.iter_levels().for_each(|(level, _)| {
-                let link = format!("{link_base}/r/{file_index}/{level}-removed.png");
-                let name = format!("{}:{}", file.filename, level + 1);
+        .for_each(|(file_index, (file, map))| match map {
+            Ok(map) => {
+                map.iter_levels().for_each(|(level, _)| {
+                    let image = format!("r/{file_index}/{level}-removed.png");
+                    let link = link_to(&image);
+                    let name = format!("{}:{}", file.filename, level + 1);
 
+                    if let Some(warning) = blank_warning(&name, &[("removed", image.clone())]) {
+                        builder.add_text(&warning);
+                    }
+
+                    builder.add_text(&format!(
+                        include_str!("../templates/diff_template_remove.txt"),
+                        filename = name,
+                        image_embed =
+                            embed_image(&link, &path_base.join(&image), is_inline(&image)),
+                        image_hash = image_hash(&image),
+                        image_link = link
+                    ));
+                });
+            }
+            Err(e) => {
+                let error = format!("{e:?}");
                 builder.add_text(&format!(
-                    include_str!("../templates/diff_template_remove.txt"),
-                    filename = name,
-                    image_link = link
+                    include_str!("../templates/diff_template_error.txt"),
+                    status = "REMOVED",
+                    filename = file.filename,
+                    error = error,
                 ));
-            });
+            }
         });
 
+    let all_filenames = added_files
+        .iter()
+        .chain(modified_files)
+        .chain(removed_files)
+        .map(|file| file.filename.as_str())
+        .collect::<Vec<_>>();
+    if let Some(section) = commits_section(&maps.commits, &all_filenames) {
+        builder.add_text(&section);
+    }
+
+    if let Some(section) = warnings_section(&maps.warnings) {
+        builder.add_text(&section);
+    }
+
+    if let Some(note) = budget.note() {
+        builder.add_text(&note);
+    }
+
+    builder.add_text(&budget.effort_note(started.elapsed()));
+    builder.add_text(&toolchain::footer());
+
     Ok(builder.build())
 }
 
-pub fn do_job(job: Job) -> Result<CheckOutputs> {
+/// Installation tokens expire after an hour, so a new one is minted for every git operation that talks to Github.
+/// Public repos fetch fine without one, so failing to get a token isn't fatal.
+async fn fresh_token(job: &Job) -> Option<String> {
+    installation_token(&job.installation)
+        .await
+        .map_err(|e| log::warn!("Failed to mint installation token: {e:?}"))
+        .ok()
+}
+
+pub fn do_job(job: Job, limiter: Option<&RenderLimiter>) -> Result<CheckOutputs> {
+    let started = Instant::now();
     log::trace!(
         "Starting Job on repo: {}, pr number: {}, base commit: {}, head commit: {}",
         job.repo.full_name(),
@@ -269,10 +1082,18 @@ pub fn do_job(job: Job) -> Result<CheckOutputs> {
         job.head.sha
     );
 
+    let mut meter = ResourceMeter::start(format!(
+        "[{}#{}] [{}]",
+        job.repo.full_name(),
+        job.pull_request,
+        job.check_run.id()
+    ));
+    meter.add_detail(format!("{} maps", job.files.len()));
+
     let base = &job.base;
     let head = &job.head;
     let repo = format!("https://github.com/{}", job.repo.full_name());
-    let repo_dir: PathBuf = ["./repos/", &job.repo.full_name()].iter().collect();
+    let repo_dir = CONFIG.get().unwrap().repos_dir.join(job.repo.full_name());
 
     let handle = actix_web::rt::Runtime::new()?;
 
@@ -287,10 +1108,16 @@ pub fn do_job(job: Job) -> Result<CheckOutputs> {
                 };
                 let _ = job.check_run.set_output(output).await; // we don't really care if updating the job fails, just continue
             });
-        clone_repo(&repo, &repo_dir).context("Cloning repo")?;
+        let token = handle.block_on(fresh_token(&job));
+        clone_repo(&repo, &repo_dir, token.as_deref()).context("Cloning repo")?;
     }
 
-    let non_abs_directory = format!("images/{}/{}", job.repo.id, job.check_run.id());
+    repo_cache::mark_used(&repo_dir);
+
+    let non_abs_directory = format!(
+        "images/{}",
+        job.image_directory(&CONFIG.get().unwrap().image_directory)
+    );
     let output_directory = Path::new(&non_abs_directory)
         .absolutize()
         .context("Absolutizing images path")?;
@@ -305,7 +1132,18 @@ pub fn do_job(job: Job) -> Result<CheckOutputs> {
         output_directory
     );
 
+    // So the images can be found and cleaned up once the PR is closed
+    if let Err(e) =
+        in_flight::mark_pull(Path::new(output_directory), (job.repo.id, job.pull_request))
+    {
+        log::warn!("Failed to mark image directory with its PR: {e}");
+    }
+
+    let render_statuses = &CONFIG.get().unwrap().render_statuses;
     let filter_on_status = |status: ChangeType| {
+        if !render_statuses.contains(&status) {
+            return vec![];
+        }
         job.files
             .iter()
             .filter(|f| f.status == status)
@@ -313,39 +1151,117 @@ pub fn do_job(job: Job) -> Result<CheckOutputs> {
     };
 
     let added_files = filter_on_status(ChangeType::Added);
-    let modified_files = filter_on_status(ChangeType::Modified);
+    let mut modified_files = filter_on_status(ChangeType::Modified);
+    if CONFIG.get().unwrap().rename_similarity.is_some() {
+        modified_files.extend(job.files.iter().filter(|f| f.status == ChangeType::Renamed));
+    }
     let removed_files = filter_on_status(ChangeType::Deleted);
 
+    // The remote is connected to once, when fetching, no need to probe it here
     let repository = git2::Repository::open(&repo_dir).context("Opening repository")?;
 
-    let mut remote = repository.find_remote("origin")?;
-
-    remote
-        .connect(git2::Direction::Fetch)
-        .context("Connecting to remote")?;
+    let budget = OutputBudget::new(CONFIG.get().unwrap().max_upload_bytes_per_job);
 
-    remote.disconnect().context("Disconnecting from remote")?;
+    // Held until the job's done, so the other bot on this host holds back too
+    let _permit = limiter
+        .map(RenderLimiter::acquire)
+        .transpose()
+        .context("Acquiring a render permit")?;
 
-    let res = match render(
+    let branches = InternalBranches::new(job.pull_request, &run_token(job.check_run.id()));
+    let render_started = Instant::now();
+    let mut res = match render(
         base,
         head,
         (&added_files, &modified_files, &removed_files),
         (&repository, &job.base.r#ref),
         (&repo_dir, Path::new(output_directory)),
-        job.pull_request,
-    ) {
-        Ok(maps) => generate_finished_output(
-            &added_files,
-            &modified_files,
-            &removed_files,
-            &non_abs_directory,
-            maps,
+        &branches,
+        &budget,
+        handle.block_on(fresh_token(&job)).as_deref(),
+        (
+            CONFIG
+                .get()
+                .unwrap()
+                .environments
+                .get(&job.repo.full_name())
+                .map(String::as_str),
+            CONFIG
+                .get()
+                .unwrap()
+                .render_pass_overrides
+                .get(&job.repo.full_name())
+                .map(Vec::as_slice)
+                .unwrap_or_default(),
         ),
+    ) {
+        Ok(maps) => {
+            let rendered = maps.added_maps.iter().filter(|map| map.is_ok()).count()
+                + maps.removed_maps.iter().filter(|map| map.is_ok()).count()
+                + maps
+                    .modified_maps
+                    .befores
+                    .iter()
+                    .filter(|map| map.is_ok())
+                    .count();
+            metrics::render_finished(&job.repo, rendered, render_started.elapsed());
+
+            let conf = CONFIG.get().unwrap();
+            let result = (conf.write_result_json || conf.api_token.is_some()).then(|| {
+                job_result(
+                    &job,
+                    (&added_files, &modified_files, &removed_files),
+                    &maps,
+                    &format!("{}/{}", conf.web.file_hosting_url, non_abs_directory),
+                )
+            });
+            if let Some(result) = result.as_ref().filter(|_| conf.api_token.is_some()) {
+                if let Err(e) =
+                    write_result_json(result, &latest_result_path(job.repo.id, job.pull_request))
+                {
+                    log::error!("Failed to save result for the results api: {e:?}");
+                }
+            }
+            let result_json = conf.write_result_json
+                && result.as_ref().is_some_and(|result| {
+                    write_result_json(result, &Path::new(output_directory).join(RESULT_FILENAME))
+                        .map_err(|e| log::error!("Failed to write result json: {e:?}"))
+                        .is_ok()
+                });
+            let html_report = conf.html_report
+                && write_html_report(
+                    &job,
+                    (&added_files, &modified_files, &removed_files),
+                    &maps,
+                    Path::new(output_directory),
+                )
+                .map_err(|e| log::error!("Failed to write html report: {e:?}"))
+                .is_ok();
+
+            generate_finished_output(
+                &added_files,
+                &modified_files,
+                &removed_files,
+                &non_abs_directory,
+                maps,
+                &budget,
+                result_json,
+                html_report,
+                started,
+            )
+        }
 
         Err(err) => Err(err),
     };
 
-    clean_up_references(&repository, &job.base.r#ref).context("Cleaning up references")?;
+    if job.files_truncated {
+        if let Ok(outputs) = &mut res {
+            warn_truncated_files(outputs, "Map renderings");
+        }
+    }
+
+    clean_up_references(&repository, &job.base.r#ref, &branches)
+        .context("Cleaning up references")?;
 
     res
 }
@@ -1,15 +1,27 @@
+mod api;
+mod context_cache;
+mod dedupe;
 mod gc_job;
 mod git_operations;
 mod github_processor;
+mod icon_refs;
 mod job_processor;
+mod metrics;
 mod rendering;
+mod repo_cache;
+mod report;
+mod results;
 mod runner;
+mod spawners;
+mod var_edits;
 
 use std::fs::File;
 use std::io::Read;
 use std::path::PathBuf;
 
 use diffbot_lib::async_mutex::Mutex;
+use diffbot_lib::github::github_types::ChangeType;
+use diffbot_lib::job::render_limiter::RenderLimiter;
 use once_cell::sync::OnceCell;
 use serde::Deserialize;
 use std::sync::Arc;
@@ -19,6 +31,21 @@ use std::sync::Arc;
 static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 
 pub type DataJobSender = actix_web::web::Data<Arc<Mutex<diffbot_lib::job::types::JobSender>>>;
+pub type DataCooldown = actix_web::web::Data<
+    diffbot_lib::job::cooldown::Cooldown<
+        diffbot_lib::github::github_types::PullRequestEventPayload,
+    >,
+>;
+
+/// 503s while the image store can't be written to, jobs hold off rendering until it's back
+#[actix_web::get("/healthz")]
+async fn healthz() -> actix_web::HttpResponse {
+    match diffbot_lib::upload::probe_store() {
+        Ok(()) => actix_web::HttpResponse::Ok().body("ok"),
+        Err(e) => actix_web::HttpResponse::ServiceUnavailable()
+            .body(format!("image store unavailable: {e}")),
+    }
+}
 
 #[actix_web::get("/")]
 async fn index() -> &'static str {
@@ -43,32 +70,278 @@ pub struct WebConfig {
     pub port: u16,
     pub file_hosting_url: String,
     pub limits: Option<WebLimitsConfig>,
+    #[serde(default = "default_serve_images")]
+    pub serve_images: bool,
+    #[serde(default = "default_images_route")]
+    pub images_route: String,
+}
+
+fn default_serve_images() -> bool {
+    true
+}
+
+fn default_images_route() -> String {
+    "/images".to_string()
+}
+
+/// What to do with changed maps that are symlinks, or sit in a symlinked directory
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkHandling {
+    /// Render the target, as long as it's still inside the repo
+    #[default]
+    Resolve,
+    Skip,
+}
+
+/// How random spawners show up in renders
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RandomSpawners {
+    /// The spawner's own sprite
+    #[default]
+    Spawner,
+    /// Whatever the random render pass picks, different every render
+    Random,
+    /// The first thing in the spawner's `loot` list
+    First,
+}
+
+/// What to do with z-levels that look moved when a map gains or loses some, see `moved_z_levels`
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ZLevelMismatch {
+    /// Still diff them by position, but say which base level they match
+    #[default]
+    Label,
+    /// Leave them out of the diff, they're only listed
+    Skip,
+}
+
+/// Render passes to change for the maps matching `pattern`, on top of the usual ones
+#[derive(Debug, Deserialize, Clone)]
+pub struct RenderPassOverride {
+    /// Glob matched against the map's path in the repo
+    pub pattern: String,
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+/// Every limit on how much runs at once, in one place so they can be checked against each other.
+/// Jobs are taken off the queue one at a time, so there's no worker count or per-repo lock to set.
+#[derive(Debug, Deserialize, Default)]
+pub struct ConcurrencyConfig {
+    /// Jobs rendering at once across every bot on the host
+    pub render_permits: Option<usize>,
+    #[serde(default = "diffbot_lib::job::render_limiter::default_directory")]
+    pub render_permits_dir: PathBuf,
+    pub render_threads: Option<usize>,
+    pub diff_threads: Option<usize>,
+    /// Clones, fetches and checkouts at once
+    pub git_ops: Option<usize>,
+}
+
+impl ConcurrencyConfig {
+    /// A single job renders with up to `render_threads` and diffs with up to `diff_threads`, more than the host has cores just thrashes.
+    /// Same goes for every permit holder rendering at once.
+    fn validate(&self) -> eyre::Result<()> {
+        for (name, limit) in [
+            ("render_permits", self.render_permits),
+            ("render_threads", self.render_threads),
+            ("diff_threads", self.diff_threads),
+            ("git_ops", self.git_ops),
+        ] {
+            if limit == Some(0) {
+                eyre::bail!("concurrency.{name} can't be 0, leave it out for no limit");
+            }
+        }
+        let cores = std::thread::available_parallelism().map_or(1, usize::from);
+        for (name, threads) in [
+            ("render_threads", self.render_threads),
+            ("diff_threads", self.diff_threads),
+        ] {
+            if let Some(threads) = threads.filter(|threads| *threads > cores) {
+                eyre::bail!(
+                    "concurrency.{name} is {threads} but this host only has {cores} cores, lower it or leave it out to use one thread per core"
+                );
+            }
+        }
+        if let Some(permits) = self.render_permits {
+            // Without render_threads every job already renders on all the cores, so only the permit count itself can be checked
+            let threads = self.render_threads.unwrap_or(1);
+            if permits * threads > cores {
+                eyre::bail!(
+                    "concurrency.render_permits ({permits}) jobs rendering with {threads} thread(s) each is more than this host's {cores} cores, lower render_permits or render_threads"
+                );
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub github: GithubConfig,
     pub web: WebConfig,
+    #[serde(default)]
+    pub concurrency: ConcurrencyConfig,
     #[serde(default = "std::collections::HashSet::new")]
     pub blacklist: std::collections::HashSet<u64>,
     #[serde(default = "String::new")]
     pub blacklist_contact: String,
     #[serde(default = "default_schedule")]
     pub gc_schedule: String,
+    #[serde(default = "default_gc_batch_size")]
+    pub gc_batch_size: usize,
+    #[serde(default)]
+    pub gc_batch_delay_secs: u64,
     #[serde(default = "default_log_level")]
     pub logging: String,
+    #[serde(alias = "webhook_secret")]
     pub secret: Option<String>,
+    pub api_token: Option<String>,
+    #[serde(default)]
+    pub metrics: bool,
+    #[serde(default)]
+    pub merge_base_diff: bool,
+    #[serde(default = "default_fetch_head_by_sha")]
+    pub fetch_head_by_sha: bool,
+    #[serde(default = "default_branch_prefix")]
+    pub branch_prefix: String,
+    pub max_upload_bytes_per_job: Option<u64>,
+    pub watermark_text: Option<String>,
+    #[serde(default)]
+    pub area_overlay: bool,
+    pub grid_spacing: Option<usize>,
+    pub zoom_inset_max_tiles: Option<usize>,
+    pub cluster_gap: Option<usize>,
+    pub max_region_size: Option<usize>,
+    #[serde(default)]
+    pub random_spawners: RandomSpawners,
+    #[serde(default)]
+    pub contact_sheets: bool,
+    #[serde(default)]
+    pub render_added_z_levels: bool,
+    #[serde(default)]
+    pub z_level_mismatch: ZLevelMismatch,
+    #[serde(default)]
+    pub icon_thumbnails: bool,
+    #[serde(default)]
+    pub map_commits: bool,
+    #[serde(default)]
+    pub priority_labels: std::collections::HashSet<String>,
+    #[serde(default)]
+    pub priority_repos: std::collections::HashSet<u64>,
+    #[serde(default)]
+    pub render_cooldown_secs: u64,
+    #[serde(default = "default_image_directory")]
+    pub image_directory: String,
+    #[serde(default)]
+    pub check_debounce_secs: u64,
+    #[serde(default = "default_no_changes_message")]
+    pub no_changes_message: String,
+    #[serde(default = "default_image_write_retries")]
+    pub image_write_retries: u32,
+    #[serde(default)]
+    pub png_compression: diffbot_lib::png_encoding::PngCompression,
+    pub max_inline_images: Option<usize>,
+    #[serde(default)]
+    pub skip_title_patterns: Vec<String>,
+    #[serde(default = "default_empty_pull_check")]
+    pub empty_pull_check: bool,
+    #[serde(default)]
+    pub rerender_on_base_push: bool,
+    #[serde(default)]
+    pub comment_commands: bool,
+    #[serde(default = "default_command_mention")]
+    pub command_mention: String,
+    #[serde(default)]
+    pub symlinked_maps: SymlinkHandling,
+    #[serde(default = "default_repos_dir")]
+    pub repos_dir: PathBuf,
+    pub max_repos_disk_bytes: Option<u64>,
+    #[serde(default)]
+    pub environments: std::collections::HashMap<String, String>,
+    #[serde(default)]
+    pub cached_environments: usize,
+    #[serde(default)]
+    pub render_pass_overrides: std::collections::HashMap<String, Vec<RenderPassOverride>>,
+    #[serde(default)]
+    pub write_result_json: bool,
+    #[serde(default)]
+    pub html_report: bool,
+    #[serde(default = "default_render_statuses")]
+    pub render_statuses: Vec<ChangeType>,
+    pub details_url: Option<String>,
+    pub rename_similarity: Option<u16>,
+    #[serde(default)]
+    pub change_labels: Vec<String>,
+    pub failure_webhook_url: Option<String>,
+    pub support_link: Option<String>,
+    #[serde(default = "default_image_store_wait_secs")]
+    pub image_store_wait_secs: u64,
+    pub check_name_suffix: Option<String>,
+    #[serde(default)]
+    pub lighting_passes: Vec<String>,
+}
+
+fn default_render_statuses() -> Vec<ChangeType> {
+    vec![ChangeType::Added, ChangeType::Modified, ChangeType::Deleted]
+}
+
+fn default_repos_dir() -> PathBuf {
+    PathBuf::from("./repos")
+}
+
+fn default_fetch_head_by_sha() -> bool {
+    true
+}
+
+fn default_branch_prefix() -> String {
+    "mdb".to_string()
+}
+
+fn default_gc_batch_size() -> usize {
+    1
 }
 
 fn default_schedule() -> String {
     "0 0 4 * * *".to_string()
 }
 
+fn default_image_directory() -> String {
+    "{repo_id}/{check_run}".to_string()
+}
+
+fn default_command_mention() -> String {
+    "@mapdiffbot".to_string()
+}
+
+fn default_empty_pull_check() -> bool {
+    true
+}
+
+fn default_image_store_wait_secs() -> u64 {
+    300
+}
+
+fn default_image_write_retries() -> u32 {
+    3
+}
+
+fn default_no_changes_message() -> String {
+    "No visual changes detected, the changed files render identically.".to_string()
+}
+
 fn default_log_level() -> String {
     "info".to_string()
 }
 
 static CONFIG: OnceCell<Config> = OnceCell::new();
+/// Only set if `concurrency.render_permits` is configured
+static RENDER_LIMITER: OnceCell<RenderLimiter> = OnceCell::new();
 
 fn read_key(path: PathBuf) -> Vec<u8> {
     let mut key_file =
@@ -86,7 +359,8 @@ fn init_config(path: &std::path::Path) -> eyre::Result<&'static Config> {
     let mut config_str = String::new();
     File::open(path)?.read_to_string(&mut config_str)?;
 
-    let config = toml::from_str(&config_str)?;
+    let config: Config = toml::from_str(&config_str)?;
+    config.concurrency.validate()?;
 
     CONFIG.set(config).expect("Failed to set config");
     Ok(CONFIG.get().unwrap())
@@ -99,11 +373,27 @@ async fn main() -> eyre::Result<()> {
     simple_eyre::install().expect("Eyre handler installation failed!");
 
     let config_path = std::path::Path::new(".").join("config.toml");
-    let config =
-        init_config(&config_path).unwrap_or_else(|_| panic!("Failed to read {config_path:?}"));
+    let config = init_config(&config_path)
+        .unwrap_or_else(|e| panic!("Failed to read {config_path:?}: {e:?}"));
 
     diffbot_lib::logger::init_logger(&config.logging).expect("Log init failed!");
 
+    // dmm_tools ignores passes it doesn't know, which would look like the option just not working
+    for pass in &config.lighting_passes {
+        if !dmm_tools::render_passes::RENDER_PASSES
+            .iter()
+            .any(|known| known.name == pass.as_str())
+        {
+            diffbot_lib::log::warn!("Unknown render pass in lighting_passes: {pass}");
+        }
+    }
+
+    if let Some(permits) = config.concurrency.render_permits {
+        let limiter = RenderLimiter::new(&config.concurrency.render_permits_dir, permits)
+            .expect("Couldn't create the render permit directory");
+        let _ = RENDER_LIMITER.set(limiter);
+    }
+
     let key = read_key(PathBuf::from(&config.github.private_key_path));
 
     octocrab::initialise(octocrab::OctocrabBuilder::new().app(
@@ -112,7 +402,9 @@ async fn main() -> eyre::Result<()> {
     ))
     .expect("fucked up octocrab");
 
-    let (job_sender, job_receiver) = yaque::channel(JOB_JOURNAL_LOCATION)
+    diffbot_lib::github::github_api::verify_app_credentials(config.github.app_id).await?;
+
+    let (job_sender, job_receiver) = diffbot_lib::job::types::job_channel(JOB_JOURNAL_LOCATION)
         .expect("Couldn't open an on-disk queue, check permissions or drive space?");
 
     actix_web::rt::spawn(runner::handle_jobs("MapDiffBot2", job_receiver));
@@ -125,6 +417,13 @@ async fn main() -> eyre::Result<()> {
 
     actix_web::rt::spawn(async move { gc_job::gc_scheduler(cron_str, job_clone).await });
 
+    let cooldown: DataCooldown = actix_web::web::Data::new(
+        diffbot_lib::job::cooldown::Cooldown::new(std::time::Duration::from_secs(
+            config.render_cooldown_secs,
+        ))
+        .with_debounce(std::time::Duration::from_secs(config.check_debounce_secs)),
+    );
+
     actix_web::HttpServer::new(move || {
         use actix_web::web::{FormConfig, PayloadConfig};
         //absolutely rancid
@@ -142,9 +441,26 @@ async fn main() -> eyre::Result<()> {
             .app_data(form_config)
             .app_data(string_config)
             .app_data(actix_web::web::Data::new(job_sender.clone()))
+            .app_data(cooldown.clone())
             .service(index)
+            .service(healthz)
             .service(github_processor::process_github_payload)
-            .service(actix_files::Files::new("/images", "./images"))
+            .configure(|cfg| {
+                if config.api_token.is_some() {
+                    cfg.service(api::get_result);
+                }
+                if config.metrics {
+                    cfg.service(metrics::metrics);
+                }
+            })
+            .configure(|cfg| {
+                if config.web.serve_images {
+                    cfg.service(actix_files::Files::new(
+                        &config.web.images_route,
+                        "./images",
+                    ));
+                }
+            })
     })
     .bind((config.web.address.as_ref(), config.web.port))?
     .run()
@@ -0,0 +1,124 @@
+//! Job and render counters for `/metrics`, in Prometheus' text format.
+//! Everything is labelled by repo, so it's clear which repos cost the most.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use diffbot_lib::github::github_types::Repository;
+
+/// Upper bounds of the render duration buckets, in seconds
+const RENDER_BUCKETS: [f64; 9] = [1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0];
+
+#[derive(Default)]
+struct RepoMetrics {
+    jobs_processed: u64,
+    jobs_failed: u64,
+    maps_rendered: u64,
+    /// Per bucket, not cumulative, that's done when writing them out
+    render_buckets: [u64; RENDER_BUCKETS.len()],
+    render_count: u64,
+    render_seconds: f64,
+}
+
+/// By repo full name, sorted so the output is stable between scrapes
+static METRICS: Mutex<BTreeMap<String, RepoMetrics>> = Mutex::new(BTreeMap::new());
+
+fn update(repo: &Repository, change: impl FnOnce(&mut RepoMetrics)) {
+    let mut metrics = METRICS.lock().unwrap();
+    change(metrics.entry(repo.full_name()).or_default());
+}
+
+/// Every job that ran, failed or not
+pub fn job_processed(repo: &Repository) {
+    update(repo, |metrics| metrics.jobs_processed += 1);
+}
+
+pub fn job_failed(repo: &Repository) {
+    update(repo, |metrics| metrics.jobs_failed += 1);
+}
+
+/// A job's render, `maps` being how many rendered without errors
+pub fn render_finished(repo: &Repository, maps: usize, took: Duration) {
+    let seconds = took.as_secs_f64();
+    update(repo, |metrics| {
+        metrics.maps_rendered += maps as u64;
+        if let Some(bucket) = RENDER_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            metrics.render_buckets[bucket] += 1;
+        }
+        metrics.render_count += 1;
+        metrics.render_seconds += seconds;
+    });
+}
+
+fn label(repo: &str) -> String {
+    repo.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn encode() -> String {
+    let metrics = METRICS.lock().unwrap();
+    let mut out = String::new();
+
+    let counters: [(&str, &str, fn(&RepoMetrics) -> u64); 3] = [
+        (
+            "jobs_processed_total",
+            "Jobs that ran, failed or not",
+            |metrics| metrics.jobs_processed,
+        ),
+        (
+            "jobs_failed_total",
+            "Jobs that errored, panicked or timed out",
+            |metrics| metrics.jobs_failed,
+        ),
+        (
+            "maps_rendered_total",
+            "Maps rendered without errors",
+            |metrics| metrics.maps_rendered,
+        ),
+    ];
+    for (name, help, value) in counters {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter");
+        for (repo, repo_metrics) in metrics.iter() {
+            let _ = writeln!(
+                out,
+                "{name}{{repo=\"{}\"}} {}",
+                label(repo),
+                value(repo_metrics)
+            );
+        }
+    }
+
+    let name = "render_duration_seconds";
+    let _ = writeln!(
+        out,
+        "# HELP {name} How long rendering a job's maps took\n# TYPE {name} histogram"
+    );
+    for (repo, repo_metrics) in metrics.iter() {
+        let repo = label(repo);
+        let mut cumulative = 0;
+        for (bound, count) in RENDER_BUCKETS.iter().zip(repo_metrics.render_buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{repo=\"{repo}\",le=\"{bound}\"}} {cumulative}"
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{repo=\"{repo}\",le=\"+Inf\"}} {}\n{name}_sum{{repo=\"{repo}\"}} {}\n{name}_count{{repo=\"{repo}\"}} {}",
+            repo_metrics.render_count, repo_metrics.render_seconds, repo_metrics.render_count
+        );
+    }
+
+    out
+}
+
+#[actix_web::get("/metrics")]
+pub async fn metrics() -> actix_web::HttpResponse {
+    actix_web::HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(encode())
+}
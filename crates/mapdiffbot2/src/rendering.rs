@@ -1,15 +1,37 @@
-use std::{cmp::min, collections::HashSet, path::Path, sync::RwLock};
+use std::{
+    cmp::{max, min},
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
 
 extern crate dreammaker;
 
 use ahash::RandomState;
 use diffbot_lib::github::github_types::FileDiff;
-use diffbot_lib::log::{error, info, trace};
-use dmm_tools::{dmi::Image, dmm, minimap, render_passes::RenderPass, IconCache};
+use diffbot_lib::job::budget::OutputBudget;
+use diffbot_lib::log::{error, info, trace, warn};
+use diffbot_lib::png_encoding::{encode_rgba, encode_rgba_to_file};
+use diffbot_lib::upload::{write_with_retries, RetryQueue};
+use diffbot_lib::watermark::{draw_backdrop, draw_text, draw_watermark, text_width, TEXT_HEIGHT};
+use dmm_tools::{
+    dmi::{render::IconRenderer, Image},
+    dmm, minimap,
+    render_passes::RenderPass,
+    IconCache,
+};
 use eyre::{Context, Result};
-use image::{io::Reader, GenericImageView, ImageBuffer, Pixel};
+use image::{
+    imageops::{self, FilterType},
+    io::Reader,
+    GenericImageView, ImageBuffer, Pixel,
+};
 use rayon::prelude::*;
 
+use crate::icon_refs::IconRef;
+use crate::spawners::first_variants;
+use crate::{RandomSpawners, RenderPassOverride, SymlinkHandling, ZLevelMismatch};
+
 #[derive(Debug, Clone)]
 pub struct BoundingBox {
     left: usize,
@@ -37,6 +59,39 @@ impl BoundingBox {
             top: dims.1 - 1,
         }
     }
+
+    /// Cuts the box down to what actually exists on `map`, for boxes spanning both sides of a resize
+    pub fn clamped_to(&self, map: &dmm::Map) -> Self {
+        let dims = map.dim_xyz();
+        Self {
+            left: self.left,
+            bottom: self.bottom,
+            right: min(self.right, dims.0 - 1),
+            top: min(self.top, dims.1 - 1),
+        }
+    }
+
+    /// Cuts the box into a grid of near equal pieces, none more than `max_size` tiles on a side
+    pub fn split(&self, max_size: usize) -> Vec<Self> {
+        let max_size = max_size.max(1);
+        let spans = |from: usize, to: usize| {
+            let len = to - from + 1;
+            let count = len.div_ceil(max_size);
+            (0..count)
+                .map(move |idx| (from + len * idx / count, from + len * (idx + 1) / count - 1))
+                .collect::<Vec<_>>()
+        };
+        let columns = spans(self.left, self.right);
+        spans(self.bottom, self.top)
+            .into_iter()
+            .rev()
+            .flat_map(|(bottom, top)| {
+                columns
+                    .iter()
+                    .map(move |&(left, right)| Self::new(left, bottom, right, top))
+            })
+            .collect()
+    }
 }
 
 impl ToString for BoundingBox {
@@ -50,6 +105,49 @@ impl ToString for BoundingBox {
 
 pub type RenderingErrors = RwLock<HashSet<String, RandomState>>;
 
+/// Paths of rendered images that came out (nearly) empty, see [`looks_empty`]
+pub type BlankRenders = RwLock<HashSet<PathBuf, RandomState>>;
+
+fn watermark_text() -> Option<&'static str> {
+    crate::CONFIG.get().unwrap().watermark_text.as_deref()
+}
+
+/// Runs `f`, turning a panic into an error so one bad map can't take the rest of the job down with it
+pub fn catch_panic<T>(f: impl FnOnce() -> Result<T>) -> Result<T> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or_else(|panic| {
+        let fuckup = match panic.downcast_ref::<&str>() {
+            Some(s) => s.to_string(),
+            None => match panic.downcast_ref::<String>() {
+                Some(s) => s.clone(),
+                None => "*crickets*".to_owned(),
+            },
+        };
+        Err(eyre::anyhow!("Panicked: {fuckup}"))
+    })
+}
+
+/// Whether two tiles hold the same prefabs, in any order.
+/// Tools re-serializing maps like to shuffle prefabs around, which shouldn't count as a change.
+/// Keys never matter here, tiles are always compared by what's in the dictionary.
+pub fn same_tile(left: &[dmm::Prefab], right: &[dmm::Prefab]) -> bool {
+    if left == right {
+        return true;
+    }
+    if left.len() != right.len() {
+        return false;
+    }
+    let mut unmatched = right.iter().collect::<Vec<_>>();
+    left.iter().all(
+        |prefab| match unmatched.iter().position(|other| *other == prefab) {
+            Some(idx) => {
+                unmatched.swap_remove(idx);
+                true
+            }
+            None => false,
+        },
+    )
+}
+
 // Returns None if there are no differences
 pub fn get_diff_bounding_box(
     base_map: &dmm::Map,
@@ -58,11 +156,19 @@ pub fn get_diff_bounding_box(
 ) -> Option<BoundingBox> {
     let left_dims = base_map.dim_xyz();
     let right_dims = head_map.dim_xyz();
-    if left_dims != right_dims {
+    if (left_dims.0, left_dims.1) != (right_dims.0, right_dims.1) {
         info!(
             "Maps have different sizes: {:?} {:?}",
             left_dims, right_dims
         );
+        // Anything outside the area both maps share changed by definition, so show the whole thing.
+        // Both renders start at the bottom left corner, render_diffs_for_directory lines them up from there
+        return Some(BoundingBox::new(
+            0,
+            0,
+            max(left_dims.0, right_dims.0) - 1,
+            max(left_dims.1, right_dims.1) - 1,
+        ));
     }
 
     let max_y = min(left_dims.1, right_dims.1);
@@ -80,7 +186,7 @@ pub fn get_diff_bounding_box(
             let left_tile = &base_map.dictionary[&base_map.grid[(z_level, left_dims.1 - y - 1, x)]];
             let right_tile =
                 &head_map.dictionary[&head_map.grid[(z_level, right_dims.1 - y - 1, x)]];
-            if left_tile != right_tile {
+            if !same_tile(left_tile, right_tile) {
                 if x < leftmost {
                     leftmost = x;
                 }
@@ -109,32 +215,162 @@ pub fn get_diff_bounding_box(
         bottommost
     );
 
+    let bounds = padded([leftmost, bottommost, rightmost, topmost], max_x, max_y);
+
+    trace!("After expansion: {}", bounds.to_string());
+
+    Some(bounds)
+}
+
+/// `[left, bottom, right, top]` of changed tiles, grown a bit so there's some context around them
+fn padded([left, bottom, right, top]: [usize; 4], max_x: usize, max_y: usize) -> BoundingBox {
     //this is a god awful way to expand bounds without it going out of bounds
+    BoundingBox::new(
+        left.saturating_sub(2).clamp(1, max_x - 1),
+        bottom.saturating_sub(2).clamp(1, max_y - 1),
+        right.saturating_add(2).clamp(1, max_x - 1),
+        top.saturating_add(2).clamp(1, max_y - 1),
+    )
+}
 
-    rightmost = rightmost.saturating_add(2).clamp(1, max_x - 1);
-    topmost = topmost.saturating_add(2).clamp(1, max_y - 1);
-    leftmost = leftmost.saturating_sub(2).clamp(1, max_x - 1);
-    bottommost = bottommost.saturating_sub(2).clamp(1, max_y - 1);
+/// Splits the changes on a z-level into clusters, changed tiles with at most `gap` unchanged tiles between them end up together.
+/// Only makes sense for maps of the same size, returns the padded bounds of each cluster.
+pub fn get_diff_clusters(
+    base_map: &dmm::Map,
+    head_map: &dmm::Map,
+    z_level: usize,
+    gap: usize,
+) -> Vec<BoundingBox> {
+    let dims = base_map.dim_xyz();
+    let (max_x, max_y) = (dims.0, dims.1);
+    let reach = gap + 1;
+    let near = |a: &[usize; 4], b: &[usize; 4]| {
+        a[0] <= b[2] + reach && b[0] <= a[2] + reach && a[1] <= b[3] + reach && b[1] <= a[3] + reach
+    };
+    let merged = |a: &[usize; 4], b: &[usize; 4]| {
+        [
+            min(a[0], b[0]),
+            min(a[1], b[1]),
+            max(a[2], b[2]),
+            max(a[3], b[3]),
+        ]
+    };
 
-    trace!(
-        "After expansion max: (right, top):({}, {}), min: (left, bottom):({}, {})",
-        rightmost,
-        topmost,
-        leftmost,
-        bottommost
-    );
+    let mut clusters: Vec<[usize; 4]> = vec![];
+    for y in 0..max_y {
+        for x in 0..max_x {
+            if same_tile(
+                &base_map.dictionary[&base_map.grid[(z_level, max_y - y - 1, x)]],
+                &head_map.dictionary[&head_map.grid[(z_level, max_y - y - 1, x)]],
+            ) {
+                continue;
+            }
+            let tile = [x, y, x, y];
+            match clusters.iter_mut().find(|cluster| near(cluster, &tile)) {
+                Some(cluster) => *cluster = merged(cluster, &tile),
+                None => clusters.push(tile),
+            }
+        }
+    }
+
+    // Growing a cluster can bring it within reach of another one
+    loop {
+        let Some((a, b)) = (0..clusters.len())
+            .flat_map(|a| (a + 1..clusters.len()).map(move |b| (a, b)))
+            .find(|&(a, b)| near(&clusters[a], &clusters[b]))
+        else {
+            break;
+        };
+        let other = clusters.swap_remove(b);
+        clusters[a] = merged(&clusters[a], &other);
+    }
+
+    clusters
+        .into_iter()
+        .map(|cluster| padded(cluster, max_x, max_y))
+        .collect()
+}
+
+/// Number of tiles on the z-level that differ between the two maps, within the area both cover
+pub fn count_changed_tiles(base_map: &dmm::Map, head_map: &dmm::Map, z_level: usize) -> usize {
+    let left_dims = base_map.dim_xyz();
+    let right_dims = head_map.dim_xyz();
+    let max_y = min(left_dims.1, right_dims.1);
+    let max_x = min(left_dims.0, right_dims.0);
+
+    (0..max_y)
+        .flat_map(|y| (0..max_x).map(move |x| (x, y)))
+        .filter(|&(x, y)| {
+            !same_tile(
+                &base_map.dictionary[&base_map.grid[(z_level, left_dims.1 - y - 1, x)]],
+                &head_map.dictionary[&head_map.grid[(z_level, right_dims.1 - y - 1, x)]],
+            )
+        })
+        .count()
+}
+
+fn same_level(base_map: &dmm::Map, base_z: usize, head_map: &dmm::Map, head_z: usize) -> bool {
+    let (dim_x, dim_y, _) = base_map.dim_xyz();
+    (0..dim_y).all(|y| {
+        (0..dim_x).all(|x| {
+            same_tile(
+                &base_map.dictionary[&base_map.grid[(base_z, y, x)]],
+                &head_map.dictionary[&head_map.grid[(head_z, y, x)]],
+            )
+        })
+    })
+}
+
+/// Head z-levels that are identical to a different base z-level, as (head level, base level).
+/// Only looked for when the number of z-levels changed, that's when comparing by position pairs up the wrong levels.
+pub fn moved_z_levels(base_map: &dmm::Map, head_map: &dmm::Map) -> Vec<(usize, usize)> {
+    let (base_dims, head_dims) = (base_map.dim_xyz(), head_map.dim_xyz());
+    if base_dims.2 == head_dims.2 || (base_dims.0, base_dims.1) != (head_dims.0, head_dims.1) {
+        return vec![];
+    }
+    (0..head_dims.2)
+        .filter(|&z| z >= base_dims.2 || !same_level(base_map, z, head_map, z))
+        .filter_map(|z| {
+            (0..base_dims.2)
+                .find(|&base_z| base_z != z && same_level(base_map, base_z, head_map, z))
+                .map(|base_z| (z, base_z))
+        })
+        .collect()
+}
 
-    Some(BoundingBox::new(leftmost, bottommost, rightmost, topmost))
+/// Where to actually read a changed map from, making sure symlinks can't be used to read files outside the repo
+fn map_path(repo_root: &Path, filename: &str) -> Result<std::path::PathBuf> {
+    let root = repo_root
+        .canonicalize()
+        .context("Resolving repository path")?;
+    let direct = root.join(filename);
+    let resolved = direct
+        .canonicalize()
+        .with_context(|| format!("Resolving {filename}"))?;
+    if resolved == direct {
+        return Ok(resolved);
+    }
+
+    match crate::CONFIG.get().unwrap().symlinked_maps {
+        SymlinkHandling::Skip => Err(eyre::anyhow!(
+            "{filename} is a symlink, symlinked maps are skipped"
+        )),
+        SymlinkHandling::Resolve if !resolved.starts_with(&root) => Err(eyre::anyhow!(
+            "{filename} is a symlink pointing outside the repository, refusing to render it"
+        )),
+        SymlinkHandling::Resolve => Ok(resolved),
+    }
 }
 
 pub fn load_maps(files: &[&FileDiff], path: &std::path::Path) -> Vec<Result<dmm::Map>> {
     files
         .iter()
         .map(|file| {
-            let actual_path = path.join(Path::new(&file.filename));
-            dmm::Map::from_file(&actual_path)
-                .map_err(|e| eyre::anyhow!(e))
-                .context(format!("Map name: {}", &file.filename))
+            catch_panic(|| {
+                let actual_path = map_path(path, &file.filename)?;
+                dmm::Map::from_file(&actual_path).map_err(|e| eyre::anyhow!(e))
+            })
+            .context(format!("Map name: {}", &file.filename))
         })
         .collect()
 }
@@ -142,18 +378,23 @@ pub fn load_maps(files: &[&FileDiff], path: &std::path::Path) -> Vec<Result<dmm:
 pub fn load_maps_with_whole_map_regions(
     files: &[&FileDiff],
     path: &std::path::Path,
-) -> Result<Vec<MapWithRegions>> {
+) -> Vec<Result<MapWithRegions>> {
     files
         .iter()
         .map(|file| {
-            let actual_path = path.join(Path::new(&file.filename));
-            let map = dmm::Map::from_file(&actual_path)?;
-            let bbox = BoundingBox::for_full_map(&map);
-            let zs = map.dim_z();
-            Ok(MapWithRegions {
-                map,
-                bounding_boxes: std::iter::repeat(Some(bbox)).take(zs).collect(),
+            catch_panic(|| {
+                let actual_path = map_path(path, &file.filename)?;
+                let map = dmm::Map::from_file(&actual_path)?;
+                let bbox = BoundingBox::for_full_map(&map);
+                let zs = map.dim_z();
+                Ok(MapWithRegions {
+                    map,
+                    bounding_boxes: std::iter::repeat(Some(bbox)).take(zs).collect(),
+                    clusters: vec![],
+                    moved_levels: vec![],
+                })
             })
+            .context(format!("Map name: {}", &file.filename))
         })
         .collect()
 }
@@ -162,6 +403,10 @@ pub struct MapWithRegions {
     pub map: dmm::Map,
     /// For each z-level, if there's a Some, render the given region
     pub bounding_boxes: Vec<Option<BoundingBox>>,
+    /// For each z-level, the regions to render instead of the whole bounding box, see `cluster_gap`. Empty if it's one region
+    pub clusters: Vec<Vec<BoundingBox>>,
+    /// Levels of the head that match a different level of the base, see `moved_z_levels`. Only set on the before side
+    pub moved_levels: Vec<(usize, usize)>,
 }
 
 // pub fn iter_levels<'a>(&'a self) -> impl Iterator<Item=(i32, ZLevel<'a>)> + 'a {
@@ -172,6 +417,27 @@ impl MapWithRegions {
             .enumerate()
             .filter_map(|(z, bbox)| bbox.as_ref().map(|bbox| (z, bbox)))
     }
+
+    /// Everything that gets rendered, a z-level's clusters if it was split up or its whole bounding box otherwise
+    pub fn iter_regions(&self) -> impl Iterator<Item = (usize, Option<usize>, &BoundingBox)> {
+        self.iter_levels()
+            .flat_map(move |(z, bbox)| match self.clusters.get(z) {
+                Some(clusters) if !clusters.is_empty() => clusters
+                    .iter()
+                    .enumerate()
+                    .map(|(cluster, bounds)| (z, Some(cluster), bounds))
+                    .collect(),
+                _ => vec![(z, None, bbox)],
+            })
+    }
+}
+
+/// What a region's images are named after, the z-level plus which cluster it is for split up levels
+pub fn region_image(level: usize, cluster: Option<usize>) -> String {
+    match cluster {
+        Some(cluster) => format!("{level}-{cluster}"),
+        None => level.to_string(),
+    }
 }
 
 pub struct MapsWithRegions {
@@ -179,39 +445,130 @@ pub struct MapsWithRegions {
     pub afters: Vec<Option<MapWithRegions>>,
 }
 
+impl MapsWithRegions {
+    /// Marks the map at `idx` as failed, so it gets reported as an error instead of rendered
+    pub fn fail(&mut self, idx: usize, error: eyre::Report) {
+        self.befores[idx] = Err(error);
+        self.afters[idx] = None;
+    }
+}
+
+/// Breaks every region bigger than `max_size` tiles on a side into pieces, a huge render is more than Github will display
+fn split_regions(
+    diffs: &[Option<BoundingBox>],
+    clusters: Vec<Vec<BoundingBox>>,
+    max_size: usize,
+) -> Vec<Vec<BoundingBox>> {
+    diffs
+        .iter()
+        .enumerate()
+        .map(|(z, diff)| {
+            let regions = match clusters.get(z) {
+                Some(regions) if !regions.is_empty() => regions.clone(),
+                _ => diff.iter().cloned().collect(),
+            };
+            let pieces = regions
+                .iter()
+                .flat_map(|region| region.split(max_size))
+                .collect::<Vec<_>>();
+            // One piece is just the bounding box again
+            if pieces.len() > 1 {
+                pieces
+            } else {
+                vec![]
+            }
+        })
+        .collect()
+}
+
 pub fn get_map_diff_bounding_boxes(
     base_maps: Vec<Result<dmm::Map>>,
     head_maps: Vec<Result<dmm::Map>>,
-) -> Result<MapsWithRegions> {
+) -> MapsWithRegions {
     let (mut befores, mut afters) = (
         Vec::with_capacity(base_maps.len()),
         Vec::with_capacity(head_maps.len()),
     );
 
     for (base, head) in base_maps.into_iter().zip(head_maps.into_iter()) {
-        let (before, after) = match (base, head) {
-            (Err(e), Ok(_)) => Ok((Err(e), None)),
-            (Ok(base), Ok(head)) => {
-                let diffs = (0..base.dim_z())
+        let diffed = match (base, head) {
+            (Ok(base), Ok(head)) => catch_panic(|| {
+                // Only levels both maps have can be diffed, a change in z-levels shows up in the resize note
+                let mut diffs = (0..min(base.dim_z(), head.dim_z()))
                     .map(|z| get_diff_bounding_box(&base, &head, z))
                     .collect::<Vec<_>>();
+                let moved = moved_z_levels(&base, &head);
+                // Diffing a moved level against whatever ended up in its old spot is just noise
+                if crate::CONFIG.get().unwrap().z_level_mismatch == ZLevelMismatch::Skip {
+                    for (level, _) in &moved {
+                        if let Some(diff) = diffs.get_mut(*level) {
+                            *diff = None;
+                        }
+                    }
+                }
+                let (base_dims, head_dims) = (base.dim_xyz(), head.dim_xyz());
+                let clusters = match crate::CONFIG.get().unwrap().cluster_gap {
+                    // A resize is shown whole anyway
+                    Some(gap) if (base_dims.0, base_dims.1) == (head_dims.0, head_dims.1) => diffs
+                        .iter()
+                        .enumerate()
+                        .map(|(z, diff)| match diff {
+                            Some(_) => get_diff_clusters(&base, &head, z, gap),
+                            None => vec![],
+                        })
+                        // One cluster is just the bounding box again
+                        .map(|clusters| if clusters.len() > 1 { clusters } else { vec![] })
+                        .collect(),
+                    _ => vec![],
+                };
+                let clusters = match crate::CONFIG.get().unwrap().max_region_size {
+                    Some(max_size) => split_regions(&diffs, clusters, max_size),
+                    None => clusters,
+                };
+                Ok((diffs, clusters, moved))
+            })
+            .context("Computing map differences")
+            .map(|(diffs, clusters, moved)| {
+                let mut after_boxes = diffs.clone();
+                // Levels only the head has are rendered whole, like an added map, there's nothing to diff them against
+                if crate::CONFIG.get().unwrap().render_added_z_levels {
+                    let full = BoundingBox::for_full_map(&head);
+                    let skip_moved =
+                        crate::CONFIG.get().unwrap().z_level_mismatch == ZLevelMismatch::Skip;
+                    after_boxes.extend((diffs.len()..head.dim_z()).map(|z| {
+                        let moved = moved.iter().any(|(level, _)| *level == z);
+                        (!(skip_moved && moved)).then(|| full.clone())
+                    }));
+                }
                 let before = MapWithRegions {
                     map: base,
-                    bounding_boxes: diffs.clone(),
+                    bounding_boxes: diffs,
+                    clusters: clusters.clone(),
+                    moved_levels: moved,
                 };
                 let after = MapWithRegions {
                     map: head,
-                    bounding_boxes: diffs,
+                    bounding_boxes: after_boxes,
+                    clusters,
+                    moved_levels: vec![],
                 };
-                Ok((Ok(before), Some(after)))
+                (before, after)
+            }),
+            // Say which side broke, "the map failed to parse" alone sends people looking at the wrong commit
+            (Err(e), Ok(_)) => {
+                Err(e.wrap_err("Base version failed to parse, head version is fine"))
             }
-            (Ok(_), Err(e)) => Err(e),  //Fails on head parse fail
-            (Err(_), Err(e)) => Err(e), //Fails on head parse fail
-        }?; //Stop the entire thing if head parse fails
-        match before {
-            Ok(o) => {
-                befores.push(Ok(o));
-                afters.push(Some(after.unwrap()))
+            (Ok(_), Err(e)) => {
+                Err(e.wrap_err("Head version failed to parse, base version is fine"))
+            }
+            (Err(base), Err(head)) => Err(eyre::anyhow!(
+                "Neither version parsed\nBase: {base:#}\nHead: {head:#}"
+            )),
+        };
+        match diffed {
+            Ok((before, after)) => {
+                befores.push(Ok(before));
+                afters.push(Some(after));
             }
             Err(e) => {
                 befores.push(Err(e));
@@ -220,23 +577,75 @@ pub fn get_map_diff_bounding_boxes(
         }
     }
 
-    Ok(MapsWithRegions { befores, afters })
+    MapsWithRegions { befores, afters }
+}
+
+/// Picks the .dme the maps should be rendered against.
+/// `configured` (relative to the repo) always wins, otherwise each map votes for the closest .dme above it
+/// and the most popular one is used. None means falling back to dreammaker's own detection.
+pub fn pick_environment(
+    repo_root: &Path,
+    map_files: &[&FileDiff],
+    configured: Option<&str>,
+) -> Option<std::path::PathBuf> {
+    if let Some(configured) = configured {
+        return Some(repo_root.join(configured));
+    }
+
+    let mut votes: std::collections::HashMap<std::path::PathBuf, usize> = Default::default();
+    for file in map_files {
+        let map_path = repo_root.join(&file.filename);
+        // Never look outside the repo, and directories with more than one .dme are too ambiguous to guess
+        let found = map_path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(repo_root))
+            .find_map(|dir| {
+                let dmes = std::fs::read_dir(dir)
+                    .ok()?
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| entry.path())
+                    .filter(|path| path.extension().map_or(false, |ext| ext == "dme"))
+                    .collect::<Vec<_>>();
+                match dmes.as_slice() {
+                    [dme] => Some(dme.clone()),
+                    _ => None,
+                }
+            });
+        if let Some(dme) = found {
+            *votes.entry(dme).or_default() += 1;
+        }
+    }
+
+    votes
+        .into_iter()
+        .max_by(|(a_path, a_votes), (b_path, b_votes)| {
+            // Ties go to whichever sorts first, so base and head agree
+            a_votes.cmp(b_votes).then_with(|| b_path.cmp(a_path))
+        })
+        .map(|(dme, _)| dme)
 }
 
 pub struct RenderingContext {
     map_renderer_config: dreammaker::config::MapRenderer,
     obj_tree: dreammaker::objtree::ObjectTree,
     icon_cache: IconCache,
+    environment: std::path::PathBuf,
+    parse_errors: Vec<String>,
 }
 
 impl RenderingContext {
-    pub fn new(path: &Path) -> Result<Self> {
+    /// `environment` is the .dme to parse, if None it's detected from `path`
+    pub fn new(path: &Path, environment: Option<&Path>) -> Result<Self> {
         let dm_context = dreammaker::Context::default();
         let mut icon_cache = IconCache::default();
 
-        let environment = match dreammaker::detect_environment(path, dreammaker::DEFAULT_ENV) {
-            Ok(Some(found)) => found,
-            _ => dreammaker::DEFAULT_ENV.into(),
+        let environment = match environment {
+            Some(environment) => environment.to_path_buf(),
+            None => match dreammaker::detect_environment(path, dreammaker::DEFAULT_ENV) {
+                Ok(Some(found)) => found,
+                _ => dreammaker::DEFAULT_ENV.into(),
+            },
         };
 
         if let Some(parent) = environment.parent() {
@@ -244,24 +653,70 @@ impl RenderingContext {
         }
 
         dm_context.autodetect_config(&environment);
-        let pp = dreammaker::preprocessor::Preprocessor::new(&dm_context, environment)
-            .context("Creating preprocessor")?;
+        let pp = dreammaker::preprocessor::Preprocessor::new(&dm_context, environment.clone())
+            .with_context(|| format!("Creating preprocessor for {}", environment.display()))?;
         let indents = dreammaker::indents::IndentProcessor::new(&dm_context, pp);
         let parser = dreammaker::parser::Parser::new(&dm_context, indents);
 
         let obj_tree = parser.parse_object_tree();
         let map_renderer_config = dm_context.config().map_renderer.clone();
 
+        // Lints and warnings are the codebase's business, errors are what end up making renders look off
+        let parse_errors = dm_context
+            .errors()
+            .iter()
+            .filter(|error| error.severity() == dreammaker::Severity::Error)
+            .map(|error| {
+                let location = error.location();
+                format!(
+                    "{}:{}: {}",
+                    dm_context.file_path(location.file).display(),
+                    location.line,
+                    error.description()
+                )
+            })
+            .collect();
+
         Ok(Self {
             map_renderer_config,
             icon_cache,
             obj_tree,
+            environment,
+            parse_errors,
         })
     }
 
     pub fn map_config(&self) -> &dreammaker::config::MapRenderer {
         &self.map_renderer_config
     }
+
+    pub fn environment(&self) -> &Path {
+        &self.environment
+    }
+
+    pub fn obj_tree(&self) -> &dreammaker::objtree::ObjectTree {
+        &self.obj_tree
+    }
+
+    /// First frame of the icon state, None if the icon or state doesn't exist
+    pub fn icon_thumbnail(&self, icon_ref: &IconRef) -> Option<Image> {
+        let icon = self.icon_cache.get_icon(Path::new(&icon_ref.icon))?;
+        let state = icon
+            .metadata
+            .states
+            .iter()
+            .find(|state| state.name == icon_ref.state)?;
+        IconRenderer::new(icon)
+            .render_to_images(&state.get_state_name_index())
+            .ok()?
+            .into_iter()
+            .next()
+    }
+
+    /// Errors hit while parsing the environment, the object tree is still usable but may be missing bits
+    pub fn parse_errors(&self) -> &[String] {
+        &self.parse_errors
+    }
 }
 
 pub fn render_map(
@@ -288,56 +743,456 @@ pub fn render_map(
         .map_err(|_| eyre::anyhow!("An error occured during map rendering"))
 }
 
+fn area_color(path: &str) -> [u8; 3] {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    path.hash(&mut hasher);
+    let [r, g, b, ..] = hasher.finish().to_le_bytes();
+    [r, g, b]
+}
+
+/// Tints every tile of a rendered region with a colour derived from its area,
+/// so area reassignments show up even when nothing visible changed
+pub fn overlay_areas(image: &mut Image, map: &dmm::Map, z_level: usize, bounds: &BoundingBox) {
+    const ALPHA: u32 = 96;
+
+    let dims = map.dim_xyz();
+    let tiles_x = bounds.right - bounds.left + 1;
+    let tiles_y = bounds.top - bounds.bottom + 1;
+    let tile_width = image.width as usize / tiles_x;
+    let tile_height = image.height as usize / tiles_y;
+
+    for tile_y in 0..tiles_y {
+        // Image rows go top to bottom, map coordinates go bottom to top
+        let y = bounds.top - tile_y;
+        for tile_x in 0..tiles_x {
+            let x = bounds.left + tile_x;
+            let prefabs = &map.dictionary[&map.grid[(z_level, dims.1 - y - 1, x)]];
+            let Some(area) = prefabs
+                .iter()
+                .find(|prefab| prefab.path.starts_with("/area"))
+            else {
+                continue;
+            };
+            let color = area_color(&area.path);
+            for py in tile_y * tile_height..(tile_y + 1) * tile_height {
+                for px in tile_x * tile_width..(tile_x + 1) * tile_width {
+                    for (c, value) in color.iter().enumerate() {
+                        let channel = &mut image.data[[py, px, c]];
+                        *channel =
+                            ((*channel as u32 * (255 - ALPHA) + *value as u32 * ALPHA) / 255) as u8;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Missing icons or a broken environment tend to parse fine and then render a flat color, which reviewers just see as a blank image.
+/// Anything that's one color, transparent, or has next to no pixels differing from that is flagged.
+fn looks_empty(image: &Image) -> bool {
+    // Out of a thousand pixels
+    const MIN_CONTENT: usize = 1;
+
+    let Some(pixels) = image.data.as_slice() else {
+        return false;
+    };
+    let total = pixels.len() / 4;
+    let Some(background) = pixels.get(..4) else {
+        return true;
+    };
+    let content = pixels
+        .chunks_exact(4)
+        .filter(|pixel| pixel[3] != 0 && *pixel != background)
+        .count();
+    content * 1000 < total * MIN_CONTENT
+}
+
+/// Draws a line every `spacing` tiles, labelled with the map coordinate the line starts at
+pub fn overlay_grid(image: &mut Image, bounds: &BoundingBox, spacing: usize) {
+    const ALPHA: u32 = 128;
+    const COLOR: [u8; 3] = [255, 255, 255];
+
+    if spacing == 0 {
+        return;
+    }
+
+    let tiles_x = bounds.right - bounds.left + 1;
+    let tiles_y = bounds.top - bounds.bottom + 1;
+    let width = image.width as usize;
+    let height = image.height as usize;
+    let tile_width = width / tiles_x;
+    let tile_height = height / tiles_y;
+
+    // Map coordinates are 1-indexed, bounds aren't
+    let columns: Vec<(usize, usize)> = (0..tiles_x)
+        .map(|tile_x| (tile_x * tile_width, bounds.left + tile_x + 1))
+        .filter(|(_, x)| x % spacing == 0)
+        .collect();
+    // Image rows go top to bottom, so a tile starts at its bottom edge
+    let rows: Vec<(usize, usize)> = (0..tiles_y)
+        .map(|tile_y| ((tile_y + 1) * tile_height - 1, bounds.top - tile_y + 1))
+        .filter(|(_, y)| y % spacing == 0)
+        .collect();
+
+    let mut blend = |px: usize, py: usize| {
+        for (c, value) in COLOR.iter().enumerate() {
+            let channel = &mut image.data[[py, px, c]];
+            *channel = ((*channel as u32 * (255 - ALPHA) + *value as u32 * ALPHA) / 255) as u8;
+        }
+    };
+    for &(px, _) in &columns {
+        for py in 0..height {
+            blend(px, py);
+        }
+    }
+    for &(py, _) in &rows {
+        for px in 0..width {
+            blend(px, py);
+        }
+    }
+
+    let Some(pixels) = image.data.as_slice_mut() else {
+        return;
+    };
+    let mut label = |(left, top): (usize, usize), text: String| {
+        draw_backdrop(
+            width,
+            height,
+            pixels,
+            (left, top),
+            (text_width(&text) + 2, TEXT_HEIGHT + 2),
+        );
+        draw_text(width, height, pixels, (left + 1, top + 1), &text, COLOR);
+    };
+    for (px, x) in columns {
+        label((px + 1, 0), x.to_string());
+    }
+    for (py, y) in rows {
+        label((0, py.saturating_sub(TEXT_HEIGHT + 2)), y.to_string());
+    }
+}
+
+const EXCLUDED_PASSES: [&str; 3] = ["hide-space", "hide-invisible", "random"];
+
+/// The render passes for a repo's maps, the usual ones unless one of its `render_pass_overrides` matches the map's path
+pub struct RenderPasses {
+    default: Vec<Box<dyn RenderPass>>,
+    overrides: Vec<(glob::Pattern, Vec<Box<dyn RenderPass>>)>,
+}
+
+impl RenderPasses {
+    pub fn new(
+        context: &RenderingContext,
+        lighting_passes: &[String],
+        overrides: &[RenderPassOverride],
+    ) -> Self {
+        let configure = |include: &[String], exclude: &[String]| {
+            // An override has the last word, whichever list the pass was on before
+            let included = lighting_passes
+                .iter()
+                .map(String::as_str)
+                .filter(|pass| !exclude.iter().any(|e| e == pass))
+                .chain(include.iter().map(String::as_str))
+                .collect::<Vec<_>>();
+            let random = crate::CONFIG.get().unwrap().random_spawners == RandomSpawners::Random;
+            let excluded = EXCLUDED_PASSES
+                .into_iter()
+                .filter(|pass| !(random && *pass == "random"))
+                .filter(|pass| !include.iter().any(|i| i == pass))
+                .chain(exclude.iter().map(String::as_str))
+                .collect::<Vec<_>>();
+            dmm_tools::render_passes::configure(
+                context.map_config(),
+                &included.join(","),
+                &excluded.join(","),
+            )
+        };
+
+        let overrides = overrides
+            .iter()
+            .filter_map(|o| match glob::Pattern::new(&o.pattern) {
+                Ok(pattern) => Some((pattern, configure(&o.include, &o.exclude))),
+                Err(e) => {
+                    warn!("Ignoring render pass override {:?}: {e}", o.pattern);
+                    None
+                }
+            })
+            .collect();
+
+        Self {
+            default: configure(&[], &[]),
+            overrides,
+        }
+    }
+
+    pub fn for_file(&self, filename: &str) -> &[Box<dyn RenderPass>] {
+        self.overrides
+            .iter()
+            .find(|(pattern, _)| pattern.matches(filename))
+            .map_or(self.default.as_slice(), |(_, passes)| passes.as_slice())
+    }
+
+    pub fn for_files(&self, files: &[&FileDiff]) -> Vec<&[Box<dyn RenderPass>]> {
+        files
+            .iter()
+            .map(|file| self.for_file(&file.filename))
+            .collect()
+    }
+}
+
+/// Renders every map that is present, returning one result per entry of `maps`.
+/// `None` entries are skipped but still take up an index, so output directories line up with the file list.
 pub fn render_map_regions(
     context: &RenderingContext,
-    maps: &[&MapWithRegions],
-    render_passes: &[Box<dyn RenderPass>],
+    maps: &[Option<&MapWithRegions>],
+    render_passes: &[&[Box<dyn RenderPass>]],
     output_dir: &Path,
     filename: &str,
     errors: &RenderingErrors,
-) -> Result<()> {
+    blank_renders: &BlankRenders,
+    budget: &OutputBudget,
+    retries: &RetryQueue,
+) -> Vec<Result<()>> {
     let objtree = &context.obj_tree;
     let icon_cache = &context.icon_cache;
-    let _: Result<()> = maps
-        .par_iter()
+    maps.par_iter()
         .enumerate()
         .map(|(idx, map)| {
-            for z_level in 0..map.map.dim_z() {
-                if let Some(bounds) = map
-                    .bounding_boxes
-                    .get(z_level)
-                    .expect("No bounding box generated for z-level")
-                {
-                    let image = render_map(
+            let Some(map) = map else {
+                return Ok(());
+            };
+            if budget.exceeded() {
+                return Err(eyre::anyhow!(
+                    "Not rendered, the job's output size limit was reached"
+                ));
+            }
+            catch_panic(|| {
+                let swapped = match crate::CONFIG.get().unwrap().random_spawners {
+                    RandomSpawners::First => first_variants(&map.map, objtree),
+                    _ => None,
+                };
+                let rendered_map = swapped.as_ref().unwrap_or(&map.map);
+                for (z_level, cluster, bounds) in map.iter_regions() {
+                    let bounds = &bounds.clamped_to(&map.map);
+                    let mut image = render_map(
                         objtree,
                         icon_cache,
-                        &map.map,
+                        rendered_map,
                         z_level,
                         bounds,
                         errors,
-                        render_passes,
+                        render_passes[idx],
                     )
                     .with_context(|| format!("Rendering map {idx}"))?;
 
+                    // Checked before any overlays, those would count as content
+                    let blank = looks_empty(&image);
+
+                    if crate::CONFIG.get().unwrap().area_overlay {
+                        overlay_areas(&mut image, &map.map, z_level, bounds);
+                    }
+
+                    if let Some(spacing) = crate::CONFIG.get().unwrap().grid_spacing {
+                        overlay_grid(&mut image, bounds, spacing);
+                    }
+
+                    if let (Some(text), Some(pixels)) =
+                        (watermark_text(), image.data.as_slice_mut())
+                    {
+                        draw_watermark(image.width as usize, image.height as usize, pixels, text);
+                    }
+
                     let directory = output_dir.join(Path::new(&idx.to_string()));
 
                     std::fs::create_dir_all(&directory).context("Creating directories")?;
-                    image
-                        .to_file(
-                            directory
-                                .join(Path::new(&format!("{z_level}-{filename}")))
-                                .as_ref(),
+                    let image_path = directory.join(Path::new(&format!(
+                        "{}-{filename}",
+                        region_image(z_level, cluster)
+                    )));
+                    if blank {
+                        warn!(
+                            "Render may have failed, {} appears empty",
+                            image_path.display()
+                        );
+                        blank_renders.write().unwrap().insert(image_path.clone());
+                    }
+                    if image.data.as_slice().is_none() {
+                        return Err(eyre::anyhow!("Image data isn't contiguous"));
+                    }
+                    let compression = crate::CONFIG.get().unwrap().png_compression;
+                    // Encoded straight to disk, whole map renders are big enough that a second copy in memory hurts.
+                    // A retry encodes again, that's cheaper than keeping the PNG around.
+                    // One that still fails is missing from the output, which shows it as failed
+                    let written = retries.write(&image_path, move |path| {
+                        encode_rgba_to_file(
+                            image.width,
+                            image.height,
+                            image.data.as_slice().unwrap(),
+                            compression,
+                            path,
                         )
-                        .with_context(|| format!("Saving image {idx}"))?;
+                        .with_context(|| format!("Saving image {idx}"))
+                    });
+                    if let Some(size) = written {
+                        budget.add(size);
+                    }
                 }
-            }
-            Ok(())
+                Ok(())
+            })
         })
-        .collect();
-    Ok(())
+        .collect()
+}
+
+/// Roughly how wide a zoomed inset ends up, smaller renders get scaled up until they're about this wide
+const ZOOM_INSET_WIDTH: u32 = 640;
+const ZOOM_INSET_GAP: u32 = 8;
+
+/// Regions bigger than this either way are scaled down to fit on a contact sheet
+const CONTACT_SHEET_CELL: u32 = 480;
+const CONTACT_SHEET_GAP: u32 = 8;
+
+/// Tiles the diffs of every region of a modified map into one labelled grid, for a look at all of its z-levels at once.
+/// `regions` are the label and name (see [`region_image`]) of each one. Returns how many bytes were written.
+pub fn write_contact_sheet(directory: &Path, regions: &[(String, String)]) -> Result<u64> {
+    let cells = regions
+        .iter()
+        .map(|(label, image)| {
+            let path = directory.join(format!("{image}-diff.png"));
+            let diff = Reader::open(&path)
+                .with_context(|| format!("Opening {}", path.display()))?
+                .decode()?
+                .to_rgba8();
+            let longest = max(diff.width(), diff.height());
+            let diff = if longest > CONTACT_SHEET_CELL {
+                imageops::resize(
+                    &diff,
+                    (diff.width() * CONTACT_SHEET_CELL / longest).max(1),
+                    (diff.height() * CONTACT_SHEET_CELL / longest).max(1),
+                    // Smoothing blends the highlighted pixels into their neighbours, showing faint changes that aren't there
+                    FilterType::Nearest,
+                )
+            } else {
+                diff
+            };
+            Ok((label, diff))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let label_height = TEXT_HEIGHT as u32 + 2;
+    let cell_width = cells
+        .iter()
+        .map(|(label, image)| max(image.width(), text_width(label) as u32 + 2))
+        .max()
+        .unwrap_or(1);
+    let cell_height = label_height
+        + cells
+            .iter()
+            .map(|(_, image)| image.height())
+            .max()
+            .unwrap_or(1);
+    let columns = (cells.len() as f64).sqrt().ceil().max(1.0) as u32;
+    let rows = (cells.len() as u32 + columns - 1) / columns;
+
+    let mut sheet = image::RgbaImage::new(
+        columns * (cell_width + CONTACT_SHEET_GAP),
+        rows * (cell_height + CONTACT_SHEET_GAP),
+    );
+    let (width, height) = (sheet.width() as usize, sheet.height() as usize);
+    for (n, (label, image)) in cells.iter().enumerate() {
+        let left = n as u32 % columns * (cell_width + CONTACT_SHEET_GAP);
+        let top = n as u32 / columns * (cell_height + CONTACT_SHEET_GAP);
+        imageops::overlay(&mut sheet, image, left as i64, (top + label_height) as i64);
+        draw_backdrop(
+            width,
+            height,
+            &mut sheet,
+            (left as usize, top as usize),
+            (text_width(label) + 2, TEXT_HEIGHT + 2),
+        );
+        draw_text(
+            width,
+            height,
+            &mut sheet,
+            (left as usize + 1, top as usize + 1),
+            label,
+            [255, 255, 255],
+        );
+    }
+
+    let conf = crate::CONFIG.get().unwrap();
+    let mut size = 0;
+    write_with_retries(
+        &directory.join("overview.png"),
+        conf.image_write_retries,
+        |path| {
+            size = encode_rgba_to_file(
+                sheet.width(),
+                sheet.height(),
+                &sheet,
+                conf.png_compression,
+                path,
+            )
+            .context("Saving contact sheet")?;
+            Ok(())
+        },
+    )?;
+    Ok(size)
 }
 
-pub fn render_diffs_for_directory<P: AsRef<Path>>(directory: P) {
+/// Scales the before and after renders of a region up and puts them side by side, for changes too small to make out otherwise.
+/// `image` is the region's name, see [`region_image`].
+/// Returns how many bytes were written, or None if the renders are already too big to be worth zooming.
+pub fn write_zoom_inset(directory: &Path, image: &str) -> Result<Option<u64>> {
+    let load = |kind: &str| -> Result<image::RgbaImage> {
+        let path = directory.join(format!("{image}-{kind}.png"));
+        Ok(Reader::open(&path)
+            .with_context(|| format!("Opening {}", path.display()))?
+            .decode()?
+            .to_rgba8())
+    };
+    let (before, after) = (load("before")?, load("after")?);
+
+    let scale = ZOOM_INSET_WIDTH / (before.width() + after.width()).max(1);
+    if scale < 2 {
+        return Ok(None);
+    }
+    let [before, after] = [before, after].map(|image| {
+        imageops::resize(
+            &image,
+            image.width() * scale,
+            image.height() * scale,
+            FilterType::Nearest,
+        )
+    });
+
+    // Bottom aligned, like the diffs
+    let height = max(before.height(), after.height());
+    let mut inset = image::RgbaImage::new(before.width() + ZOOM_INSET_GAP + after.width(), height);
+    imageops::overlay(&mut inset, &before, 0, (height - before.height()) as i64);
+    imageops::overlay(
+        &mut inset,
+        &after,
+        (before.width() + ZOOM_INSET_GAP) as i64,
+        (height - after.height()) as i64,
+    );
+
+    let conf = crate::CONFIG.get().unwrap();
+    let png = encode_rgba(inset.width(), inset.height(), &inset, conf.png_compression)
+        .context("Encoding zoomed inset")?;
+    write_with_retries(
+        &directory.join(format!("{image}-zoom.png")),
+        conf.image_write_retries,
+        |path| std::fs::write(path, &png).context("Saving zoomed inset"),
+    )?;
+    Ok(Some(png.len() as u64))
+}
+
+/// Returns how many diffs were written and their size in bytes, not counting any that failed and were queued in `retries`
+pub fn render_diffs_for_directory<P: AsRef<Path>>(
+    directory: P,
+    retries: &RetryQueue,
+) -> (usize, u64) {
     let directory = directory.as_ref();
 
     glob::glob(directory.join("*-before.png").to_str().unwrap())
@@ -350,21 +1205,55 @@ pub fn render_diffs_for_directory<P: AsRef<Path>>(directory: P) {
             let before = Reader::open(&entry)?.decode()?;
             let after = Reader::open(replaced_entry)?.decode()?;
 
-            ImageBuffer::from_fn(after.width(), after.height(), |x, y| {
-                let before_pixel = before.get_pixel(x, y);
-                let after_pixel = after.get_pixel(x, y);
+            // Renders of a resized map differ in size but share the bottom left corner,
+            // so line them up along the bottom and leave whatever only one of them covers transparent
+            let width = max(before.width(), after.width());
+            let height = max(before.height(), after.height());
+            let pixel_at = |image: &image::DynamicImage, x: u32, y: u32| {
+                let offset = height - image.height();
+                if x < image.width() && y >= offset {
+                    image.get_pixel(x, y - offset)
+                } else {
+                    image::Rgba([0, 0, 0, 0])
+                }
+            };
+
+            let mut diff = ImageBuffer::from_fn(width, height, |x, y| {
+                let before_pixel = pixel_at(&before, x, y);
+                let after_pixel = pixel_at(&after, x, y);
                 if before_pixel == after_pixel {
                     after_pixel.map_without_alpha(|c| c.saturating_add((255 - c) / 3))
                 } else {
                     image::Rgba([255, 0, 0, 255])
                 }
-            })
-            .save(fuck.replace("-before.png", "-diff.png"))?;
+            });
+            if let Some(text) = watermark_text() {
+                draw_watermark(
+                    diff.width() as usize,
+                    diff.height() as usize,
+                    &mut diff,
+                    text,
+                );
+            }
+            let diff_path = fuck.replace("-before.png", "-diff.png");
+            let compression = crate::CONFIG.get().unwrap().png_compression;
+            let written = retries.write(Path::new(&diff_path), move |path| {
+                encode_rgba_to_file(diff.width(), diff.height(), &diff, compression, path)
+                    .context("Saving diff image")
+            });
 
-            Ok(())
+            Ok(written)
+        })
+        .filter_map(|r: Result<Option<u64>>| match r {
+            Ok(written) => written,
+            Err(e) => {
+                error!("Diff rendering error: {}", e);
+                None
+            }
         })
-        .filter_map(|r: Result<()>| r.err())
-        .for_each(|e| {
-            error!("Diff rendering error: {}", e);
-        });
+        .map(|size| (1, size))
+        .reduce(
+            || (0, 0),
+            |(count, bytes), (more, size)| (count + more, bytes + size),
+        )
 }
@@ -0,0 +1,70 @@
+//! Keeps `repos_dir` under a size limit by throwing out the checkouts that haven't been used in the longest time.
+//! Evicted repos are cloned again by the next job that needs them.
+
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use diffbot_lib::log;
+use eyre::{Context, Result};
+
+/// Atime is unreliable with relatime/noatime mounts, so jobs leave their own timestamp behind
+const LAST_USED_MARKER: &str = "mdb_last_used";
+
+pub fn mark_used(repo_dir: &Path) {
+    if let Err(e) = std::fs::write(repo_dir.join(".git").join(LAST_USED_MARKER), b"") {
+        log::warn!("Failed to mark {} as used: {e}", repo_dir.display());
+    }
+}
+
+fn last_used(repo_dir: &Path) -> SystemTime {
+    std::fs::metadata(repo_dir.join(".git").join(LAST_USED_MARKER))
+        .and_then(|meta| meta.modified())
+        .or_else(|_| std::fs::metadata(repo_dir).and_then(|meta| meta.accessed()))
+        .unwrap_or(SystemTime::UNIX_EPOCH)
+}
+
+fn disk_usage(dir: &Path) -> u64 {
+    walkdir::WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|meta| meta.is_file())
+        .map(|meta| meta.len())
+        .sum()
+}
+
+pub fn evict_least_recently_used(repos_dir: &Path, limit: u64) -> Result<()> {
+    let mut repos: Vec<(PathBuf, u64, SystemTime)> = walkdir::WalkDir::new(repos_dir)
+        .min_depth(2)
+        .max_depth(2)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_dir())
+        .map(|entry| {
+            let path = entry.into_path();
+            let size = disk_usage(&path);
+            let used = last_used(&path);
+            (path, size, used)
+        })
+        .collect();
+
+    let mut total: u64 = repos.iter().map(|(_, size, _)| size).sum();
+    log::info!("Repos are using {total} bytes, limit is {limit}");
+
+    repos.sort_by_key(|(_, _, used)| *used);
+
+    for (path, size, _) in repos {
+        if total <= limit {
+            break;
+        }
+        log::info!("Evicting {} to free {size} bytes", path.display());
+        std::fs::remove_dir_all(&path).with_context(|| format!("Removing {}", path.display()))?;
+        total = total.saturating_sub(size);
+        // Drop the owner directory too once its last repo is gone, remove_dir refuses if it isn't empty
+        if let Some(owner) = path.parent() {
+            let _ = std::fs::remove_dir(owner);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,129 @@
+//! A single page with every render of a job, for PRs too big to comfortably review through the check output.
+//! Images are linked relative to the page, so it works wherever the job's image directory is served from.
+
+use std::path::Path;
+
+use diffbot_lib::github::github_types::FileDiff;
+use diffbot_lib::job::types::Job;
+use eyre::{Context, Result};
+
+use crate::job_processor::RenderedMaps;
+use crate::rendering::{region_image, MapWithRegions};
+
+pub const REPORT_FILENAME: &str = "report.html";
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn error_html(error: &eyre::Report) -> String {
+    format!("<p class=\"error\">{}</p>\n", escape(&format!("{error:?}")))
+}
+
+fn single_image_levels(map: &MapWithRegions, image: impl Fn(usize) -> String) -> String {
+    map.iter_levels()
+        .map(|(level, bounds)| {
+            format!(
+                "<div class=\"level\">\n<h3>Z-level {} <small>{}</small></h3>\n<div class=\"view\"><img src=\"{}\" loading=\"lazy\"></div>\n</div>\n",
+                level + 1,
+                escape(&bounds.to_string()),
+                image(level),
+            )
+        })
+        .collect()
+}
+
+fn modified_level(image: &str, heading: &str, bounds: &str) -> String {
+    format!(
+        r#"<div class="level">
+<h3>{heading} <small>{bounds}</small></h3>
+<div class="modes"><button data-mode="side" class="active">Side by side</button><button data-mode="slider">Slider</button><button data-mode="diff">Difference</button></div>
+<div class="view side"><img src="{image}-before.png" loading="lazy"><img src="{image}-after.png" loading="lazy"></div>
+<div class="view slider" hidden><div class="slider-frame"><img src="{image}-after.png" loading="lazy"><div class="slider-before"><img src="{image}-before.png" loading="lazy"></div></div><input type="range" min="0" max="100" value="50"></div>
+<div class="view diff" hidden><img src="{image}-diff.png" loading="lazy"></div>
+</div>
+"#,
+        bounds = escape(bounds),
+    )
+}
+
+pub fn write_html_report(
+    job: &Job,
+    (added_files, modified_files, removed_files): (&[&FileDiff], &[&FileDiff], &[&FileDiff]),
+    maps: &RenderedMaps,
+    file_directory: &Path,
+) -> Result<()> {
+    let mut sections = String::new();
+    let mut section = |status: &str, filename: &str, body: String| {
+        sections.push_str(&format!(
+            "<section>\n<h2>{status} - {}</h2>\n{body}</section>\n",
+            escape(filename)
+        ));
+    };
+
+    for (file_index, (file, map)) in added_files.iter().zip(maps.added_maps.iter()).enumerate() {
+        let body = match map {
+            Ok(map) => {
+                single_image_levels(map, |level| format!("a/{file_index}/{level}-added.png"))
+            }
+            Err(e) => error_html(e),
+        };
+        section("ADDED", &file.filename, body);
+    }
+
+    for (file_index, (file, map)) in modified_files
+        .iter()
+        .zip(maps.modified_maps.befores.iter())
+        .enumerate()
+    {
+        let body = match map {
+            Ok(map) => map
+                .iter_regions()
+                .map(|(level, cluster, bounds)| {
+                    let heading = match cluster {
+                        Some(cluster) => format!("Z-level {}, region {}", level + 1, cluster + 1),
+                        None => format!("Z-level {}", level + 1),
+                    };
+                    modified_level(
+                        &format!("m/{file_index}/{}", region_image(level, cluster)),
+                        &heading,
+                        &bounds.to_string(),
+                    )
+                })
+                .collect(),
+            Err(e) => error_html(e),
+        };
+        section("MODIFIED", &file.filename, body);
+    }
+
+    for (file_index, (file, map)) in removed_files
+        .iter()
+        .zip(maps.removed_maps.iter())
+        .enumerate()
+    {
+        let body = match map {
+            Ok(map) => {
+                single_image_levels(map, |level| format!("r/{file_index}/{level}-removed.png"))
+            }
+            Err(e) => error_html(e),
+        };
+        section("REMOVED", &file.filename, body);
+    }
+
+    let title = escape(&format!(
+        "Map renderings for {}#{}",
+        job.repo.full_name(),
+        job.pull_request
+    ));
+    let report = include_str!("../templates/report.html")
+        .replace("{{title}}", &title)
+        .replace("{{maps}}", &sections);
+
+    std::fs::create_dir_all(file_directory).context("Creating directories")?;
+    std::fs::write(file_directory.join(REPORT_FILENAME), report).context("Writing report")?;
+
+    Ok(())
+}
@@ -0,0 +1,211 @@
+//! Machine readable summary of a job's renders, for tooling that would otherwise have to scrape the check output
+//! The latest one for each pull is also kept under `./results` for the results API.
+
+use std::path::{Path, PathBuf};
+
+use diffbot_lib::github::github_types::FileDiff;
+use diffbot_lib::job::types::Job;
+use eyre::{Context, Result};
+use serde::Serialize;
+
+use crate::job_processor::RenderedMaps;
+use crate::rendering::{count_changed_tiles, region_image, MapWithRegions};
+
+pub const RESULT_FILENAME: &str = "result.json";
+const LATEST_DIRECTORY: &str = "./results";
+
+/// Where the latest result for a pull is kept, overwritten by every job for it
+pub fn latest_result_path(repo_id: u64, pull_request: u64) -> PathBuf {
+    Path::new(LATEST_DIRECTORY)
+        .join(repo_id.to_string())
+        .join(format!("{pull_request}.json"))
+}
+
+#[derive(Serialize)]
+pub struct JobResult {
+    repository: String,
+    repository_id: u64,
+    pull_request: u64,
+    base_sha: String,
+    head_sha: String,
+    /// "success" if every map rendered, "partial" if some failed, "failure" if all of them did
+    conclusion: &'static str,
+    counts: Counts,
+    maps: Vec<MapResult>,
+}
+
+#[derive(Serialize, Default)]
+struct Counts {
+    added: usize,
+    modified: usize,
+    removed: usize,
+    errored: usize,
+    images: usize,
+}
+
+#[derive(Serialize)]
+struct MapResult {
+    filename: String,
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    levels: Vec<LevelResult>,
+}
+
+#[derive(Serialize)]
+struct LevelResult {
+    /// 1-indexed, like in game
+    z: usize,
+    /// 1-indexed too, only for z-levels split into several regions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    region: Option<usize>,
+    bounds: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    changed_tiles: Option<usize>,
+    images: Vec<String>,
+}
+
+fn whole_map_result(
+    file: &FileDiff,
+    map: &Result<MapWithRegions>,
+    status: &'static str,
+    link: impl Fn(usize) -> String,
+) -> MapResult {
+    match map {
+        Ok(map) => MapResult {
+            filename: file.filename.clone(),
+            status,
+            error: None,
+            levels: map
+                .iter_levels()
+                .map(|(level, bounds)| LevelResult {
+                    z: level + 1,
+                    region: None,
+                    bounds: bounds.to_string(),
+                    changed_tiles: None,
+                    images: vec![link(level)],
+                })
+                .collect(),
+        },
+        Err(e) => MapResult {
+            filename: file.filename.clone(),
+            status,
+            error: Some(format!("{e:?}")),
+            levels: vec![],
+        },
+    }
+}
+
+pub fn job_result(
+    job: &Job,
+    (added_files, modified_files, removed_files): (&[&FileDiff], &[&FileDiff], &[&FileDiff]),
+    maps: &RenderedMaps,
+    link_base: &str,
+) -> JobResult {
+    let mut results = vec![];
+
+    for (file_index, (file, map)) in added_files.iter().zip(maps.added_maps.iter()).enumerate() {
+        results.push(whole_map_result(file, map, "added", |level| {
+            format!("{link_base}/a/{file_index}/{level}-added.png")
+        }));
+    }
+
+    for (file_index, (file, (before, after))) in modified_files
+        .iter()
+        .zip(
+            maps.modified_maps
+                .befores
+                .iter()
+                .zip(maps.modified_maps.afters.iter()),
+        )
+        .enumerate()
+    {
+        let result = match (before, after) {
+            (Ok(before), Some(after)) => MapResult {
+                filename: file.filename.clone(),
+                status: "modified",
+                error: None,
+                levels: before
+                    .iter_regions()
+                    .map(|(level, cluster, bounds)| {
+                        let link = format!(
+                            "{link_base}/m/{file_index}/{}",
+                            region_image(level, cluster)
+                        );
+                        LevelResult {
+                            z: level + 1,
+                            region: cluster.map(|cluster| cluster + 1),
+                            bounds: bounds.to_string(),
+                            changed_tiles: Some(count_changed_tiles(
+                                &before.map,
+                                &after.map,
+                                level,
+                            )),
+                            images: ["before", "after", "diff"]
+                                .iter()
+                                .map(|kind| format!("{link}-{kind}.png"))
+                                .collect(),
+                        }
+                    })
+                    .collect(),
+            },
+            (Err(e), _) => MapResult {
+                filename: file.filename.clone(),
+                status: "modified",
+                error: Some(format!("{e:?}")),
+                levels: vec![],
+            },
+            (Ok(_), None) => unreachable!("Modified map has a before but no after"),
+        };
+        results.push(result);
+    }
+
+    for (file_index, (file, map)) in removed_files
+        .iter()
+        .zip(maps.removed_maps.iter())
+        .enumerate()
+    {
+        results.push(whole_map_result(file, map, "removed", |level| {
+            format!("{link_base}/r/{file_index}/{level}-removed.png")
+        }));
+    }
+
+    let counts = Counts {
+        added: added_files.len(),
+        modified: modified_files.len(),
+        removed: removed_files.len(),
+        errored: results.iter().filter(|map| map.error.is_some()).count(),
+        images: results
+            .iter()
+            .flat_map(|map| &map.levels)
+            .map(|level| level.images.len())
+            .sum(),
+    };
+    let conclusion = match counts.errored {
+        0 => "success",
+        errored if errored < results.len() => "partial",
+        _ => "failure",
+    };
+
+    JobResult {
+        repository: job.repo.full_name(),
+        repository_id: job.repo.id,
+        pull_request: job.pull_request,
+        base_sha: job.base.sha.clone(),
+        head_sha: job.head.sha.clone(),
+        conclusion,
+        counts,
+        maps: results,
+    }
+}
+
+pub fn write_result_json(result: &JobResult, path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).context("Creating directories")?;
+    }
+    let file = std::fs::File::create(path).context("Creating result file")?;
+    serde_json::to_writer_pretty(std::io::BufWriter::new(file), result)
+        .context("Writing result file")?;
+
+    Ok(())
+}
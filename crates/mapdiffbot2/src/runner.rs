@@ -1,115 +1,121 @@
-use std::path::PathBuf;
 use std::time::Duration;
 
 use super::job_processor::do_job;
-use diffbot_lib::job::types::{Job, JobType};
+use diffbot_lib::job::{
+    in_flight::InFlight,
+    runner::{fail_job, FailureCategory},
+    types::{Job, JobReceiver, JobType},
+};
 
 use diffbot_lib::log;
 
-pub async fn handle_jobs<S: AsRef<str>>(name: S, mut job_receiver: yaque::Receiver) {
-    loop {
-        match job_receiver.recv().await {
-            Ok(jobguard) => {
-                log::info!("Job received from queue");
-                let job: Result<JobType, serde_json::Error> = serde_json::from_slice(&jobguard);
-                match job {
-                    Ok(job) => match job {
-                        JobType::GithubJob(job) => job_handler(name.as_ref(), *job).await,
-                        JobType::CleanupJob(_) => garbage_collect_all_repos().await,
-                    },
-                    Err(err) => log::error!("Failed to parse job from queue: {}", err),
-                }
-                if let Err(err) = jobguard.commit() {
-                    log::error!("Failed to commit change to queue: {}", err)
-                };
+pub async fn handle_jobs<S: AsRef<str>>(name: S, job_receiver: JobReceiver) {
+    let name = name.as_ref();
+    job_receiver
+        .run(|job| async move {
+            log::info!("Job received from queue");
+            let job: Result<JobType, serde_json::Error> = serde_json::from_slice(&job);
+            match job {
+                Ok(job) => match job {
+                    JobType::GithubJob(job) => job_handler(name, *job).await,
+                    JobType::CleanupJob(_) => garbage_collect_all_repos().await,
+                },
+                Err(err) => log::error!("Failed to parse job from queue: {}", err),
             }
-            Err(err) => log::error!("{}", err),
-        }
-    }
+        })
+        .await
 }
 
-async fn garbage_collect_all_repos() {
+/// Runs `git gc` on one repo, failures are only logged
+fn gc_repo(path: &std::path::Path) {
     use eyre::Result;
     use path_absolutize::Absolutize;
     use std::process::Command;
+
+    //tfw no try blocks
+    if let Err(err) = || -> Result<()> {
+        let path = path.absolutize()?;
+        let output = Command::new("git").current_dir(&path).arg("gc").status()?;
+        if !output.success() {
+            match output.code() {
+                Some(num) => log::error!("GC failed on dir {} with code {}", path.display(), num),
+                None => log::error!("GC failed on dir {}, process terminated!", path.display()),
+            }
+        }
+        Ok(())
+    }() {
+        log::error!("{}", err);
+    }
+}
+
+async fn garbage_collect_all_repos() {
+    use eyre::{Context, Result};
     log::info!("Garbage collection starting!");
 
-    let output = actix_web::rt::time::timeout(
-        Duration::from_secs(3600),
-        //tfw no try blocks
-        actix_web::rt::task::spawn_blocking(move || -> Result<()> {
-            let path = PathBuf::from("./repos");
-            if !path.exists() {
-                log::info!("Repo path doesn't exist, skipping GC");
-                return Ok(());
+    let conf = crate::CONFIG.get().unwrap();
+    let output = actix_web::rt::time::timeout(Duration::from_secs(3600), async {
+        let path = conf.repos_dir.clone();
+        if !path.exists() {
+            log::info!("Repo path doesn't exist, skipping GC");
+            return Ok(());
+        }
+        let repos = walkdir::WalkDir::new(path)
+            .min_depth(2)
+            .max_depth(2)
+            .into_iter()
+            .filter_map(|entry| {
+                entry
+                    .map_err(|err| log::error!("Walkdir failed: {}", err))
+                    .ok()
+            })
+            .map(walkdir::DirEntry::into_path)
+            .collect::<Vec<_>>();
+
+        // A few at a time with a breather in between, so a big repos directory doesn't hog the disk from running jobs
+        for (idx, batch) in repos.chunks(conf.gc_batch_size.max(1)).enumerate() {
+            if idx > 0 {
+                actix_web::rt::time::sleep(Duration::from_secs(conf.gc_batch_delay_secs)).await;
             }
-            for entry in walkdir::WalkDir::new(path).min_depth(2).max_depth(2) {
-                match entry {
-                    Ok(entry) => {
-                        let path = entry.into_path();
-                        //tfw no try blocks
-                        if let Err(err) = || -> Result<()> {
-                            let path = path.absolutize()?;
-                            let output =
-                                Command::new("git").current_dir(&path).arg("gc").status()?;
-                            if !output.success() {
-                                match output.code() {
-                                    Some(num) => log::error!(
-                                        "GC failed on dir {} with code {}",
-                                        path.display(),
-                                        num
-                                    ),
-                                    None => log::error!(
-                                        "GC failed on dir {}, process terminated!",
-                                        path.display(),
-                                    ),
-                                }
-                            }
-                            Ok(())
-                        }() {
-                            log::error!("{}", err);
-                        }
+            let batch = batch.to_vec();
+            actix_web::rt::task::spawn_blocking(move || {
+                std::thread::scope(|scope| {
+                    for path in &batch {
+                        scope.spawn(move || gc_repo(path));
                     }
-                    Err(err) => log::error!("Walkdir failed: {}", err),
-                }
-            }
-            Ok(())
-        }),
-    )
-    .await;
-
-    log::info!("Garbage collection finished!");
+                });
+            })
+            .await
+            .context("GC batch panicked")?;
+        }
 
-    let output = {
-        if output.is_err() {
-            log::error!("GC timed out!");
-            return;
+        if let Some(limit) = conf.max_repos_disk_bytes {
+            actix_web::rt::task::spawn_blocking(move || -> Result<()> {
+                crate::repo_cache::evict_least_recently_used(&conf.repos_dir, limit)
+                    .context("Evicting repos")
+            })
+            .await
+            .context("Evicting repos panicked")??;
         }
-        output.unwrap()
-    };
+        Ok::<(), eyre::Report>(())
+    })
+    .await;
 
-    if let Err(e) = output {
-        let fuckup = match e.try_into_panic() {
-            Ok(panic) => match panic.downcast_ref::<&str>() {
-                Some(s) => s.to_string(),
-                None => "*crickets*".to_owned(),
-            },
-            Err(e) => e.to_string(),
-        };
-        log::error!("Join Handle error: {}", fuckup);
-        return;
-    }
+    log::info!("Garbage collection finished!");
 
-    let output = output.unwrap();
-    if let Err(e) = output {
-        let fuckup = format!("{e:?}");
-        log::error!("GC errored: {}", fuckup);
+    match output {
+        Err(_) => log::error!("GC timed out!"),
+        Ok(Err(e)) => log::error!("GC errored: {:?}", e),
+        Ok(Ok(())) => {}
     }
 }
 
 async fn job_handler(name: &str, job: Job) {
-    let (repo, pull_request, check_run) =
-        (job.repo.clone(), job.pull_request, job.check_run.clone());
+    let (repo, pull_request, check_run, installation) = (
+        job.repo.clone(),
+        job.pull_request,
+        job.check_run.clone(),
+        job.installation,
+    );
     log::info!(
         "[{}#{}] [{}] Starting",
         repo.full_name(),
@@ -117,11 +123,41 @@ async fn job_handler(name: &str, job: Job) {
         check_run.id()
     );
 
-    let _ = check_run.mark_started().await;
+    if !diffbot_lib::job::runner::check_repo_available(&job).await {
+        return;
+    }
+
+    // Queued before someone turned rendering off
+    if diffbot_lib::job::opt_out::is_opted_out((repo.id, pull_request)) {
+        let _ = check_run
+            .mark_skipped(crate::github_processor::opted_out_output())
+            .await;
+        return;
+    }
+
+    let conf = crate::CONFIG.get().unwrap();
+    let notify = (
+        conf.failure_webhook_url.as_deref(),
+        conf.support_link.as_deref(),
+    );
+
+    let store_wait = Duration::from_secs(conf.image_store_wait_secs);
+    if !diffbot_lib::job::runner::wait_for_image_store(&job, store_wait, notify).await {
+        return;
+    }
+
+    let _ = check_run.mark_started(job.queue_wait()).await;
+
+    if let Some(template) = &crate::CONFIG.get().unwrap().details_url {
+        let _ = check_run.set_details_url(&job.details_url(template)).await;
+    }
+
+    // Held until the job's output is posted, a closed PR's images are only cleaned up after that
+    let _in_flight = InFlight::start((repo.id, pull_request));
 
     let output = actix_web::rt::time::timeout(
         Duration::from_secs(3600),
-        actix_web::rt::task::spawn_blocking(move || do_job(job)),
+        actix_web::rt::task::spawn_blocking(move || do_job(job, crate::RENDER_LIMITER.get())),
     )
     .await;
 
@@ -132,10 +168,20 @@ async fn job_handler(name: &str, job: Job) {
         check_run.id()
     );
 
+    crate::metrics::job_processed(&repo);
+
     let output = {
         if output.is_err() {
             log::error!("Job timed out!");
-            let _ = check_run.mark_failed("Job timed out after 1 hours!").await;
+            crate::metrics::job_failed(&repo);
+            fail_job(
+                &check_run,
+                (&repo, pull_request),
+                FailureCategory::Timeout,
+                "Job timed out after 1 hours!",
+                notify,
+            )
+            .await;
             return;
         }
         output.unwrap()
@@ -150,7 +196,15 @@ async fn job_handler(name: &str, job: Job) {
             Err(e) => e.to_string(),
         };
         log::error!("Join Handle error: {}", fuckup);
-        let _ = check_run.mark_failed(&fuckup).await;
+        crate::metrics::job_failed(&repo);
+        fail_job(
+            &check_run,
+            (&repo, pull_request),
+            FailureCategory::Panic,
+            &fuckup,
+            notify,
+        )
+        .await;
         return;
     }
 
@@ -158,10 +212,38 @@ async fn job_handler(name: &str, job: Job) {
     if let Err(e) = output {
         let fuckup = format!("{e:?}");
         log::error!("Other rendering error: {}", fuckup);
-        let _ = check_run.mark_failed(&fuckup).await;
+        crate::metrics::job_failed(&repo);
+        fail_job(
+            &check_run,
+            (&repo, pull_request),
+            FailureCategory::Error,
+            &fuckup,
+            notify,
+        )
+        .await;
         return;
     }
 
     let output = output.unwrap();
-    diffbot_lib::job::runner::handle_output(output, check_run, name).await;
+    // An empty output means nothing visually changed
+    if !output.is_empty() {
+        diffbot_lib::job::runner::apply_labels(
+            &installation,
+            &repo,
+            pull_request,
+            &crate::CONFIG.get().unwrap().change_labels,
+        )
+        .await;
+    }
+    diffbot_lib::job::runner::handle_output(
+        output,
+        check_run,
+        diffbot_lib::github::github_api::check_name(
+            name,
+            crate::CONFIG.get().unwrap().check_name_suffix.as_deref(),
+            &repo,
+        ),
+        &crate::CONFIG.get().unwrap().no_changes_message,
+    )
+    .await;
 }
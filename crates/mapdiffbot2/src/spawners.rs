@@ -0,0 +1,54 @@
+//! Random spawners swapped out for the first thing they can spawn, see `random_spawners`.
+//! The random render pass picks something different every render, which hides changes to what a spawner can spawn.
+
+use dmm_tools::dmm;
+use dreammaker::constants::Constant;
+use dreammaker::objtree::ObjectTree;
+
+/// What the spawner's `loot` list starts with, None if it isn't a spawner with one
+fn first_loot(prefab: &dmm::Prefab, objtree: &ObjectTree) -> Option<dmm::Prefab> {
+    let ty = objtree.find(&prefab.path)?;
+    let loot = prefab.vars.get("loot").or_else(|| {
+        ty.get_value("loot")
+            .and_then(|value| value.constant.as_ref())
+    })?;
+    let Constant::List(entries) = loot else {
+        return None;
+    };
+    let Some((Constant::Prefab(pop), _)) = entries.first() else {
+        return None;
+    };
+    Some(dmm::Prefab {
+        path: format!("/{}", pop.path.join("/")),
+        vars: Default::default(),
+    })
+}
+
+/// A copy of `map` with every spawner replaced, None if there weren't any to replace
+pub fn first_variants(map: &dmm::Map, objtree: &ObjectTree) -> Option<dmm::Map> {
+    let replacements = map
+        .dictionary
+        .iter()
+        .filter_map(|(key, prefabs)| {
+            let mut swapped_any = false;
+            let swapped = prefabs
+                .iter()
+                .map(|prefab| match first_loot(prefab, objtree) {
+                    Some(loot) => {
+                        swapped_any = true;
+                        loot
+                    }
+                    None => prefab.clone(),
+                })
+                .collect::<Vec<_>>();
+            swapped_any.then_some((*key, swapped))
+        })
+        .collect::<Vec<_>>();
+    if replacements.is_empty() {
+        return None;
+    }
+
+    let mut map = map.clone();
+    map.dictionary.extend(replacements);
+    Some(map)
+}
@@ -0,0 +1,209 @@
+//! Var edits that changed on the tiles a PR touched, the kind of change a render shows subtly or not at all.
+
+use std::cmp::min;
+
+use dmm_tools::dmm;
+use dreammaker::constants::Constant;
+
+use crate::rendering::same_tile;
+
+/// Past this a map's list stops being a quick glance
+const MAX_LISTED: usize = 20;
+
+/// An object nudged within its tile, on a tile where nothing else changed
+pub struct OffsetChange {
+    /// 1-indexed with y counting up, like in game
+    pub coords: (usize, usize, usize),
+    pub path: String,
+    pub dx: f32,
+    pub dy: f32,
+}
+
+impl std::fmt::Display for OffsetChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (x, y, z) = self.coords;
+        write!(
+            f,
+            "`{}` at ({x}, {y}, {z}): pixel offset change:",
+            self.path
+        )?;
+        if self.dx != 0.0 {
+            write!(f, " {:+}x", self.dx)?;
+        }
+        if self.dy != 0.0 {
+            write!(f, " {:+}y", self.dy)?;
+        }
+        Ok(())
+    }
+}
+
+/// Relative to the type's default, which is 0 for just about everything
+fn offset(prefab: &dmm::Prefab, var: &str) -> f32 {
+    match prefab.vars.get(var) {
+        Some(Constant::Float(value)) => *value,
+        _ => 0.0,
+    }
+}
+
+fn without_offsets(prefab: &dmm::Prefab) -> dmm::Prefab {
+    let mut prefab = prefab.clone();
+    prefab.vars.remove("pixel_x");
+    prefab.vars.remove("pixel_y");
+    prefab
+}
+
+/// Pairs up the objects of two versions of a tile, None if anything besides offsets changed
+fn nudged_prefabs(
+    before: &[dmm::Prefab],
+    after: &[dmm::Prefab],
+) -> Option<Vec<(String, f32, f32)>> {
+    if before.len() != after.len() {
+        return None;
+    }
+    let mut unmatched: Vec<(dmm::Prefab, &dmm::Prefab)> = after
+        .iter()
+        .map(|prefab| (without_offsets(prefab), prefab))
+        .collect();
+    let mut nudged = vec![];
+    for prefab in before {
+        let stripped = without_offsets(prefab);
+        // An untouched twin on the same tile shouldn't be mistaken for the nudged one
+        let idx = unmatched
+            .iter()
+            .position(|(_, other)| *other == prefab)
+            .or_else(|| unmatched.iter().position(|(other, _)| *other == stripped))?;
+        let (_, other) = unmatched.swap_remove(idx);
+        let dx = offset(other, "pixel_x") - offset(prefab, "pixel_x");
+        let dy = offset(other, "pixel_y") - offset(prefab, "pixel_y");
+        if dx != 0.0 || dy != 0.0 {
+            nudged.push((prefab.path.clone(), dx, dy));
+        }
+    }
+    Some(nudged)
+}
+
+/// Tiles that differ within the area both maps cover, with their in game coordinates
+fn changed_tiles<'a>(
+    base_map: &'a dmm::Map,
+    head_map: &'a dmm::Map,
+) -> impl Iterator<Item = ((usize, usize, usize), &'a [dmm::Prefab], &'a [dmm::Prefab])> {
+    let base_dims = base_map.dim_xyz();
+    let head_dims = head_map.dim_xyz();
+    let (max_x, max_y) = (min(base_dims.0, head_dims.0), min(base_dims.1, head_dims.1));
+
+    (0..min(base_dims.2, head_dims.2))
+        .flat_map(move |z| (0..max_y).flat_map(move |y| (0..max_x).map(move |x| (x, y, z))))
+        .filter_map(move |(x, y, z)| {
+            let before = &base_map.dictionary[&base_map.grid[(z, base_dims.1 - y - 1, x)]];
+            let after = &head_map.dictionary[&head_map.grid[(z, head_dims.1 - y - 1, x)]];
+            (!same_tile(before, after))
+                .then(|| ((x + 1, y + 1, z + 1), before.as_slice(), after.as_slice()))
+        })
+}
+
+/// Objects moved within tiles whose only change is that, across every z-level both maps have
+pub fn offset_only_changes(base_map: &dmm::Map, head_map: &dmm::Map) -> Vec<OffsetChange> {
+    changed_tiles(base_map, head_map)
+        .filter_map(|(coords, before, after)| Some((coords, nudged_prefabs(before, after)?)))
+        .flat_map(|(coords, nudged)| {
+            nudged.into_iter().map(move |(path, dx, dy)| OffsetChange {
+                coords,
+                path,
+                dx,
+                dy,
+            })
+        })
+        .collect()
+}
+
+/// A var edit that changed on an object that stayed put, these can matter without changing how anything looks
+pub struct VarChange {
+    /// 1-indexed with y counting up, like in game
+    pub coords: (usize, usize, usize),
+    pub path: String,
+    pub var: String,
+    /// None when the var isn't edited, so it has the type's default
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+impl std::fmt::Display for VarChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (x, y, z) = self.coords;
+        let value = |value: &Option<String>| match value {
+            Some(value) => format!("`{value}`"),
+            None => "default".to_owned(),
+        };
+        write!(
+            f,
+            "`{}` at ({x}, {y}, {z}): `{}` {} → {}",
+            self.path,
+            self.var,
+            value(&self.before),
+            value(&self.after)
+        )
+    }
+}
+
+/// Var edits that changed on objects present on both sides of a changed tile, objects of the same type are paired up in order.
+/// Tiles that only had objects nudged are left to [`offset_only_changes`].
+pub fn var_changes(base_map: &dmm::Map, head_map: &dmm::Map) -> Vec<VarChange> {
+    let mut changes = vec![];
+    for (coords, before, after) in changed_tiles(base_map, head_map) {
+        if nudged_prefabs(before, after).is_some() {
+            continue;
+        }
+        let mut unmatched: Vec<&dmm::Prefab> = after.iter().collect();
+        for prefab in before {
+            let Some(idx) = unmatched.iter().position(|other| other.path == prefab.path) else {
+                continue;
+            };
+            let other = unmatched.remove(idx);
+            if other.vars == prefab.vars {
+                continue;
+            }
+            let mut vars: Vec<&String> = prefab.vars.keys().chain(other.vars.keys()).collect();
+            vars.sort();
+            vars.dedup();
+            changes.extend(vars.into_iter().filter_map(|var| {
+                let (old, new) = (prefab.vars.get(var), other.vars.get(var));
+                (old != new).then(|| VarChange {
+                    coords,
+                    path: prefab.path.clone(),
+                    var: var.clone(),
+                    before: old.map(ToString::to_string),
+                    after: new.map(ToString::to_string),
+                })
+            }));
+        }
+    }
+    changes
+}
+
+pub fn var_changes_markdown(filename: &str, changes: &[VarChange]) -> String {
+    let mut section = format!(
+        "<details>\n<summary>Var edits changed on {filename}, these may not show up in the renders</summary>\n\n"
+    );
+    for change in changes.iter().take(MAX_LISTED) {
+        section.push_str(&format!("- {change}\n"));
+    }
+    if changes.len() > MAX_LISTED {
+        section.push_str(&format!("\n...and {} more\n", changes.len() - MAX_LISTED));
+    }
+    section.push_str("\n</details>\n\n");
+    section
+}
+
+pub fn offsets_markdown(filename: &str, changes: &[OffsetChange]) -> String {
+    let mut section = format!(
+        "<details>\n<summary>Objects on {filename} only moved within their tile, this can be hard to spot in the renders</summary>\n\n"
+    );
+    for change in changes.iter().take(MAX_LISTED) {
+        section.push_str(&format!("- {change}\n"));
+    }
+    if changes.len() > MAX_LISTED {
+        section.push_str(&format!("\n...and {} more\n", changes.len() - MAX_LISTED));
+    }
+    section.push_str("\n</details>\n\n");
+    section
+}